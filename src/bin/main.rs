@@ -1,19 +1,67 @@
 use std::env;
+use std::io;
 use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
 
-use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use clap::{value_t, App, AppSettings, Arg, ArgMatches, SubCommand};
 use env_logger;
 use log::{error, info, LevelFilter};
-use tc_cache::{Config, Error, Pull, Push, Service, ServiceFactory, Stats, Storage};
+use tc_cache::{
+    exec, verify, Cache, Config, Diff, Error, Exec, Export, Extract, Format, Gc, Import, Inspect,
+    Key, Layout, Lock, OnConflict, Project, Pull, Push, Service, ServiceFactory, ServiceMessages,
+    SigningKey, Source, Stats, Storage, Target, TrustedKeys, Verify, DEFAULT_CACHE, PROJECT_FILE,
+};
 
 const PULL_COMMAND: &str = "pull";
 const PUSH_COMMAND: &str = "push";
+const INSPECT_COMMAND: &str = "inspect";
+const VERIFY_COMMAND: &str = "verify";
+const DIFF_COMMAND: &str = "diff";
+const EXTRACT_COMMAND: &str = "extract";
+const EXPORT_COMMAND: &str = "export";
+const IMPORT_COMMAND: &str = "import";
+const GC_COMMAND: &str = "gc";
+const EXEC_COMMAND: &str = "exec";
 const PREFIX: &str = "prefix";
 const HOME: &str = "home";
 const DIRECTORY: &str = "directory";
 const TEAMCITY_PROPS_FILE: &str = "teamcity-props-file";
 const KEY: &str = "key";
 const VERBOSE: &str = "verbose";
+const ALLOW_UNSIGNED: &str = "allow-unsigned";
+const LAYOUT: &str = "layout";
+const STREAM: &str = "stream";
+const MAX_DELTAS: &str = "max-deltas";
+const MAX_DELTA_RATIO: &str = "max-delta-ratio";
+const SNAPSHOT: &str = "snapshot";
+const REMOTE: &str = "remote";
+const JSON: &str = "json";
+const SUMMARY: &str = "summary";
+const LARGEST: &str = "largest";
+const DISK: &str = "disk";
+const LEFT: &str = "left";
+const RIGHT: &str = "right";
+const REMOTE_PREFIX: &str = "remote:";
+const OUTPUT: &str = "output";
+const PATTERN: &str = "pattern";
+const FORMAT: &str = "format";
+const TAR: &str = "tar";
+const UPLOAD: &str = "upload";
+const OLDER_THAN: &str = "older-than";
+const KEEP: &str = "keep";
+const BRANCHES_OLDER_THAN: &str = "branches-older-than";
+const CHUNKS: &str = "chunks";
+const DRY_RUN: &str = "dry-run";
+const DIR: &str = "dir";
+const PUSH_ON_FAILURE: &str = "push-on-failure";
+const COMMAND: &str = "command";
+const CONFIG: &str = "config";
+const NAME: &str = "name";
+const LOCK_TIMEOUT: &str = "lock-timeout";
+const ON_CONFLICT: &str = "on-conflict";
+const STRICT: &str = "strict";
+const TEAMCITY_VERSION: &str = "TEAMCITY_VERSION";
 
 fn new_service(args: &ArgMatches) -> Result<Box<dyn Service>, Error> {
     let env = env::vars().collect();
@@ -32,27 +80,187 @@ fn new_config(args: &ArgMatches) -> Result<Config, Error> {
         cfg.verbose(true);
     }
 
+    let env = env::vars().collect();
+    cfg.encryption_key(Key::from_env(&env)?);
+    cfg.signing_key(SigningKey::from_env(&env)?);
+    cfg.trusted_keys(TrustedKeys::from_env(&env)?);
+
+    let project = match args.value_of(CONFIG) {
+        Some(path) => Project::from_path(path)?,
+        None => Project::load(PROJECT_FILE)?,
+    };
+    cfg.project(project);
+
     Ok(cfg)
 }
 
+/// The cache declared in the project file, or an empty one to fill from args and env.
+fn project_cache(cfg: &Config) -> Cache {
+    cfg.project.cache(&cfg.name).cloned().unwrap_or_default()
+}
+
+/// Directories from args, otherwise the ones declared in the project file,
+/// then the ones set in the service's build settings.
+fn cached_dirs(
+    args: &ArgMatches,
+    name: &str,
+    cache: &Cache,
+    service: &dyn Service,
+) -> Vec<PathBuf> {
+    let dirs = match args.values_of(name) {
+        Some(values) => values.map(PathBuf::from).collect::<Vec<_>>(),
+        None if !cache.dirs.is_empty() => cache.dirs.clone(),
+        None => service.dirs().to_vec(),
+    };
+
+    if dirs.is_empty() {
+        let msg = format!(
+            "No directories to cache, pass them or declare in {}",
+            PROJECT_FILE
+        );
+        clap::Error::with_description(&msg, clap::ErrorKind::MissingRequiredArgument).exit();
+    }
+    dirs
+}
+
 fn new_storage(
     cfg: &Config,
     service: &Box<dyn Service>,
     args: &ArgMatches,
+    cache: &Cache,
 ) -> Result<Storage, Error> {
-    let mut storage = Storage::new(&cfg)
-        .uri(service.remote_url())?
-        .key_prefix(service.project_id())
-        .uploadable(service.is_uploadable());
+    let env = env::vars().collect();
+    let branch_key = cache.branch_key(&env, service.as_ref());
 
-    if let Some(key_prefix) = args.value_of(KEY) {
+    let storage = scoped_storage(cfg, service.as_ref(), args, cache, branch_key)?;
+    storage.save()?;
+    Ok(storage)
+}
+
+/// The default branch's storage, pulled from by a feature branch without a cache of its own.
+fn fallback_storage(
+    cfg: &Config,
+    service: &dyn Service,
+    args: &ArgMatches,
+    cache: &Cache,
+) -> Result<Option<Storage>, Error> {
+    let env = env::vars().collect();
+    if cache.branch_key(&env, service).is_none() {
+        return Ok(None);
+    }
+
+    let storage = scoped_storage(cfg, service, args, cache, None)?;
+    Ok(Some(storage.uploadable(false)))
+}
+
+/// A storage under the project's key, or under the feature branch's key within it.
+fn scoped_storage(
+    cfg: &Config,
+    service: &dyn Service,
+    args: &ArgMatches,
+    cache: &Cache,
+    branch_key: Option<String>,
+) -> Result<Storage, Error> {
+    let env = env::vars().collect();
+    let mut storage = Storage::new(cfg)
+        .uri(cache.remote_url(&env, service))?
+        .project(service.project_id())
+        .uploadable(cache.is_uploadable(&env, service));
+
+    if let Some(branch_key) = branch_key {
+        storage = storage.key_prefix(branch_key);
+    }
+
+    if cfg.name != DEFAULT_CACHE {
+        storage = storage.key_prefix(&cfg.name);
+    }
+
+    if let Some(key_prefix) = args.value_of(KEY).or_else(|| service.key()) {
         storage = storage.key_prefix(key_prefix);
     }
 
-    storage.save()?;
+    if let Some(key) = cache.key()? {
+        storage = storage.key_prefix(key);
+    }
+
+    match args.value_of(LAYOUT) {
+        Some(layout) => storage = storage.layout(layout.parse()?),
+        None => storage = storage.layout(cache.layout.unwrap_or_default()),
+    }
+    storage = storage.compression(cache.compression.unwrap_or_default());
+
     Ok(storage)
 }
 
+/// A snapshot file, or an object in the remote location when prefixed with `remote:`.
+fn new_source<'a>(cfg: &Config, storage: &'a Option<Storage>, value: Option<&str>) -> Source<'a> {
+    match (value, storage) {
+        (Some(value), Some(storage)) if value.starts_with(REMOTE_PREFIX) => {
+            Source::Remote(storage, value[REMOTE_PREFIX.len()..].to_string())
+        }
+        (Some(value), _) => Source::File(PathBuf::from(value)),
+        (None, _) => Source::File(cfg.snapshot_file.clone()),
+    }
+}
+
+fn run_exec(cfg: &Config, args: &ArgMatches, exec: &ArgMatches) -> Result<i32, Error> {
+    let _lock = lock(cfg, exec, true)?;
+    let service = new_service(args)?;
+    let cache = project_cache(cfg);
+    let storage = new_storage(cfg, &service, exec, &cache)?;
+    let fallback = fallback_storage(cfg, service.as_ref(), exec, &cache)?;
+    let on_conflict = exec.value_of(ON_CONFLICT).unwrap().parse::<OnConflict>()?;
+
+    let directories = cached_dirs(exec, DIR, &cache, service.as_ref());
+    let command = exec.values_of(COMMAND).unwrap().collect::<Vec<_>>();
+
+    let mut exec_cmd = Exec::new(cfg, &storage, &directories, &command)
+        .allow_unsigned(exec.is_present(ALLOW_UNSIGNED))
+        .stream(exec.is_present(STREAM))
+        .push_on_failure(exec.is_present(PUSH_ON_FAILURE))
+        .excludes(&cache.excludes)
+        .on_conflict(on_conflict);
+
+    if let Some(fallback) = &fallback {
+        exec_cmd = exec_cmd.fallback(fallback);
+    }
+
+    exec_cmd.run()
+}
+
+/// Locks the working directory of a cache, exclusively for commands changing its state.
+fn lock(cfg: &Config, args: &ArgMatches, exclusive: bool) -> Result<Lock, Error> {
+    let secs = value_t!(args, LOCK_TIMEOUT, u64).unwrap_or_else(|err| err.exit());
+    let timeout = Duration::from_secs(secs);
+
+    if exclusive {
+        Lock::exclusive(&cfg.lock_file, timeout)
+    } else {
+        Lock::shared(&cfg.lock_file, timeout)
+    }
+}
+
+fn run_push(cfg: &Config, push: &ArgMatches) -> Result<(), Error> {
+    let _lock = lock(cfg, push, true)?;
+    let storage = Storage::load(&cfg.storage_file)?;
+    let on_conflict = push.value_of(ON_CONFLICT).unwrap().parse::<OnConflict>()?;
+    let mut push_cmd = Push::new(cfg, &storage)
+        .excludes(&project_cache(cfg).excludes)
+        .on_conflict(on_conflict);
+
+    if push.is_present(MAX_DELTAS) {
+        let max_deltas = value_t!(push, MAX_DELTAS, usize).unwrap_or_else(|err| err.exit());
+        push_cmd = push_cmd.max_deltas(max_deltas);
+    }
+
+    if push.is_present(MAX_DELTA_RATIO) {
+        let ratio = value_t!(push, MAX_DELTA_RATIO, f64).unwrap_or_else(|err| err.exit());
+        push_cmd = push_cmd.max_delta_ratio(ratio);
+    }
+
+    push_cmd.run().map(|_| ())
+}
+
 fn init_logger(args: &ArgMatches) {
     let log_level = if args.is_present(VERBOSE) {
         LevelFilter::Debug
@@ -65,28 +273,244 @@ fn init_logger(args: &ArgMatches) {
         .init();
 }
 
+/// Service messages are only understood by TeamCity, it sets the variable for build steps.
+fn init_service_messages(args: &ArgMatches) {
+    if env::var_os(TEAMCITY_VERSION).is_some() {
+        ServiceMessages::current().enable(args.is_present(STRICT));
+    }
+}
+
+/// Statistics are reported to TeamCity for commands transferring caches only,
+/// others may print JSON to stdout.
+fn report_stats(command: Option<&str>) {
+    info!("{}", Stats::current());
+    match command {
+        Some(PULL_COMMAND) | Some(PUSH_COMMAND) | Some(EXEC_COMMAND) => {
+            ServiceMessages::current().stats(Stats::current())
+        }
+        _ => {}
+    }
+}
+
 fn run(args: &ArgMatches) -> Result<(), Error> {
     init_logger(args);
+    init_service_messages(args);
 
-    let cfg = new_config(&args)?;
+    let root = new_config(&args)?;
+    let cfg = match args.value_of(NAME) {
+        Some(name) => root.named(name)?,
+        None => root.clone(),
+    };
+
+    // push and exec lock caches on their own, gc doesn't touch the working directory
+    let _lock = match args.subcommand_name() {
+        Some(PULL_COMMAND) | Some(IMPORT_COMMAND) => Some(lock(&cfg, args, true)?),
+        Some(PUSH_COMMAND) | Some(EXEC_COMMAND) | Some(GC_COMMAND) => None,
+        _ => Some(lock(&cfg, args, false)?),
+    };
 
     if let Some(pull) = args.subcommand_matches(PULL_COMMAND) {
         let service = new_service(&args)?;
-        let storage = new_storage(&cfg, &service, &pull)?;
+        let cache = project_cache(&cfg);
+        let storage = new_storage(&cfg, &service, &pull, &cache)?;
+        let fallback = fallback_storage(&cfg, service.as_ref(), &pull, &cache)?;
 
-        let directories = pull.values_of(DIRECTORY).unwrap();
-        let directories = directories.map(PathBuf::from).collect::<Vec<_>>();
+        let directories = cached_dirs(pull, DIRECTORY, &cache, service.as_ref());
         let prefix = pull.value_of("prefix").map(PathBuf::from);
-        let pull = Pull::new(&cfg, &storage, &directories, prefix);
+        let mut pull_cmd = Pull::new(&cfg, &storage, &directories, prefix)
+            .allow_unsigned(pull.is_present(ALLOW_UNSIGNED))
+            .stream(pull.is_present(STREAM));
+
+        if let Some(fallback) = &fallback {
+            pull_cmd = pull_cmd.fallback(fallback);
+        }
 
-        return pull.run();
+        return pull_cmd.run();
     };
 
-    if let Some(_push) = args.subcommand_matches(PUSH_COMMAND) {
+    if let Some(push) = args.subcommand_matches(PUSH_COMMAND) {
+        if args.is_present(NAME) {
+            return run_push(&cfg, push);
+        }
+
+        // every cache pulled and not pushed yet, a failed one doesn't stop others
+        let mut result = Ok(());
+        for name in root.pulled_names()? {
+            info!("Pushing cache '{}' ...", name);
+            if let Err(err) = root.named(&name).and_then(|cfg| run_push(&cfg, push)) {
+                error!("{}", err);
+                result = result.and(Err(err));
+            }
+        }
+        return result;
+    }
+
+    if let Some(inspect) = args.subcommand_matches(INSPECT_COMMAND) {
+        let storage;
+        let source = if let Some(name) = inspect.value_of(REMOTE) {
+            storage = Storage::load(&cfg.storage_file)?;
+            Source::Remote(&storage, name.to_string())
+        } else {
+            let path = inspect.value_of(SNAPSHOT).map(PathBuf::from);
+            Source::File(path.unwrap_or_else(|| cfg.snapshot_file.clone()))
+        };
+
+        let mut inspect_cmd = Inspect::new(&cfg, source)
+            .json(inspect.is_present(JSON))
+            .summary(inspect.is_present(SUMMARY));
+
+        if inspect.is_present(LARGEST) {
+            let largest = value_t!(inspect, LARGEST, usize).unwrap_or_else(|err| err.exit());
+            inspect_cmd = inspect_cmd.largest(largest);
+        }
+
+        let stdout = io::stdout();
+        return inspect_cmd.run(stdout.lock());
+    }
+
+    if let Some(verify) = args.subcommand_matches(VERIFY_COMMAND) {
+        let storage;
+        let source = if let Some(name) = verify.value_of(REMOTE) {
+            storage = Storage::load(&cfg.storage_file)?;
+            Source::Remote(&storage, name.to_string())
+        } else {
+            let path = verify.value_of(SNAPSHOT).map(PathBuf::from);
+            Source::File(path.unwrap_or_else(|| cfg.snapshot_file.clone()))
+        };
+
+        let code = match Verify::new(&cfg, source)
+            .disk(verify.is_present(DISK))
+            .run()
+        {
+            Ok(report) => report.exit_code(),
+            Err(err) => {
+                error!("{}", err);
+                verify::EXIT_FAILED
+            }
+        };
+
+        process::exit(code);
+    }
+
+    if let Some(diff) = args.subcommand_matches(DIFF_COMMAND) {
+        let (left, right) = (diff.value_of(LEFT), diff.value_of(RIGHT));
+        let is_remote = |it: Option<&str>| it.map_or(false, |it| it.starts_with(REMOTE_PREFIX));
+
+        let storage = if is_remote(left) || is_remote(right) {
+            Some(Storage::load(&cfg.storage_file)?)
+        } else {
+            None
+        };
+
+        let left = new_source(&cfg, &storage, left);
+        let right = match right {
+            Some(_) => Target::Snapshot(new_source(&cfg, &storage, right)),
+            None => Target::Disk,
+        };
+
+        let stdout = io::stdout();
+        return Diff::new(&cfg, left, right)
+            .json(diff.is_present(JSON))
+            .run(stdout.lock());
+    }
+
+    if let Some(extract) = args.subcommand_matches(EXTRACT_COMMAND) {
+        let snapshot = extract.value_of(SNAPSHOT);
+        let storage = match snapshot {
+            Some(it) if it.starts_with(REMOTE_PREFIX) => Some(Storage::load(&cfg.storage_file)?),
+            _ => None,
+        };
+
+        let source = new_source(&cfg, &storage, snapshot);
+        let patterns = extract.values_of(PATTERN).unwrap().collect::<Vec<_>>();
+        let output = extract.value_of(OUTPUT).unwrap();
+
+        return Extract::new(&cfg, source, &patterns, output)
+            .run()
+            .map(|_| ());
+    }
+
+    if let Some(export) = args.subcommand_matches(EXPORT_COMMAND) {
+        let snapshot = export.value_of(SNAPSHOT);
+        let storage = match snapshot {
+            Some(it) if it.starts_with(REMOTE_PREFIX) => Some(Storage::load(&cfg.storage_file)?),
+            _ => None,
+        };
+
+        let source = new_source(&cfg, &storage, snapshot);
+        let mut export_cmd = Export::new(&cfg, source, export.value_of(OUTPUT).unwrap());
+
+        if let Some(format) = export.value_of(FORMAT) {
+            export_cmd = export_cmd.format(format.parse::<Format>()?);
+        }
+
+        return export_cmd.run().map(|_| ());
+    }
+
+    if let Some(import) = args.subcommand_matches(IMPORT_COMMAND) {
+        let storage = if import.is_present(UPLOAD) {
+            Some(Storage::load(&cfg.storage_file)?)
+        } else {
+            None
+        };
+
+        let mut import_cmd =
+            Import::new(&cfg, import.value_of(TAR).unwrap()).prefix(import.value_of(PREFIX));
+
+        if let Some(format) = import.value_of(FORMAT) {
+            import_cmd = import_cmd.format(format.parse::<Format>()?);
+        }
+
+        if let Some(storage) = &storage {
+            import_cmd = import_cmd.upload(storage);
+        }
+
+        return import_cmd.run().map(|_| ());
+    }
+
+    if let Some(exec) = args.subcommand_matches(EXEC_COMMAND) {
+        let code = match run_exec(&cfg, args, exec) {
+            Ok(code) => code,
+            Err(err) => {
+                error!("{}", err);
+                ServiceMessages::current().problem(&err);
+                exec::EXIT_NOT_STARTED
+            }
+        };
+
+        report_stats(Some(EXEC_COMMAND));
+        process::exit(code);
+    }
+
+    if let Some(gc) = args.subcommand_matches(GC_COMMAND) {
         let storage = Storage::load(&cfg.storage_file)?;
-        let push = Push::new(&cfg, &storage);
+        let mut gc_cmd = Gc::new(&storage).dry_run(gc.is_present(DRY_RUN));
 
-        return push.run().map(|_| ());
+        if let Some(prefix) = gc.value_of(PREFIX) {
+            gc_cmd = gc_cmd.prefix(prefix);
+        }
+
+        if gc.is_present(OLDER_THAN) {
+            let days = value_t!(gc, OLDER_THAN, u64).unwrap_or_else(|err| err.exit());
+            gc_cmd = gc_cmd.older_than(days);
+        }
+
+        if gc.is_present(KEEP) {
+            let keep = value_t!(gc, KEEP, usize).unwrap_or_else(|err| err.exit());
+            gc_cmd = gc_cmd.keep(keep);
+        }
+
+        if gc.is_present(BRANCHES_OLDER_THAN) {
+            let days = value_t!(gc, BRANCHES_OLDER_THAN, u64).unwrap_or_else(|err| err.exit());
+            gc_cmd = gc_cmd.branches_older_than(days);
+        }
+
+        if gc.is_present(CHUNKS) {
+            let days = value_t!(gc, CHUNKS, u64).unwrap_or_else(|err| err.exit());
+            gc_cmd = gc_cmd.chunks(&cfg, days);
+        }
+
+        return gc_cmd.run().map(|_| ());
     }
 
     Ok(())
@@ -117,15 +541,320 @@ fn main() {
                 .value_name("text")
                 .help("Cache key prefix"),
         )
+        .arg(
+            Arg::with_name(ALLOW_UNSIGNED)
+                .long("allow-unsigned")
+                .help("Unpack a snapshot even if its signature is missing or invalid"),
+        )
+        .arg(
+            Arg::with_name(LAYOUT)
+                .long("layout")
+                .value_name("layout")
+                .possible_values(Layout::variants())
+                .help("How a snapshot is kept in the remote location (default 'archive')"),
+        )
+        .arg(
+            Arg::with_name(STREAM)
+                .long("stream")
+                .help("Unpack an archive while downloading it, without a local copy"),
+        )
         .arg(
             Arg::with_name(DIRECTORY)
+                .min_values(1)
+                .help("A list of directories to cache (default the project file's dirs)"),
+        );
+
+    let push = SubCommand::with_name(PUSH_COMMAND)
+        .about("Push cached directories into remote location")
+        .arg(
+            Arg::with_name(MAX_DELTAS)
+                .long("max-deltas")
+                .value_name("number")
+                .help("[delta layout] Compact after that many deltas (default 10)"),
+        )
+        .arg(
+            Arg::with_name(MAX_DELTA_RATIO)
+                .long("max-delta-ratio")
+                .value_name("ratio")
+                .help(
+                    "[delta layout] Compact when deltas exceed that part of the base (default 0.5)",
+                ),
+        )
+        .arg(
+            Arg::with_name(ON_CONFLICT)
+                .long("on-conflict")
+                .value_name("policy")
+                .possible_values(OnConflict::variants())
+                .default_value(OnConflict::default().as_str())
+                .help("What to do when the remote snapshot has changed since the pull"),
+        );
+
+    let inspect = SubCommand::with_name(INSPECT_COMMAND)
+        .about("List the contents of a snapshot without unpacking it")
+        .arg(
+            Arg::with_name(REMOTE)
+                .long("remote")
+                .short("r")
+                .value_name("name")
+                .conflicts_with(SNAPSHOT)
+                .help("Read an object from the remote location of the last pull, e.g. snapshot.snappy"),
+        )
+        .arg(
+            Arg::with_name(JSON)
+                .long("json")
+                .help("Print JSON instead of a table"),
+        )
+        .arg(
+            Arg::with_name(SUMMARY)
+                .long("summary")
+                .short("s")
+                .help("Print totals per top-level directory, the largest files and the compression ratio"),
+        )
+        .arg(
+            Arg::with_name(LARGEST)
+                .long("largest")
+                .value_name("number")
+                .help("How many of the largest files a summary shows (default 10)"),
+        )
+        .arg(
+            Arg::with_name(SNAPSHOT)
+                .help("A snapshot file (default the last pulled one)"),
+        );
+
+    let verify = SubCommand::with_name(VERIFY_COMMAND)
+        .about("Check a snapshot's integrity")
+        .after_help(
+            "EXIT CODES:\n    \
+             0    The snapshot is fine\n    \
+             1    The snapshot can't be read, e.g. a download failed\n    \
+             2    The snapshot is corrupted\n    \
+             3    A file's content doesn't match its md5 or length\n    \
+             4    The snapshot differs from what's on disk (--disk)",
+        )
+        .arg(
+            Arg::with_name(REMOTE)
+                .long("remote")
+                .short("r")
+                .value_name("name")
+                .conflicts_with(SNAPSHOT)
+                .help("Read an object from the remote location of the last pull, e.g. snapshot.snappy"),
+        )
+        .arg(
+            Arg::with_name(DISK)
+                .long("disk")
+                .help("Also compare the snapshot with what's on disk under the cached directories"),
+        )
+        .arg(
+            Arg::with_name(SNAPSHOT)
+                .help("A snapshot file (default the last pulled one)"),
+        );
+
+    let diff = SubCommand::with_name(DIFF_COMMAND)
+        .about("Compare two snapshots, or a snapshot with what's on disk")
+        .after_help(
+            "A snapshot is a file, or an object in the remote location of the last pull \
+             when prefixed with 'remote:', e.g. remote:snapshot.snappy",
+        )
+        .arg(
+            Arg::with_name(JSON)
+                .long("json")
+                .help("Print JSON instead of text"),
+        )
+        .arg(Arg::with_name(LEFT).help("The old snapshot (default the last pulled one)"))
+        .arg(
+            Arg::with_name(RIGHT)
+                .help("The new snapshot (default what's on disk under the cached directories)"),
+        );
+
+    let extract = SubCommand::with_name(EXTRACT_COMMAND)
+        .about("Unpack entries matching glob patterns from a snapshot")
+        .arg(
+            Arg::with_name(SNAPSHOT)
+                .long("snapshot")
+                .short("s")
+                .value_name("snapshot")
+                .help(
+                    "A snapshot file, or a remote object prefixed with 'remote:' \
+                     (default the last pulled one)",
+                ),
+        )
+        .arg(
+            Arg::with_name(OUTPUT)
+                .long("output")
+                .short("o")
+                .value_name("directory")
+                .required(true)
+                .help("Extract matching entries into that directory, keeping their paths"),
+        )
+        .arg(
+            Arg::with_name(PATTERN)
                 .required(true)
                 .min_values(1)
-                .help("A list of directories to cache"),
+                .help("Glob patterns of paths to extract, e.g. '**/guava-*.jar'"),
+        );
+
+    let export = SubCommand::with_name(EXPORT_COMMAND)
+        .about("Convert a snapshot into a tar archive")
+        .arg(
+            Arg::with_name(SNAPSHOT)
+                .long("snapshot")
+                .short("s")
+                .value_name("snapshot")
+                .help(
+                    "A snapshot file, or a remote object prefixed with 'remote:' \
+                     (default the last pulled one)",
+                ),
+        )
+        .arg(
+            Arg::with_name(FORMAT)
+                .long("format")
+                .value_name("format")
+                .possible_values(Format::variants())
+                .help("Archive format (default by the output's extension)"),
+        )
+        .arg(
+            Arg::with_name(OUTPUT)
+                .long("output")
+                .short("o")
+                .value_name("file")
+                .required(true)
+                .help("A tar file to write, paths in it are relative to '/'"),
         );
 
-    let push =
-        SubCommand::with_name(PUSH_COMMAND).about("Push cached directories into remote location");
+    let import = SubCommand::with_name(IMPORT_COMMAND)
+        .about("Build a snapshot from a tar archive")
+        .arg(
+            Arg::with_name(FORMAT)
+                .long("format")
+                .value_name("format")
+                .possible_values(Format::variants())
+                .help("Archive format (default by the file's extension)"),
+        )
+        .arg(
+            Arg::with_name(PREFIX)
+                .long("prefix")
+                .short("p")
+                .value_name("directory")
+                .default_value("/")
+                .help("Place paths from the tar under that directory"),
+        )
+        .arg(
+            Arg::with_name(UPLOAD)
+                .long("upload")
+                .help("Upload the snapshot into the remote location of the last pull"),
+        )
+        .arg(
+            Arg::with_name(TAR)
+                .required(true)
+                .help("A tar file to read"),
+        );
+
+    let exec = SubCommand::with_name(EXEC_COMMAND)
+        .about("Pull, run a command and push when it succeeds")
+        .after_help("Exits with the command's status, 127 when it couldn't be started.")
+        .arg(
+            Arg::with_name(TEAMCITY_PROPS_FILE)
+                .hidden(true)
+                .long("build-props")
+                .value_name("file")
+                .env("TEAMCITY_BUILD_PROPERTIES_FILE")
+                .help("[advanced] override teamcity's build properties file"),
+        )
+        .arg(
+            Arg::with_name(DIR)
+                .long("dir")
+                .value_name("directory")
+                .multiple(true)
+                .number_of_values(1)
+                .help("A directory to cache, can be repeated (default the project file's dirs)"),
+        )
+        .arg(
+            Arg::with_name(KEY)
+                .long("key")
+                .short("k")
+                .value_name("text")
+                .help("Cache key prefix"),
+        )
+        .arg(
+            Arg::with_name(ALLOW_UNSIGNED)
+                .long("allow-unsigned")
+                .help("Unpack a snapshot even if its signature is missing or invalid"),
+        )
+        .arg(
+            Arg::with_name(LAYOUT)
+                .long("layout")
+                .value_name("layout")
+                .possible_values(Layout::variants())
+                .help("How a snapshot is kept in the remote location (default 'archive')"),
+        )
+        .arg(
+            Arg::with_name(STREAM)
+                .long("stream")
+                .help("Unpack an archive while downloading it, without a local copy"),
+        )
+        .arg(
+            Arg::with_name(PUSH_ON_FAILURE)
+                .long("push-on-failure")
+                .help("Push even when the command exits with a non-zero status"),
+        )
+        .arg(
+            Arg::with_name(ON_CONFLICT)
+                .long("on-conflict")
+                .value_name("policy")
+                .possible_values(OnConflict::variants())
+                .default_value(OnConflict::default().as_str())
+                .help("What to do when the remote snapshot has changed since the pull"),
+        )
+        .arg(
+            Arg::with_name(COMMAND)
+                .required(true)
+                .multiple(true)
+                .last(true)
+                .help("A command to run after '--', e.g. '-- ./gradlew build'"),
+        );
+
+    let gc = SubCommand::with_name(GC_COMMAND)
+        .about("Delete stale caches from the remote location of the last pull")
+        .arg(
+            Arg::with_name(OLDER_THAN)
+                .long("older-than")
+                .value_name("days")
+                .required_unless_one(&[KEEP, BRANCHES_OLDER_THAN, CHUNKS])
+                .help("Delete caches not pushed for that many days"),
+        )
+        .arg(
+            Arg::with_name(BRANCHES_OLDER_THAN)
+                .long("branches-older-than")
+                .value_name("days")
+                .help("Delete caches of feature branches not pushed for that many days"),
+        )
+        .arg(
+            Arg::with_name(KEEP)
+                .long("keep")
+                .value_name("count")
+                .help("Keep only that many of the newest versions of each cache, by key files"),
+        )
+        .arg(
+            Arg::with_name(CHUNKS)
+                .long("chunks")
+                .value_name("days")
+                .help(
+                    "Delete chunks not pushed for that many days which no manifest refers to, \
+                     chunks are kept otherwise",
+                ),
+        )
+        .arg(
+            Arg::with_name(PREFIX)
+                .long("prefix")
+                .short("p")
+                .value_name("key")
+                .help("Only look at caches which keys start with that prefix (default the project's key)"),
+        )
+        .arg(
+            Arg::with_name(DRY_RUN)
+                .long("dry-run")
+                .help("Report what would be deleted without deleting anything"),
+        );
 
     let app = App::new(env!("CARGO_PKG_DESCRIPTION"))
         .bin_name(env!("CARGO_PKG_NAME"))
@@ -141,6 +870,37 @@ fn main() {
                 .help("Set working directory (default '~/.tc-cache')")
                 .global(true),
         )
+        .arg(
+            Arg::with_name(CONFIG)
+                .long("config")
+                .value_name("file")
+                .env("TC_CACHE_CONFIG")
+                .help("Set project file declaring caches (default './.tc-cache.toml')")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name(NAME)
+                .long("name")
+                .short("n")
+                .value_name("name")
+                .help("Use a named cache, its state is kept apart from others (push: all caches pulled and not pushed yet by default)")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name(LOCK_TIMEOUT)
+                .long("lock-timeout")
+                .value_name("seconds")
+                .default_value("300")
+                .env("TC_CACHE_LOCK_TIMEOUT")
+                .help("Wait that long for other processes using the working directory")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name(STRICT)
+                .long("strict")
+                .help("Exit with an error when caching fails, reported as a build problem on TeamCity")
+                .global(true),
+        )
         .arg(
             Arg::with_name(VERBOSE)
                 .long("verbose")
@@ -150,11 +910,23 @@ fn main() {
         )
         .subcommand(pull)
         .subcommand(push)
+        .subcommand(inspect)
+        .subcommand(verify)
+        .subcommand(diff)
+        .subcommand(extract)
+        .subcommand(export)
+        .subcommand(import)
+        .subcommand(exec)
+        .subcommand(gc)
         .get_matches();
 
     if let Err(err) = run(&app) {
         error!("{}", err);
+        ServiceMessages::current().problem(&err);
+        if app.is_present(STRICT) {
+            process::exit(1);
+        }
     } else {
-        info!("{}", Stats::current());
+        report_stats(app.subcommand_name());
     }
 }
@@ -0,0 +1,185 @@
+use lazy_static::lazy_static;
+
+const MIN_SIZE: usize = 256 * 1024; // 256kb
+const AVG_SIZE: usize = 1024 * 1024; // 1mb
+const MAX_SIZE: usize = 4 * 1024 * 1024; // 4mb
+
+lazy_static! {
+    static ref GEAR: [u64; 256] = gear_table();
+}
+
+/// Content defined chunking based on a gear rolling hash (FastCDC with normalized chunking),
+/// an insertion or removal in a file only changes boundaries of the chunks around it.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunker {
+    min: usize,
+    avg: usize,
+    max: usize,
+    mask_small: u64,
+    mask_large: u64,
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        Chunker::new(MIN_SIZE, AVG_SIZE, MAX_SIZE)
+    }
+}
+
+impl Chunker {
+    pub fn new(min: usize, avg: usize, max: usize) -> Self {
+        assert!(min > 0 && min <= avg && avg <= max, "invalid chunk sizes");
+
+        let bits = (avg as f64).log2().round() as u32;
+
+        Chunker {
+            min,
+            avg,
+            max,
+            mask_small: mask(bits + 1),
+            mask_large: mask(bits - 1),
+        }
+    }
+
+    /// Returns the length of the first chunk in `src`.
+    pub fn cut(&self, src: &[u8]) -> usize {
+        let len = src.len();
+        if len <= self.min {
+            return len;
+        }
+
+        let normal = len.min(self.avg);
+        let max = len.min(self.max);
+        let mut hash = 0_u64;
+        let mut idx = self.min;
+
+        while idx < normal {
+            hash = (hash << 1).wrapping_add(GEAR[src[idx] as usize]);
+            if hash & self.mask_small == 0 {
+                return idx + 1;
+            }
+            idx += 1;
+        }
+
+        while idx < max {
+            hash = (hash << 1).wrapping_add(GEAR[src[idx] as usize]);
+            if hash & self.mask_large == 0 {
+                return idx + 1;
+            }
+            idx += 1;
+        }
+
+        max
+    }
+
+    pub fn chunks<'a>(&self, src: &'a [u8]) -> Chunks<'a> {
+        Chunks {
+            chunker: *self,
+            src,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Chunks<'a> {
+    chunker: Chunker,
+    src: &'a [u8],
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.src.is_empty() {
+            return None;
+        }
+
+        let len = self.chunker.cut(self.src);
+        let (chunk, rest) = self.src.split_at(len);
+        self.src = rest;
+
+        Some(chunk)
+    }
+}
+
+// the highest bits, so a boundary depends on the last 64 bytes
+#[inline]
+fn mask(bits: u32) -> u64 {
+    !0_u64 << (64 - bits)
+}
+
+// splitmix64 with a fixed seed, boundaries must be stable between versions
+fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state = 0x2545_F491_4F6C_DD1D_u64;
+
+    for it in table.iter_mut() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        *it = z ^ (z >> 31);
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    fn chunker() -> Chunker {
+        Chunker::new(1024, 4096, 16384)
+    }
+
+    #[test]
+    fn chunks_cover_source() {
+        let src = random_bytes(256 * 1024, 42);
+        let chunks = chunker().chunks(&src).collect::<Vec<_>>();
+
+        assert!(chunks.len() > 16, "got {} chunks", chunks.len());
+        assert_eq!(chunks.concat(), src);
+
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= 1024 && chunk.len() <= 16384);
+        }
+    }
+
+    #[test]
+    fn small_source_is_single_chunk() {
+        let src = random_bytes(100, 42);
+        let chunks = chunker().chunks(&src).collect::<Vec<_>>();
+
+        assert_eq!(chunks, vec![src.as_slice()]);
+        assert_eq!(chunker().chunks(&[]).count(), 0);
+    }
+
+    #[test]
+    fn insertion_keeps_most_chunks() {
+        let src = random_bytes(256 * 1024, 7);
+        let mut changed = src.clone();
+        changed.splice(100_000..100_000, b"inserted".iter().cloned());
+
+        let left = chunker().chunks(&src).collect::<Vec<_>>();
+        let right = chunker().chunks(&changed).collect::<Vec<_>>();
+        let same = right.iter().filter(|it| left.contains(it)).count();
+
+        assert!(
+            same + 3 >= left.len(),
+            "only {} of {} chunks are the same",
+            same,
+            left.len()
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::crypto::{Key, Opening, Sealing};
+use crate::errors::ResultExt;
+use crate::snapshot::{self, Entry};
+use crate::Error;
+
+const MANIFEST_VERSION: &[u8; 4] = &[0xA0, 0xF1, 0xB2, 0xC1];
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub entry: Entry,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+}
+
+/// A snapshot in the chunks layout, entries in the same order as in an archive,
+/// files refer to their content by chunk ids.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn read<P>(path: P, key: Option<&Key>) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::open(&path).io_err(&path)?;
+        let mut src = snap::Reader::new(Opening::new(file, key)?);
+
+        let mut version = [0; 4];
+        src.read_exact(&mut version)
+            .map_err(Error::snapshot_io("Read manifest version failed"))?;
+
+        if &version != MANIFEST_VERSION {
+            let err = format!("Expected {:?}, got {:?}", MANIFEST_VERSION, version);
+            return Error::snapshot_err("Manifest version mismatch", err);
+        }
+
+        let mut buf = Vec::new();
+        src.read_to_end(&mut buf)
+            .map_err(Error::snapshot_io("Read manifest failed"))?;
+
+        serde_cbor::from_slice(&buf).snapshot_err("Read manifest failed")
+    }
+
+    pub fn write<P>(&self, path: P, key: Option<&Key>) -> Result<usize, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&path)
+            .io_err(&path)?;
+
+        let buf = serde_cbor::to_vec(self).snapshot_err("Create manifest failed")?;
+        let mut dst = snap::Writer::new(Sealing::new(file, key)?);
+
+        dst.write_all(MANIFEST_VERSION)
+            .snapshot_err("Write manifest failed")?;
+        dst.write_all(&buf).snapshot_err("Write manifest failed")?;
+
+        dst.into_inner()
+            .map_err(|err| err.to_string())
+            .snapshot_err("Flush failed")?
+            .finish()?;
+
+        let len = path.as_ref().metadata().io_err(&path)?.len();
+        Ok(len as usize)
+    }
+
+    pub fn chunk_ids(&self) -> HashSet<&str> {
+        self.entries
+            .iter()
+            .flat_map(|it| it.chunks.iter())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Chunk ids of entries placed under one of the directories.
+    pub fn included_chunk_ids<P>(&self, dirs: &[P]) -> HashSet<&str>
+    where
+        P: AsRef<Path>,
+    {
+        self.entries
+            .iter()
+            .filter(|it| snapshot::is_include(dirs, it.entry.as_ref()))
+            .flat_map(|it| it.chunks.iter())
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::snapshot::Attributes;
+    use crate::testing;
+
+    #[test]
+    fn write_and_read() {
+        let dst = testing::temp_file(".manifest");
+        let key = Key::from_hex(hex::encode([3; 32])).unwrap();
+        let attr = Attributes::new(0o644, 0, 0);
+
+        let manifest = Manifest {
+            entries: vec![
+                ManifestEntry {
+                    entry: Entry::dir("a", attr),
+                    chunks: vec![],
+                },
+                ManifestEntry {
+                    entry: Entry::file("a/b", attr, "md5", 2).unwrap(),
+                    chunks: vec!["one".into(), "two".into()],
+                },
+            ],
+        };
+
+        for key in &[None, Some(&key)] {
+            manifest.write(&dst, *key).unwrap();
+            let actual = Manifest::read(&dst, *key).unwrap();
+
+            assert_eq!(actual, manifest);
+        }
+
+        let mut ids = manifest.chunk_ids().into_iter().collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec!["one", "two"]);
+
+        assert_eq!(manifest.included_chunk_ids(&["a"]).len(), 2);
+        assert_eq!(manifest.included_chunk_ids(&["b"]).len(), 0);
+    }
+}
@@ -0,0 +1,7 @@
+mod chunker;
+mod manifest;
+mod store;
+
+pub use self::chunker::Chunker;
+pub use self::manifest::{Manifest, ManifestEntry};
+pub use self::store::ChunkStore;
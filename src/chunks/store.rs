@@ -0,0 +1,420 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs as unix_fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::chunks::{Chunker, Manifest, ManifestEntry};
+use crate::crypto::{self, Key, Opening, Sealing};
+use crate::errors::ResultExt;
+use crate::snapshot::{self, Entry, BUFFER_SIZE};
+use crate::{mmap, Config, Error, Stats, Storage};
+
+const CHUNKS_KEY_PREFIX: &str = "chunks";
+
+/// Local cache of chunks, each one is stored compressed (and encrypted when a key is given)
+/// exactly as it's stored in the remote location under `chunks/<id>`.
+#[derive(Debug)]
+pub struct ChunkStore<'a> {
+    dir: PathBuf,
+    key: Option<&'a Key>,
+    chunker: Chunker,
+}
+
+impl<'a> ChunkStore<'a> {
+    pub fn new(cfg: &'a Config) -> Result<Self, Error> {
+        let dir = cfg.chunks_dir.clone();
+
+        if !dir.exists() {
+            fs::create_dir_all(&dir).io_err(&dir)?;
+        }
+
+        Ok(ChunkStore {
+            dir,
+            key: cfg.encryption_key.as_ref(),
+            chunker: Chunker::default(),
+        })
+    }
+
+    pub fn pack(&self, entries: &[Entry]) -> Result<Manifest, Error> {
+        let entries = entries
+            .par_iter()
+            .map(|entry| {
+                let chunks = match entry.as_file() {
+                    Some((path, _, _, len)) if len > 0 => self.pack_file(path, len)?,
+                    _ => Vec::new(),
+                };
+
+                Ok(ManifestEntry {
+                    entry: entry.clone(),
+                    chunks,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Manifest { entries })
+    }
+
+    pub fn unpack<P>(
+        &self,
+        manifest: &Manifest,
+        prefix: Option<PathBuf>,
+        dirs: &[P],
+    ) -> Result<(Vec<Entry>, usize), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let prefixed = snapshot::prefixed(prefix);
+        let mut read: usize = 0;
+        let mut entries = Vec::new();
+
+        for it in &manifest.entries {
+            let entry = &it.entry;
+
+            if !snapshot::is_include(dirs, entry.as_ref()) {
+                continue;
+            }
+
+            if let Some((path, attr)) = entry.as_dir() {
+                let path = prefixed(path);
+                fs::create_dir_all(&path).io_err(&path)?;
+                snapshot::restore_attributes(&path, &attr)?;
+            }
+
+            if let Some((path, target, _)) = entry.as_symlink() {
+                let path = prefixed(path);
+                unix_fs::symlink(&target, &path).io_err(&path)?;
+            }
+
+            if let Some((path, attr, _, _)) = entry.as_file() {
+                let path = prefixed(path);
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .truncate(true)
+                    .create(true)
+                    .open(&path)
+                    .io_err(&path)?;
+
+                for id in &it.chunks {
+                    read += self.read_chunk(id, &mut file)?;
+                }
+                snapshot::restore_attributes(&path, &attr)?;
+            }
+
+            entries.push(entry.clone());
+        }
+
+        Ok((entries, read))
+    }
+
+    /// Uploads chunks which aren't in the remote location yet, returns uploaded bytes.
+    /// The remote chunks are listed once, only the unlisted ones are checked one by one.
+    pub fn upload_missing(&self, ids: &HashSet<&str>, storage: &Storage) -> Result<usize, Error> {
+        let listed = list_remote(storage);
+        let unknown = ids
+            .iter()
+            .filter(|id| !listed.contains(&chunk_key(id)))
+            .collect::<Vec<_>>();
+        let missing = unknown
+            .par_iter()
+            .map(|id| storage.exists(chunk_key(id)).map(|exists| (id, exists)))
+            .collect::<Result<Vec<_>, Error>>()?
+            .into_iter()
+            .filter(|(_, exists)| !exists)
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+
+        info!("Uploading {} of {} chunks ...", missing.len(), ids.len());
+
+        let uploaded = missing
+            .par_iter()
+            .map(|id| {
+                let path = self.path(id);
+                let len = path.metadata().io_err(&path)?.len() as usize;
+                storage.upload_object(chunk_key(id), &path, len)?;
+                Ok(len)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(uploaded.iter().sum())
+    }
+
+    /// Downloads chunks which aren't in the local store yet, returns downloaded bytes.
+    pub fn download_missing(&self, ids: &HashSet<&str>, storage: &Storage) -> Result<usize, Error> {
+        let missing = ids
+            .iter()
+            .filter(|id| !self.path(id).exists())
+            .collect::<Vec<_>>();
+
+        info!("Downloading {} of {} chunks ...", missing.len(), ids.len());
+
+        let downloaded = missing
+            .par_iter()
+            .map(|id| {
+                let path = self.path(id);
+                let tmp = self.temp_path(id)?;
+
+                storage.download_object(chunk_key(id), &tmp)?;
+                fs::rename(&tmp, &path).io_err(&path)?;
+
+                let len = path.metadata().io_err(&path)?.len();
+                Ok(len as usize)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(downloaded.iter().sum())
+    }
+
+    /// Removes local chunks which aren't referenced anymore, returns a number of removed ones.
+    pub fn retain(&self, ids: &HashSet<&str>) -> Result<usize, Error> {
+        let mut removed = 0;
+
+        for item in fs::read_dir(&self.dir).io_err(&self.dir)? {
+            let path = item.io_err(&self.dir)?.path();
+            let is_referenced = path
+                .file_name()
+                .and_then(|it| it.to_str())
+                .map(|it| ids.contains(it))
+                .unwrap_or(false);
+
+            if !is_referenced {
+                debug!("Remove chunk {:?}", path.as_os_str());
+                fs::remove_file(&path).io_err(&path)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn pack_file(&self, path: &Path, len: usize) -> Result<Vec<String>, Error> {
+        let (_, len, src) = mmap::read(&path, Some(len))?;
+        let mut ids = Vec::new();
+
+        for chunk in self.chunker.chunks(&src) {
+            let id = self.id(chunk);
+            self.write_chunk(&id, chunk)?;
+            ids.push(id);
+        }
+
+        Stats::current().packing().inc(len);
+
+        Ok(ids)
+    }
+
+    fn write_chunk(&self, id: &str, chunk: &[u8]) -> Result<(), Error> {
+        let path = self.path(id);
+        if path.exists() {
+            return Ok(());
+        }
+
+        let tmp = self.temp_path(id)?;
+        let file = File::create(&tmp).io_err(&tmp)?;
+        let mut dst = snap::Writer::new(Sealing::new(file, self.key)?);
+
+        dst.write_all(chunk).io_err(&tmp)?;
+        dst.into_inner()
+            .map_err(|err| err.to_string())
+            .io_err(&tmp)?
+            .finish()?;
+
+        fs::rename(&tmp, &path).io_err(&path)
+    }
+
+    fn read_chunk<W: Write>(&self, id: &str, dst: &mut W) -> Result<usize, Error> {
+        let path = self.path(id);
+        let file = File::open(&path).io_err(&path)?;
+        let mut src = snap::Reader::new(Opening::new(file, self.key)?);
+
+        let mut hasher = self.hasher();
+        let mut buf = vec![0; BUFFER_SIZE];
+        let mut read = 0;
+
+        loop {
+            let len = src
+                .read(&mut buf)
+                .map_err(Error::snapshot_io("Read chunk failed"))?;
+            if len == 0 {
+                break;
+            }
+
+            hasher.update(&buf[..len]);
+            dst.write_all(&buf[..len]).snapshot_err("Copy failed")?;
+            read += len;
+        }
+
+        if hex::encode(hasher.finalize()) != id {
+            let err = format!("Chunk {} is corrupted", id);
+            return Error::snapshot_err("Checksum mismatch", err);
+        }
+
+        Stats::current().unpacking().inc(read);
+
+        Ok(read)
+    }
+
+    // chunk ids are keyed with the encryption key, so they don't reveal known content
+    fn hasher(&self) -> Sha256 {
+        let mut hasher = Sha256::new();
+        if let Some(key) = self.key {
+            hasher.update(key.as_bytes());
+        }
+        hasher
+    }
+
+    fn id(&self, chunk: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(chunk);
+        hex::encode(hasher.finalize())
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn temp_path(&self, id: &str) -> Result<PathBuf, Error> {
+        let mut suffix = [0; 8];
+        crypto::random_bytes(&mut suffix)?;
+        Ok(self.dir.join(format!("{}.{}.tmp", id, hex::encode(suffix))))
+    }
+}
+
+/// Keys of the remote chunks, a failed listing is logged and lists nothing.
+fn list_remote(storage: &Storage) -> HashSet<String> {
+    match storage.list(format!("{}/", CHUNKS_KEY_PREFIX)) {
+        Ok(objects) => objects.into_iter().map(|it| it.key).collect(),
+        Err(err) => {
+            warn!("Cannot list remote chunks: {}", err);
+            HashSet::new()
+        }
+    }
+}
+
+fn chunk_key(id: &str) -> String {
+    format!("{}/{}", CHUNKS_KEY_PREFIX, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use url::Url;
+
+    use crate::testing::{self, FIXTURES_PATH, IS_DIR_PATH};
+
+    #[test]
+    fn pack_and_unpack() {
+        let work = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let dirs = vec![Path::new(FIXTURES_PATH)];
+
+        let cfg = Config::from(&work).unwrap();
+        let store = ChunkStore {
+            chunker: Chunker::new(1024, 4096, 16384),
+            ..ChunkStore::new(&cfg).unwrap()
+        };
+
+        let entries = Entry::walk_into_vec(&dirs).unwrap();
+        let manifest = store.pack(&entries).unwrap();
+
+        let packed = manifest.entries.into_iter().map(|it| it.entry);
+        assert_eq!(packed.collect::<Vec<_>>(), entries);
+
+        let manifest = store.pack(&entries).unwrap();
+        let (actual, _) = store
+            .unpack(&manifest, Some(dst.as_ref().to_path_buf()), &dirs)
+            .unwrap();
+
+        assert_eq!(actual, entries);
+
+        let unpacked = dst.as_ref().join(FIXTURES_PATH).canonicalize().unwrap();
+        let expected = Path::new(FIXTURES_PATH).canonicalize().unwrap();
+        for entry in entries.iter().filter_map(|it| it.as_file()) {
+            let (path, _, md5, _) = entry;
+            let path = unpacked.join(path.strip_prefix(FIXTURES_PATH).unwrap());
+            assert_eq!(crate::hashing::md5::path(&path).unwrap(), md5);
+        }
+
+        assert!(expected.exists());
+        assert!(dst.as_ref().join(IS_DIR_PATH).is_dir());
+    }
+
+    #[test]
+    fn sync_with_remote() {
+        let work = testing::temp_dir();
+        let other = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let dirs = vec![Path::new(FIXTURES_PATH)];
+
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+        let cfg = Config::from(&work).unwrap();
+        let storage = Storage::new(&cfg).uri(uri.as_str()).unwrap();
+
+        let store = ChunkStore::new(&cfg).unwrap();
+        let entries = Entry::walk_into_vec(&dirs).unwrap();
+        let manifest = store.pack(&entries).unwrap();
+        let ids = manifest.chunk_ids();
+
+        let uploaded = store.upload_missing(&ids, &storage).unwrap();
+        assert!(uploaded > 0);
+        assert_eq!(store.upload_missing(&ids, &storage).unwrap(), 0);
+
+        // only the chunk missing remotely is uploaded again
+        let id = ids.iter().next().unwrap();
+        let len = store.path(id).metadata().unwrap().len() as usize;
+        storage.delete(chunk_key(id)).unwrap();
+        assert_eq!(store.upload_missing(&ids, &storage).unwrap(), len);
+
+        let cfg = Config::from(&other).unwrap();
+        let store = ChunkStore::new(&cfg).unwrap();
+
+        let downloaded = store.download_missing(&ids, &storage).unwrap();
+        assert_eq!(downloaded, uploaded);
+        assert_eq!(store.download_missing(&ids, &storage).unwrap(), 0);
+    }
+
+    #[test]
+    fn reject_corrupted_chunk() {
+        let work = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let dirs = vec![Path::new(FIXTURES_PATH)];
+
+        let cfg = Config::from(&work).unwrap();
+        let store = ChunkStore::new(&cfg).unwrap();
+        let entries = Entry::walk_into_vec(&dirs).unwrap();
+        let manifest = store.pack(&entries).unwrap();
+
+        for id in manifest.chunk_ids() {
+            let mut file = File::create(store.path(id)).unwrap();
+            let mut dst = snap::Writer::new(&mut file);
+            dst.write_all(b"replaced").unwrap();
+        }
+
+        let err = store
+            .unpack(&manifest, Some(dst.as_ref().to_path_buf()), &dirs)
+            .unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn retain_referenced() {
+        let work = testing::temp_dir();
+        let dirs = vec![Path::new(FIXTURES_PATH)];
+
+        let cfg = Config::from(&work).unwrap();
+        let store = ChunkStore::new(&cfg).unwrap();
+        let entries = Entry::walk_into_vec(&dirs).unwrap();
+        let manifest = store.pack(&entries).unwrap();
+
+        File::create(store.path("unknown")).unwrap();
+
+        assert_eq!(store.retain(&manifest.chunk_ids()).unwrap(), 1);
+        assert_eq!(store.retain(&manifest.chunk_ids()).unwrap(), 0);
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::crypto;
+use crate::errors::ResultExt;
+use crate::snapshot::{self, Diff, Entry, EntryKind};
+use crate::{Config, Error, Storage};
+
+/// The delta layout's index: a full base snapshot and deltas applied on top of it in order.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Chain {
+    pub base: Option<Link>,
+    #[serde(default)]
+    pub deltas: Vec<Link>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    pub name: String,
+    pub len: usize,
+    /// Tombstones, paths removed since the previous link.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<PathBuf>,
+}
+
+impl Link {
+    pub fn new(kind: &str, len: usize, removed: Vec<PathBuf>) -> Result<Self, Error> {
+        Ok(Link {
+            name: Link::name(kind)?,
+            len,
+            removed,
+        })
+    }
+
+    pub fn name(kind: &str) -> Result<String, Error> {
+        let mut id = [0; 8];
+        crypto::random_bytes(&mut id)?;
+        Ok(format!("{}-{}.snappy", kind, hex::encode(id)))
+    }
+
+    pub fn snapshot_file(&self, cfg: &Config) -> PathBuf {
+        cfg.deltas_dir.join(&self.name)
+    }
+
+    pub fn signature_file(&self, cfg: &Config) -> PathBuf {
+        cfg.deltas_dir.join(format!("{}.sig", self.name))
+    }
+}
+
+impl Chain {
+    pub fn read<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        if !path.as_ref().exists() {
+            return Ok(Chain::default());
+        }
+
+        let file = File::open(&path).io_err(&path)?;
+        serde_json::from_reader(&file).io_err(&path)
+    }
+
+    pub fn write<P>(&self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&path)
+            .io_err(&path)?;
+
+        serde_json::to_writer(&file, self).io_err(&path)
+    }
+
+    pub fn links(&self) -> impl Iterator<Item = &Link> {
+        self.base.iter().chain(self.deltas.iter())
+    }
+
+    /// Whether the next delta of `len` bytes should rather be a new base snapshot.
+    pub fn needs_compaction(&self, len: usize, max_deltas: usize, max_ratio: f64) -> bool {
+        let base_len = match &self.base {
+            Some(base) => base.len,
+            None => return true,
+        };
+
+        let deltas_len = self.deltas.iter().map(|it| it.len).sum::<usize>() + len;
+
+        self.deltas.len() >= max_deltas || deltas_len as f64 > base_len as f64 * max_ratio
+    }
+
+    /// Removes local snapshots which aren't referenced by the chain.
+    pub fn retain(&self, cfg: &Config) -> Result<(), Error> {
+        let mut names = HashSet::new();
+        names.insert(Config::chain_file_name().to_string());
+        names.insert(Config::chain_signature_file_name().to_string());
+
+        for link in self.links() {
+            names.insert(link.name.clone());
+            names.insert(format!("{}.sig", link.name));
+        }
+
+        for item in fs::read_dir(&cfg.deltas_dir).io_err(&cfg.deltas_dir)? {
+            let path = item.io_err(&cfg.deltas_dir)?.path();
+            let is_referenced = path
+                .file_name()
+                .and_then(|it| it.to_str())
+                .map(|it| names.contains(it))
+                .unwrap_or(false);
+
+            if !is_referenced {
+                debug!("Remove snapshot {:?}", path.as_os_str());
+                fs::remove_file(&path).io_err(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes remote snapshots of the previous chain which this one doesn't reference,
+    /// e.g. the base and deltas replaced by a compaction. Call it once this chain is uploaded.
+    pub fn retain_remote(&self, previous: &Chain, storage: &Storage) {
+        let names = self.links().map(|it| &it.name).collect::<HashSet<_>>();
+
+        for link in previous.links().filter(|it| !names.contains(&it.name)) {
+            for name in &[link.name.clone(), format!("{}.sig", link.name)] {
+                let key = storage.key_prefixed(name);
+                let res = storage.exists(&key).and_then(|exists| {
+                    if exists {
+                        storage.delete(&key)
+                    } else {
+                        Ok(())
+                    }
+                });
+
+                match res {
+                    Ok(()) => debug!("Remove remote snapshot {}", key),
+                    Err(err) => warn!("Cannot remove remote snapshot {}: {}", key, err),
+                }
+            }
+        }
+    }
+}
+
+/// Splits changes between two walks into entries to pack and tombstones. Paths which
+/// change their kind or symlinks are tombstoned too, so they never clash on unpack.
+pub fn delta(previous: &[Entry], current: &[Entry]) -> (Vec<Entry>, Vec<PathBuf>) {
+    let mut changed = HashSet::new();
+    let mut removed = Vec::new();
+
+    for it in snapshot::diff(previous, current) {
+        match it {
+            Diff::Added(path) => {
+                changed.insert(path);
+            }
+            Diff::Removed(path) => removed.push(path),
+            Diff::Changed { left, right } => {
+                if left.kind() == EntryKind::Symlink || left.kind() != right.kind() {
+                    removed.push(left.as_path().to_path_buf());
+                }
+                changed.insert(right.as_path().to_path_buf());
+            }
+        }
+    }
+
+    let entries = current
+        .iter()
+        .filter(|it| changed.contains(it.as_path()))
+        .cloned()
+        .collect();
+
+    removed.sort();
+
+    (entries, removed)
+}
+
+/// Removes tombstoned paths from the disk and from the unpacked entries.
+pub fn apply_tombstones<P>(
+    removed: &[PathBuf],
+    prefix: Option<PathBuf>,
+    dirs: &[P],
+    entries: &mut Vec<Entry>,
+) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    let prefixed = snapshot::prefixed(prefix);
+
+    for path in removed {
+        if !snapshot::is_include(dirs, path) {
+            continue;
+        }
+
+        let path = prefixed(path);
+        match fs::symlink_metadata(&path) {
+            Ok(ref meta) if meta.is_dir() => fs::remove_dir_all(&path).io_err(&path)?,
+            Ok(_) => fs::remove_file(&path).io_err(&path)?,
+            Err(_) => continue,
+        }
+    }
+
+    entries.retain(|it| !removed.iter().any(|path| it.as_path().starts_with(path)));
+
+    Ok(())
+}
+
+/// Replaces unpacked entries with the ones from a delta.
+pub fn merge(entries: &mut Vec<Entry>, delta: Vec<Entry>) {
+    let paths = delta.iter().map(Entry::as_path).collect::<HashSet<_>>();
+    entries.retain(|it| !paths.contains(it.as_path()));
+    entries.extend(delta);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::snapshot::Attributes;
+
+    fn link(len: usize) -> Link {
+        Link::new("delta", len, vec![]).unwrap()
+    }
+
+    #[test]
+    fn delta_of_entries() {
+        let attr = Attributes::new(0o644, 0, 0);
+        let previous = vec![
+            Entry::dir("d", attr),
+            Entry::file("d/a", attr, "a", 1).unwrap(),
+            Entry::file("d/b", attr, "b", 1).unwrap(),
+            Entry::symlink("d/c", "d/a", attr),
+        ];
+        let current = vec![
+            Entry::dir("d", attr),
+            Entry::file("d/a", attr, "a", 1).unwrap(),
+            Entry::file("d/c", attr, "c", 1).unwrap(),
+            Entry::file("d/e", attr, "e", 1).unwrap(),
+        ];
+
+        let (entries, removed) = delta(&previous, &current);
+
+        assert_eq!(entries, current[2..].to_vec());
+        assert_eq!(removed, vec![PathBuf::from("d/b"), PathBuf::from("d/c")]);
+    }
+
+    #[test]
+    fn merge_entries() {
+        let attr = Attributes::new(0o644, 0, 0);
+        let mut entries = vec![
+            Entry::dir("d", attr),
+            Entry::file("d/a", attr, "a", 1).unwrap(),
+            Entry::dir("d/x", attr),
+            Entry::file("d/x/b", attr, "b", 1).unwrap(),
+        ];
+
+        let removed = vec![PathBuf::from("d/x")];
+        apply_tombstones(&removed, None, &["/nowhere"], &mut entries).unwrap();
+        merge(
+            &mut entries,
+            vec![Entry::file("d/a", attr, "new", 1).unwrap()],
+        );
+
+        assert_eq!(
+            entries,
+            vec![
+                Entry::dir("d", attr),
+                Entry::file("d/a", attr, "new", 1).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn needs_compaction() {
+        let mut chain = Chain::default();
+        assert!(chain.needs_compaction(1, 10, 0.5));
+
+        chain.base = Some(link(100));
+        assert!(!chain.needs_compaction(10, 10, 0.5));
+        assert!(chain.needs_compaction(60, 10, 0.5));
+
+        chain.deltas = vec![link(10), link(10)];
+        assert!(chain.needs_compaction(40, 10, 0.5));
+        assert!(chain.needs_compaction(1, 2, 0.5));
+    }
+}
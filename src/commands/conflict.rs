@@ -0,0 +1,345 @@
+use std::fmt::{self, Display};
+use std::fs::{self, File, OpenOptions};
+use std::str::FromStr;
+
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::commands::group::Group;
+use crate::commands::push::read_cached_dirs;
+use crate::errors::ResultExt;
+use crate::{Config, Error, Storage};
+
+/// What a push does when the remote snapshot was replaced since the pull,
+/// e.g. by a build of another branch pushing the same key.
+///
+/// It's a check before the upload, not an atomic compare-and-swap, a push
+/// that starts in between still wins. A snapshot with a newer generation than
+/// the pulled one is never replaced, unless conflicts are ignored.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OnConflict {
+    Ignore,
+    Warn,
+    Refuse,
+}
+
+/// The tag and generation of a remote snapshot when it was pulled (or pushed) the last time.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Seen {
+    key: String,
+    etag: Option<String>,
+    #[serde(default)]
+    generation: Option<u64>,
+}
+
+impl Default for OnConflict {
+    fn default() -> Self {
+        OnConflict::Warn
+    }
+}
+
+impl OnConflict {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OnConflict::Ignore => "ignore",
+            OnConflict::Warn => "warn",
+            OnConflict::Refuse => "refuse",
+        }
+    }
+
+    pub fn variants() -> &'static [&'static str] {
+        &["ignore", "warn", "refuse"]
+    }
+}
+
+impl FromStr for OnConflict {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(OnConflict::Ignore),
+            "warn" => Ok(OnConflict::Warn),
+            "refuse" => Ok(OnConflict::Refuse),
+            _ => {
+                let err = format!("Unknown conflict policy '{}'", s);
+                Err(Error::storage(err))
+            }
+        }
+    }
+}
+
+impl Display for OnConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Remembers the current tags and generations of the remote snapshots, one per group for the split layout.
+pub(super) fn record(cfg: &Config, storage: &Storage) -> Result<(), Error> {
+    let path = &cfg.remote_file;
+    if path.exists() {
+        fs::remove_file(path).io_err(path)?;
+    }
+
+    if !storage.is_downloable() {
+        return Ok(());
+    }
+
+    let file_names = head_file_names(cfg, storage)?;
+    update(cfg, storage, &file_names)
+}
+
+/// Remembers the current tags and generations of the pushed snapshots, keeping the other ones.
+pub(super) fn update<S: AsRef<str>>(
+    cfg: &Config,
+    storage: &Storage,
+    file_names: &[S],
+) -> Result<(), Error> {
+    let mut all = read_all(cfg)?;
+
+    for file_name in file_names {
+        let file_name = file_name.as_ref();
+        let seen = Seen {
+            key: storage.key_prefixed(file_name),
+            etag: storage.etag(file_name)?,
+            generation: storage.remote_generation(file_name)?,
+        };
+        debug!("Remote snapshot {:?}", seen);
+
+        all.retain(|it| it.key != seen.key);
+        all.push(seen);
+    }
+
+    let path = &cfg.remote_file;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(path)
+        .io_err(path)?;
+    serde_json::to_writer(&file, &all).io_err(path)
+}
+
+/// Whether a push may replace the remote snapshot: it isn't newer than the pulled one
+/// and it's unchanged since the pull, or the policy lets the push overwrite it.
+pub(super) fn may_replace(
+    cfg: &Config,
+    storage: &Storage,
+    file_name: &str,
+    on_conflict: OnConflict,
+) -> Result<bool, Error> {
+    if on_conflict == OnConflict::Ignore {
+        return Ok(true);
+    }
+
+    if is_outdated(cfg, storage, file_name)? {
+        warn!("A newer snapshot was pushed since the pull, skip pushing");
+        return Ok(false);
+    }
+
+    check(cfg, storage, file_name, on_conflict)?;
+    Ok(true)
+}
+
+/// Whether a later build pushed a snapshot since the pull, pushing would replace a newer one.
+pub(super) fn is_outdated(cfg: &Config, storage: &Storage, file_name: &str) -> Result<bool, Error> {
+    let seen = match read_seen(cfg, storage, file_name)? {
+        Some(val) => val,
+        None => return Ok(false),
+    };
+
+    let outdated = match (storage.remote_generation(file_name)?, seen.generation) {
+        (Some(remote), Some(pulled)) => remote > pulled,
+        // there was nothing to pull, any snapshot pushed since is newer
+        (Some(_), None) => seen.etag.is_none(),
+        (None, _) => false,
+    };
+
+    if outdated {
+        warn!(
+            "Remote snapshot '{}' is newer than the pulled one",
+            seen.key
+        );
+    }
+    Ok(outdated)
+}
+
+/// Compares the current tag of the remote snapshot with the recorded one.
+pub(super) fn check(
+    cfg: &Config,
+    storage: &Storage,
+    file_name: &str,
+    on_conflict: OnConflict,
+) -> Result<(), Error> {
+    if on_conflict == OnConflict::Ignore {
+        return Ok(());
+    }
+
+    let seen = match read_seen(cfg, storage, file_name)? {
+        Some(val) => val,
+        None => return Ok(()),
+    };
+
+    if storage.etag(file_name)? == seen.etag {
+        return Ok(());
+    }
+
+    let msg = format!("Remote snapshot '{}' has changed since the pull", seen.key);
+    match on_conflict {
+        OnConflict::Refuse => Err(Error::storage(format!("{}, refusing to push", msg))),
+        _ => {
+            warn!("{}, overwriting it", msg);
+            Ok(())
+        }
+    }
+}
+
+/// Names of the remote snapshots a push replaces, the groups' ones for the split layout.
+fn head_file_names(cfg: &Config, storage: &Storage) -> Result<Vec<String>, Error> {
+    match storage.head_file_name() {
+        Some(val) => Ok(vec![val.to_string()]),
+        None => {
+            let cached_dirs = read_cached_dirs(&cfg.cached_dirs_file)?;
+            let groups = Group::all(cfg, &cached_dirs)?;
+            Ok(groups.iter().map(Group::file_name).collect())
+        }
+    }
+}
+
+/// The recorded remote snapshot under the storage's key.
+fn read_seen(cfg: &Config, storage: &Storage, file_name: &str) -> Result<Option<Seen>, Error> {
+    let key = storage.key_prefixed(file_name);
+    Ok(read_all(cfg)?.into_iter().find(|it| it.key == key))
+}
+
+fn read_all(cfg: &Config) -> Result<Vec<Seen>, Error> {
+    let path = &cfg.remote_file;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let file = File::open(path).io_err(path)?;
+    serde_json::from_reader(&file).io_err(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use url::Url;
+
+    use crate::storage::Layout;
+    use crate::testing::{self, A_FILE_PATH, FIXTURES_PATH, IS_DIR_PATH};
+
+    const SNAPSHOT: &str = "snapshot.snappy";
+
+    #[test]
+    fn check_conflicts() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .key_prefix("project");
+        let upload = || storage.upload_object("project/snapshot.snappy", A_FILE_PATH, 0);
+
+        record(&cfg, &storage).unwrap();
+        assert!(check(&cfg, &storage, SNAPSHOT, OnConflict::Refuse).is_ok());
+
+        upload().unwrap();
+        assert!(check(&cfg, &storage, SNAPSHOT, OnConflict::Refuse).is_err());
+        assert!(check(&cfg, &storage, SNAPSHOT, OnConflict::Warn).is_ok());
+
+        record(&cfg, &storage).unwrap();
+        assert!(check(&cfg, &storage, SNAPSHOT, OnConflict::Refuse).is_ok());
+
+        upload().unwrap();
+        assert!(check(&cfg, &storage, SNAPSHOT, OnConflict::Ignore).is_ok());
+
+        // another key doesn't conflict with the recorded one
+        let other = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .key_prefix("other");
+        assert!(check(&cfg, &other, SNAPSHOT, OnConflict::Refuse).is_ok());
+    }
+
+    #[test]
+    fn outdated_by_generation() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg).uri(uri.as_str()).unwrap();
+        let upload = |generation| {
+            storage
+                .clone()
+                .generation(generation)
+                .upload_object("snapshot.snappy", A_FILE_PATH, 0)
+        };
+
+        assert_eq!(is_outdated(&cfg, &storage, SNAPSHOT).unwrap(), false);
+
+        // pulled nothing, then another build pushed
+        record(&cfg, &storage).unwrap();
+        upload(2).unwrap();
+        assert_eq!(is_outdated(&cfg, &storage, SNAPSHOT).unwrap(), true);
+
+        record(&cfg, &storage).unwrap();
+        assert_eq!(is_outdated(&cfg, &storage, SNAPSHOT).unwrap(), false);
+
+        upload(1).unwrap();
+        assert_eq!(is_outdated(&cfg, &storage, SNAPSHOT).unwrap(), false);
+
+        upload(3).unwrap();
+        assert_eq!(is_outdated(&cfg, &storage, SNAPSHOT).unwrap(), true);
+    }
+
+    #[test]
+    fn outdated_groups() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+        let dirs = vec![PathBuf::from(FIXTURES_PATH), PathBuf::from(IS_DIR_PATH)];
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .layout(Layout::Split);
+        let groups = Group::all(&cfg, &dirs).unwrap();
+        let (first, second) = (groups[0].file_name(), groups[1].file_name());
+        let upload = |file_name: &str, generation| {
+            storage
+                .clone()
+                .generation(generation)
+                .upload_object(file_name, A_FILE_PATH, 0)
+        };
+
+        fs::write(&cfg.cached_dirs_file, serde_json::to_vec(&dirs).unwrap()).unwrap();
+        upload(&first, 1).unwrap();
+        upload(&second, 1).unwrap();
+        record(&cfg, &storage).unwrap();
+
+        upload(&second, 2).unwrap();
+        assert_eq!(is_outdated(&cfg, &storage, &first).unwrap(), false);
+        assert_eq!(is_outdated(&cfg, &storage, &second).unwrap(), true);
+        assert_eq!(
+            may_replace(&cfg, &storage, &second, OnConflict::Warn).unwrap(),
+            false
+        );
+        assert!(check(&cfg, &storage, &second, OnConflict::Refuse).is_err());
+
+        // pushing one group keeps the recorded state of the other
+        update(&cfg, &storage, &[&second]).unwrap();
+        upload(&first, 3).unwrap();
+        assert_eq!(is_outdated(&cfg, &storage, &first).unwrap(), true);
+        assert_eq!(is_outdated(&cfg, &storage, &second).unwrap(), false);
+    }
+}
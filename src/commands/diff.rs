@@ -0,0 +1,270 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde_derive::Serialize;
+
+use crate::commands::inspect::Source;
+use crate::commands::push::read_cached_dirs;
+use crate::errors::ResultExt;
+use crate::pretty;
+use crate::snapshot::{self, Entry};
+use crate::{Config, Error};
+
+/// What a snapshot is compared with.
+#[derive(Debug)]
+pub enum Target<'b> {
+    Snapshot(Source<'b>),
+    /// What's currently on disk under the cached directories.
+    Disk,
+}
+
+#[derive(Debug)]
+pub struct Diff<'a, 'b> {
+    cfg: &'a Config,
+    left: Source<'b>,
+    right: Target<'b>,
+    json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Change {
+    change: &'static str,
+    path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    left: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    right: Option<usize>,
+    #[serde(skip_serializing)]
+    line: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Changes {
+    added: usize,
+    removed: usize,
+    changed: usize,
+    added_bytes: usize,
+    removed_bytes: usize,
+    changed_bytes: i64,
+    delta_bytes: i64,
+    entries: Vec<Change>,
+}
+
+impl<'a, 'b> Diff<'a, 'b> {
+    pub fn new(cfg: &'a Config, left: Source<'b>, right: Target<'b>) -> Self {
+        Diff {
+            cfg,
+            left,
+            right,
+            json: false,
+        }
+    }
+
+    pub fn json(self, json: bool) -> Self {
+        Diff { json, ..self }
+    }
+
+    pub fn run<W: Write>(self, mut out: W) -> Result<(), Error> {
+        let Self {
+            cfg,
+            left,
+            right,
+            json,
+        } = self;
+        let key = cfg.encryption_key.as_ref();
+
+        let left = left.read_entries(key)?;
+        let (left, right) = match right {
+            Target::Snapshot(right) => (left, right.read_entries(key)?),
+            Target::Disk => on_disk(cfg, &left)?,
+        };
+
+        let changes = changes(&left, &right);
+
+        if json {
+            serde_json::to_writer_pretty(&mut out, &changes).snapshot_err("Write diff failed")?;
+            writeln!(out).snapshot_err("Write diff failed")
+        } else {
+            write_changes(&mut out, &changes).snapshot_err("Write diff failed")
+        }
+    }
+}
+
+/// Snapshot entries under the cached directories and entries currently on disk there.
+pub(super) fn on_disk(cfg: &Config, entries: &[Entry]) -> Result<(Vec<Entry>, Vec<Entry>), Error> {
+    let cached_dirs = read_cached_dirs(&cfg.cached_dirs_file)?;
+    let existing_dirs = cached_dirs
+        .iter()
+        .filter(|it| it.exists())
+        .collect::<Vec<_>>();
+
+    let entries = entries
+        .iter()
+        .filter(|it| snapshot::is_include(&cached_dirs, it.as_ref()))
+        .cloned()
+        .collect::<Vec<_>>();
+    let on_disk = Entry::walk_into_vec(&existing_dirs)?;
+
+    Ok((entries, on_disk))
+}
+
+pub(super) fn sorted(diff: HashSet<snapshot::Diff>) -> Vec<snapshot::Diff> {
+    let mut diff = diff.into_iter().collect::<Vec<_>>();
+    diff.sort_by(|a, b| a.as_path().cmp(b.as_path()));
+    diff
+}
+
+fn changes(left: &[Entry], right: &[Entry]) -> Changes {
+    let lens = |entries: &[Entry]| -> HashMap<PathBuf, usize> {
+        entries
+            .iter()
+            .map(|it| (it.as_path().to_path_buf(), len(it)))
+            .collect()
+    };
+    let (left_lens, right_lens) = (lens(left), lens(right));
+    let len_of = |lens: &HashMap<PathBuf, usize>, path: &Path| lens.get(path).cloned();
+
+    let mut changes = Changes::default();
+
+    for it in sorted(snapshot::diff(left, right)) {
+        let path = it.as_path().to_path_buf();
+        let (left, right) = (len_of(&left_lens, &path), len_of(&right_lens, &path));
+
+        let change = match it {
+            snapshot::Diff::Added(_) => {
+                changes.added += 1;
+                changes.added_bytes += right.unwrap_or(0);
+                "added"
+            }
+            snapshot::Diff::Removed(_) => {
+                changes.removed += 1;
+                changes.removed_bytes += left.unwrap_or(0);
+                "removed"
+            }
+            snapshot::Diff::Changed { .. } => {
+                changes.changed += 1;
+                changes.changed_bytes += right.unwrap_or(0) as i64 - left.unwrap_or(0) as i64;
+                "changed"
+            }
+        };
+
+        changes.entries.push(Change {
+            change,
+            path,
+            left,
+            right,
+            line: it.to_string(),
+        });
+    }
+
+    changes.delta_bytes =
+        changes.added_bytes as i64 - changes.removed_bytes as i64 + changes.changed_bytes;
+    changes
+}
+
+#[inline]
+fn len(entry: &Entry) -> usize {
+    entry.as_file().map_or(0, |(_, _, _, len)| len)
+}
+
+fn signed(bytes: i64) -> String {
+    if bytes < 0 {
+        format!("-{}", pretty::bytes(-bytes as usize))
+    } else {
+        format!("+{}", pretty::bytes(bytes as usize))
+    }
+}
+
+fn write_changes<W: Write>(out: &mut W, changes: &Changes) -> io::Result<()> {
+    for it in &changes.entries {
+        writeln!(out, "{}", it.line)?;
+    }
+
+    writeln!(
+        out,
+        "added {} ({}), removed {} ({}), changed {} ({}), total {}",
+        changes.added,
+        signed(changes.added_bytes as i64),
+        changes.removed,
+        signed(-(changes.removed_bytes as i64)),
+        changes.changed,
+        signed(changes.changed_bytes),
+        signed(changes.delta_bytes)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::Value;
+
+    use crate::snapshot::{Attributes, Writing};
+    use crate::testing;
+
+    #[test]
+    fn changes_of_entries() {
+        let attr = Attributes::new(0o644, 0, 0);
+        let left = vec![
+            Entry::file("a", attr, "a", 10).unwrap(),
+            Entry::file("b", attr, "b", 20).unwrap(),
+            Entry::dir("c", attr),
+        ];
+        let right = vec![
+            Entry::file("a", attr, "a", 10).unwrap(),
+            Entry::file("b", attr, "b2", 25).unwrap(),
+            Entry::file("d", attr, "d", 7).unwrap(),
+        ];
+
+        let changes = changes(&left, &right);
+
+        assert_eq!((changes.added, changes.added_bytes), (1, 7));
+        assert_eq!((changes.removed, changes.removed_bytes), (1, 0));
+        assert_eq!((changes.changed, changes.changed_bytes), (1, 5));
+        assert_eq!(changes.delta_bytes, 12);
+
+        let paths = changes
+            .entries
+            .iter()
+            .map(|it| &it.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec![Path::new("b"), Path::new("c"), Path::new("d")]);
+    }
+
+    #[test]
+    fn diff_snapshots() {
+        let work = testing::temp_dir();
+        let other = testing::temp_file(".snappy");
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let attr = Attributes::new(0o644, 0, 0);
+
+        let write = |path: &Path, entries: &[Entry]| {
+            let mut snapshot = Writing::open(path, None).unwrap();
+            for it in entries {
+                snapshot.write_entry(it).unwrap();
+            }
+            snapshot.finish().unwrap();
+        };
+
+        write(&cfg.snapshot_file, &[Entry::dir("a", attr)]);
+        write(
+            other.as_ref(),
+            &[Entry::dir("a", attr), Entry::dir("b", attr)],
+        );
+
+        let left = Source::File(cfg.snapshot_file.clone());
+        let right = Target::Snapshot(Source::File(other.as_ref().to_path_buf()));
+
+        let mut out = Vec::new();
+        Diff::new(&cfg, left, right)
+            .json(true)
+            .run(&mut out)
+            .unwrap();
+        let actual: Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(actual["added"], 1);
+        assert_eq!(actual["entries"][0]["path"], "b");
+        assert_eq!(actual["entries"][0]["change"], "added");
+    }
+}
@@ -0,0 +1,266 @@
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use libc::{c_int, sighandler_t};
+use log::{error, info, warn};
+
+use crate::commands::conflict::OnConflict;
+use crate::commands::pull::Pull;
+use crate::commands::push::Push;
+use crate::errors::ResultExt;
+use crate::{Config, Error, ServiceMessages, Storage};
+
+/// An exit code when the command couldn't be started, as shells do.
+pub const EXIT_NOT_STARTED: i32 = 127;
+
+const FORWARDED_SIGNALS: &[c_int] = &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT];
+
+static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+
+#[derive(Debug)]
+pub struct Exec<'a, 'b> {
+    cfg: &'a Config,
+    storage: &'b Storage,
+    fallback: Option<&'b Storage>,
+    cached_dirs: Vec<PathBuf>,
+    command: Vec<String>,
+    allow_unsigned: bool,
+    stream: bool,
+    push_on_failure: bool,
+    excludes: Vec<String>,
+    on_conflict: OnConflict,
+}
+
+impl<'a, 'b> Exec<'a, 'b> {
+    pub fn new<P, S>(
+        cfg: &'a Config,
+        storage: &'b Storage,
+        cached_dirs: &[P],
+        command: &[S],
+    ) -> Self
+    where
+        P: AsRef<Path>,
+        S: AsRef<str>,
+    {
+        Exec {
+            cfg,
+            storage,
+            fallback: None,
+            cached_dirs: cached_dirs
+                .iter()
+                .map(|it| it.as_ref().to_path_buf())
+                .collect(),
+            command: command.iter().map(|it| it.as_ref().to_string()).collect(),
+            allow_unsigned: false,
+            stream: false,
+            push_on_failure: false,
+            excludes: Vec::new(),
+            on_conflict: OnConflict::default(),
+        }
+    }
+
+    /// Where to pull from while there is no snapshot in the storage yet.
+    pub fn fallback(self, fallback: &'b Storage) -> Self {
+        Exec {
+            fallback: Some(fallback),
+            ..self
+        }
+    }
+
+    pub fn allow_unsigned(self, allow_unsigned: bool) -> Self {
+        Exec {
+            allow_unsigned,
+            ..self
+        }
+    }
+
+    pub fn stream(self, stream: bool) -> Self {
+        Exec { stream, ..self }
+    }
+
+    /// Push even when the command exits with a non-zero status.
+    pub fn push_on_failure(self, push_on_failure: bool) -> Self {
+        Exec {
+            push_on_failure,
+            ..self
+        }
+    }
+
+    /// Glob patterns of entries that aren't pushed.
+    pub fn excludes<S: AsRef<str>>(self, excludes: &[S]) -> Self {
+        Exec {
+            excludes: excludes.iter().map(|it| it.as_ref().to_string()).collect(),
+            ..self
+        }
+    }
+
+    pub fn on_conflict(self, on_conflict: OnConflict) -> Self {
+        Exec {
+            on_conflict,
+            ..self
+        }
+    }
+
+    /// Pulls, runs the command and pushes, returns the command's exit code.
+    ///
+    /// A failed pull or push is logged and doesn't change the exit code,
+    /// the cache only makes the command faster.
+    pub fn run(self) -> Result<i32, Error> {
+        let Self {
+            cfg,
+            storage,
+            fallback,
+            cached_dirs,
+            command,
+            allow_unsigned,
+            stream,
+            push_on_failure,
+            excludes,
+            on_conflict,
+        } = self;
+
+        let (program, args) = match command.split_first() {
+            Some(it) => it,
+            None => return Error::io_err(Path::new(""), "No command to execute"),
+        };
+
+        let mut pull = Pull::new(cfg, storage, &cached_dirs, None::<PathBuf>)
+            .allow_unsigned(allow_unsigned)
+            .stream(stream);
+        if let Some(fallback) = fallback {
+            pull = pull.fallback(fallback);
+        }
+        let pulled = pull.run();
+        if let Err(err) = pulled {
+            error!("Pull failed, running without the cache: {}", err);
+            ServiceMessages::current().problem(&err);
+        }
+
+        info!("Executing {:?} ...", command.join(" "));
+        let child = Command::new(program)
+            .args(args)
+            .spawn()
+            .io_err(Path::new(program))?;
+        let status = wait(child).io_err(Path::new(program))?;
+        let code = exit_code(status);
+
+        if code == 0 || push_on_failure {
+            if code != 0 {
+                warn!("Command exited with {}, pushing anyway", code);
+            }
+            let push = Push::new(cfg, storage)
+                .excludes(&excludes)
+                .on_conflict(on_conflict);
+            if let Err(err) = push.run() {
+                error!("Push failed: {}", err);
+                ServiceMessages::current().problem(&err);
+            }
+        } else {
+            warn!("Command exited with {}, skip pushing", code);
+        }
+
+        Ok(code)
+    }
+}
+
+/// Waits for the child, forwarding termination signals to it meanwhile.
+fn wait(mut child: Child) -> std::io::Result<ExitStatus> {
+    CHILD_PID.store(child.id() as i32, Ordering::SeqCst);
+
+    let previous = FORWARDED_SIGNALS
+        .iter()
+        .map(|&signal| {
+            let handler = forward as extern "C" fn(c_int) as sighandler_t;
+            (signal, unsafe { libc::signal(signal, handler) })
+        })
+        .collect::<Vec<_>>();
+
+    let status = child.wait();
+
+    for (signal, handler) in previous {
+        unsafe { libc::signal(signal, handler) };
+    }
+    CHILD_PID.store(0, Ordering::SeqCst);
+
+    status
+}
+
+extern "C" fn forward(signal: c_int) {
+    let pid = CHILD_PID.load(Ordering::SeqCst);
+    if pid > 0 {
+        unsafe { libc::kill(pid, signal) };
+    }
+}
+
+/// The status code, or 128 plus a signal's number when the child was killed.
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use url::Url;
+
+    use crate::testing;
+
+    #[test]
+    fn exec_pushes_on_success_only() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let cached = testing::temp_dir();
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .uploadable(true);
+        let dirs = vec![cached.as_ref().to_path_buf()];
+        let uploaded = remote.as_ref().join(Config::snapshot_file_name());
+
+        let write = format!("echo a > {:?}/a.txt; exit 3", cached.as_ref());
+        let code = Exec::new(&cfg, &storage, &dirs, &["sh", "-c", &write])
+            .run()
+            .unwrap();
+        assert_eq!(code, 3);
+        assert_eq!(uploaded.exists(), false);
+
+        let code = Exec::new(&cfg, &storage, &dirs, &["sh", "-c", "exit 0"])
+            .run()
+            .unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(uploaded.exists(), true);
+    }
+
+    #[test]
+    fn exec_push_on_failure() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let cached = testing::temp_dir();
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .uploadable(true);
+        let dirs = vec![cached.as_ref().to_path_buf()];
+
+        let write = format!("echo a > {:?}/a.txt; kill -TERM $$", cached.as_ref());
+        let code = Exec::new(&cfg, &storage, &dirs, &["sh", "-c", &write])
+            .push_on_failure(true)
+            .run()
+            .unwrap();
+
+        assert_eq!(code, 128 + libc::SIGTERM);
+        assert!(remote.as_ref().join(Config::snapshot_file_name()).exists());
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt::{self, Display};
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::info;
+use tar::{Builder, EntryType, Header};
+
+use crate::commands::inspect::Source;
+use crate::errors::ResultExt;
+use crate::snapshot::{EntryKind, Reading};
+use crate::{Config, Error};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Tar,
+    /// A tar compressed with zstd.
+    TarZst,
+}
+
+#[derive(Debug)]
+pub struct Export<'a, 'b> {
+    cfg: &'a Config,
+    source: Source<'b>,
+    output: PathBuf,
+    format: Format,
+}
+
+impl Format {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::Tar => "tar",
+            Format::TarZst => "tar.zst",
+        }
+    }
+
+    pub fn variants() -> &'static [&'static str] {
+        &["tar", "tar.zst"]
+    }
+
+    /// Guesses a format by a file's extension, a plain tar unless it ends with `.zst`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension() {
+            Some(ext) if ext == "zst" => Format::TarZst,
+            _ => Format::Tar,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tar" => Ok(Format::Tar),
+            "tar.zst" => Ok(Format::TarZst),
+            _ => Error::snapshot_err("Unknown format", s.to_string()),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl<'a, 'b> Export<'a, 'b> {
+    pub fn new<P>(cfg: &'a Config, source: Source<'b>, output: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Export {
+            cfg,
+            source,
+            format: Format::from_path(&output),
+            output: output.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn format(self, format: Format) -> Self {
+        Export { format, ..self }
+    }
+
+    /// Converts a snapshot into a tar, paths are relative to '/'.
+    pub fn run(self) -> Result<usize, Error> {
+        let Self {
+            cfg,
+            source,
+            output,
+            format,
+        } = self;
+
+        let mut snapshot = Reading::from(source.open()?, cfg.encryption_key.as_ref())?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&output)
+            .io_err(&output)?;
+
+        let written = match format {
+            Format::Tar => write_tar(&mut snapshot, file)?.1,
+            Format::TarZst => {
+                let encoder = zstd::Encoder::new(file, 0).io_err(&output)?;
+                let (encoder, written) = write_tar(&mut snapshot, encoder)?;
+                encoder.finish().io_err(&output)?;
+                written
+            }
+        };
+
+        info!("Exported {} entries into {:?}", written, output);
+        Ok(written)
+    }
+}
+
+fn write_tar<R, W>(snapshot: &mut Reading<R>, dst: W) -> Result<(W, usize), Error>
+where
+    R: Read,
+    W: Write,
+{
+    let mut builder = Builder::new(dst);
+    let mut written = 0;
+
+    while let Some((entry, _)) = snapshot.read_entry()? {
+        let path = entry.as_path();
+        let path = path.strip_prefix("/").unwrap_or(path);
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let attr = entry.as_attr();
+
+        let mut header = Header::new_gnu();
+        header.set_mode(attr.mode & 0o7777);
+        header.set_mtime(attr.mtime.max(0) as u64);
+        header.set_size(0);
+
+        let res = match entry.kind() {
+            EntryKind::Dir => {
+                header.set_entry_type(EntryType::Directory);
+                builder.append_data(&mut header, path, io::empty())
+            }
+            EntryKind::Symlink => {
+                let (_, target, _) = entry.as_symlink().unwrap();
+                header.set_entry_type(EntryType::Symlink);
+                header
+                    .set_link_name(target)
+                    .and_then(|_| builder.append_data(&mut header, path, io::empty()))
+            }
+            EntryKind::File => {
+                let (_, _, _, len) = entry.as_file().unwrap();
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(len as u64);
+                builder.append_data(&mut header, path, snapshot.take(len))
+            }
+        };
+
+        res.map_err(|err| Error::snapshot_io(format!("Write {:?} into tar failed", path))(err))?;
+        written += 1;
+    }
+
+    let dst = builder
+        .into_inner()
+        .map_err(Error::snapshot_io("Write tar failed"))?;
+
+    Ok((dst, written))
+}
@@ -0,0 +1,110 @@
+use std::path::{Path, PathBuf};
+
+use glob::Pattern;
+use log::{info, warn};
+
+use crate::commands::inspect::Source;
+use crate::snapshot::{self, Entry, Reading, Unpack};
+use crate::{Config, Error};
+
+#[derive(Debug)]
+pub struct Extract<'a, 'b> {
+    cfg: &'a Config,
+    source: Source<'b>,
+    patterns: Vec<String>,
+    output_dir: PathBuf,
+}
+
+impl<'a, 'b> Extract<'a, 'b> {
+    pub fn new<S, P>(cfg: &'a Config, source: Source<'b>, patterns: &[S], output_dir: P) -> Self
+    where
+        S: AsRef<str>,
+        P: AsRef<Path>,
+    {
+        Extract {
+            cfg,
+            source,
+            patterns: patterns.iter().map(|it| it.as_ref().to_string()).collect(),
+            output_dir: output_dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Unpacks entries matching any of the patterns into the output directory,
+    /// keeping their paths as `pull --prefix` does.
+    pub fn run(self) -> Result<Vec<Entry>, Error> {
+        let Self {
+            cfg,
+            source,
+            patterns,
+            output_dir,
+        } = self;
+
+        let patterns = patterns
+            .iter()
+            .map(|it| {
+                Pattern::new(it).map_err(Error::snapshot(format!("Invalid pattern '{}'", it)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let snapshot = Reading::from(source.open()?, cfg.encryption_key.as_ref())?;
+        let (entries, len) = snapshot.unpack_with(Some(output_dir.clone()), |path| {
+            snapshot::is_glob_include(&patterns, path)
+        })?;
+
+        if entries.is_empty() {
+            warn!("Nothing matches {:?}", patterns);
+        } else {
+            info!(
+                "Extracted {} entries ({} bytes) into {:?}",
+                entries.len(),
+                len,
+                output_dir
+            );
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::hashing::md5;
+    use crate::testing::{self, A_FILE_PATH, B_FILE_PATH, FIXTURES_PATH};
+
+    #[test]
+    fn extract_matching() {
+        let work = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+
+        testing::pack(&cfg.snapshot_file, &[FIXTURES_PATH]);
+
+        let source = Source::File(cfg.snapshot_file.clone());
+        let entries = Extract::new(&cfg, source, &["**/a.txt"], &dst)
+            .run()
+            .unwrap();
+
+        assert_eq!(entries.len(), 1);
+
+        let extracted = dst.as_ref().join(A_FILE_PATH);
+        assert_eq!(
+            md5::path(&extracted).unwrap(),
+            md5::path(A_FILE_PATH).unwrap()
+        );
+        assert_eq!(dst.as_ref().join(B_FILE_PATH).exists(), false);
+    }
+
+    #[test]
+    fn extract_invalid_pattern() {
+        let work = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+
+        let source = Source::File(cfg.snapshot_file.clone());
+        let result = Extract::new(&cfg, source, &["a/***"], &dst).run();
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,509 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+use crate::chunks::Manifest;
+use crate::errors::ResultExt;
+use crate::pretty;
+use crate::project::BRANCHES_KEY;
+use crate::storage::Object;
+use crate::{Config, Error, Storage};
+
+const SECS_IN_DAY: i64 = 24 * 60 * 60;
+// chunks are shared between keys, a manifest of any key may refer to them
+const CHUNKS_KEY_PREFIX: &str = "chunks/";
+// a hex MD5 digest of key files
+const DIGEST_LEN: usize = 32;
+
+#[derive(Debug)]
+pub struct Gc<'a> {
+    storage: &'a Storage,
+    prefix: Option<String>,
+    older_than: Option<u64>,
+    branches_older_than: Option<u64>,
+    keep: Option<usize>,
+    chunks: Option<(&'a Config, u64)>,
+    dry_run: bool,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Reclaimed {
+    pub caches: usize,
+    pub objects: usize,
+    pub bytes: usize,
+}
+
+/// Objects sharing a key prefix, e.g. a snapshot and its signature.
+#[derive(Debug, Default)]
+struct Cache {
+    objects: Vec<Object>,
+    modified: i64,
+}
+
+impl<'a> Gc<'a> {
+    pub fn new(storage: &'a Storage) -> Self {
+        Gc {
+            storage,
+            prefix: None,
+            older_than: None,
+            branches_older_than: None,
+            keep: None,
+            chunks: None,
+            dry_run: false,
+        }
+    }
+
+    /// Only look at caches which keys start with the prefix, by default the storage's project key.
+    pub fn prefix<S: Into<String>>(self, prefix: S) -> Self {
+        Gc {
+            prefix: Some(prefix.into()),
+            ..self
+        }
+    }
+
+    /// Delete caches not pushed for that many days.
+    pub fn older_than(self, days: u64) -> Self {
+        Gc {
+            older_than: Some(days),
+            ..self
+        }
+    }
+
+    /// Delete caches of feature branches not pushed for that many days.
+    pub fn branches_older_than(self, days: u64) -> Self {
+        Gc {
+            branches_older_than: Some(days),
+            ..self
+        }
+    }
+
+    /// Keep only that many of the most recently pushed versions of each cache,
+    /// i.e. caches which keys differ only by the digest of key files.
+    pub fn keep(self, keep: usize) -> Self {
+        Gc {
+            keep: Some(keep),
+            ..self
+        }
+    }
+
+    /// Delete chunks not pushed for that many days which no remaining manifest refers to.
+    /// Chunks are shared between all keys, so the manifests of all keys are downloaded
+    /// and read with the config's key, a manifest that can't be read skips the deletion.
+    ///
+    /// The grace period protects chunks uploaded by a push that hasn't uploaded its manifest yet.
+    pub fn chunks(self, cfg: &'a Config, days: u64) -> Self {
+        Gc {
+            chunks: Some((cfg, days)),
+            ..self
+        }
+    }
+
+    pub fn dry_run(self, dry_run: bool) -> Self {
+        Gc { dry_run, ..self }
+    }
+
+    pub fn run(self) -> Result<Reclaimed, Error> {
+        info!("Listing remote objects ...");
+
+        let prefix = match (&self.prefix, self.storage.as_project()) {
+            (Some(prefix), _) => prefix.to_string(),
+            (None, Some(project)) => format!("{}/", project),
+            (None, None) => String::new(),
+        };
+
+        let caches = caches(self.storage.list(&prefix)?);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|it| it.as_secs() as i64)
+            .unwrap_or(0);
+
+        let mut expired = BTreeSet::new();
+
+        if let Some(days) = self.older_than {
+            let deadline = now - days as i64 * SECS_IN_DAY;
+            expired.extend(
+                caches
+                    .iter()
+                    .filter(|(_, cache)| cache.modified < deadline)
+                    .map(|(key, _)| key.as_str()),
+            );
+        }
+
+        if let Some(days) = self.branches_older_than {
+            let deadline = now - days as i64 * SECS_IN_DAY;
+            expired.extend(
+                caches
+                    .iter()
+                    .filter(|(key, cache)| is_branch(key) && cache.modified < deadline)
+                    .map(|(key, _)| key.as_str()),
+            );
+        }
+
+        if let Some(keep) = self.keep {
+            let mut versions = BTreeMap::new();
+            for (key, cache) in &caches {
+                versions
+                    .entry(without_digest(key))
+                    .or_insert_with(Vec::new)
+                    .push((cache.modified, key.as_str()));
+            }
+
+            for (_, mut keys) in versions {
+                keys.sort_by(|a, b| b.cmp(a));
+                expired.extend(keys.into_iter().skip(keep).map(|(_, key)| key));
+            }
+        }
+
+        let mut reclaimed = Reclaimed::default();
+
+        for key in &expired {
+            let cache = &caches[*key];
+            let days = (now - cache.modified) / SECS_IN_DAY;
+            info!("Deleting '{}', pushed {} days ago ...", key, days);
+
+            for it in &cache.objects {
+                if !self.dry_run {
+                    self.storage.delete(&it.key)?;
+                }
+                reclaimed.objects += 1;
+                reclaimed.bytes += it.len;
+            }
+            reclaimed.caches += 1;
+        }
+
+        if let Some((cfg, days)) = self.chunks {
+            let deleted = expired
+                .iter()
+                .flat_map(|key| caches[*key].objects.iter().map(|it| it.key.as_str()))
+                .collect::<HashSet<_>>();
+            let deadline = now - days as i64 * SECS_IN_DAY;
+
+            for it in self.unreferenced_chunks(cfg, &deleted)? {
+                if it.modified >= deadline {
+                    continue;
+                }
+
+                info!("Deleting unreferenced chunk '{}' ...", it.key);
+                if !self.dry_run {
+                    self.storage.delete(&it.key)?;
+                }
+                reclaimed.objects += 1;
+                reclaimed.bytes += it.len;
+            }
+        }
+
+        let verb = if self.dry_run {
+            "Would reclaim"
+        } else {
+            "Reclaimed"
+        };
+        info!(
+            "{} {} in {} caches ({} objects)",
+            verb,
+            pretty::bytes(reclaimed.bytes),
+            reclaimed.caches,
+            reclaimed.objects
+        );
+
+        Ok(reclaimed)
+    }
+
+    /// Remote chunks which none of the manifests, except the deleted ones, refers to.
+    /// It's empty when some manifest can't be downloaded or read.
+    fn unreferenced_chunks(
+        &self,
+        cfg: &Config,
+        deleted: &HashSet<&str>,
+    ) -> Result<Vec<Object>, Error> {
+        info!("Listing referenced chunks ...");
+
+        let objects = self.storage.list("")?;
+        let manifests = objects.iter().filter(|it| {
+            parent_and_name(&it.key).1 == Config::manifest_file_name()
+                && !deleted.contains(it.key.as_str())
+        });
+
+        let path = cfg
+            .working_dir
+            .join(format!("{}.gc", Config::manifest_file_name()));
+        let mut referenced = HashSet::new();
+
+        for it in manifests {
+            let res = self
+                .storage
+                .download_object(it.key.as_str(), &path)
+                .and_then(|_| Manifest::read(&path, cfg.encryption_key.as_ref()));
+            remove_file(&path)?;
+
+            match res {
+                Ok(manifest) => {
+                    referenced.extend(manifest.chunk_ids().into_iter().map(str::to_string))
+                }
+                Err(err) => {
+                    warn!(
+                        "Cannot read manifest '{}', keep all chunks: {}",
+                        it.key, err
+                    );
+                    return Ok(Vec::new());
+                }
+            }
+        }
+
+        Ok(objects
+            .into_iter()
+            .filter(|it| {
+                it.key.starts_with(CHUNKS_KEY_PREFIX)
+                    && !referenced.contains(&it.key[CHUNKS_KEY_PREFIX.len()..])
+            })
+            .collect())
+    }
+}
+
+fn remove_file(path: &Path) -> Result<(), Error> {
+    if path.exists() {
+        fs::remove_file(path).io_err(path)?;
+    }
+    Ok(())
+}
+
+fn caches(objects: Vec<Object>) -> BTreeMap<String, Cache> {
+    let mut caches = BTreeMap::new();
+
+    for it in objects {
+        if it.key.starts_with(CHUNKS_KEY_PREFIX) {
+            continue;
+        }
+
+        let cache = caches
+            .entry(parent(&it.key).to_string())
+            .or_insert_with(Cache::default);
+        cache.modified = cache.modified.max(it.modified);
+        cache.objects.push(it);
+    }
+
+    caches
+}
+
+/// Whether it's a key of a feature branch's cache, i.e. `<project>/branches/<branch>/...`.
+#[inline]
+fn is_branch(key: &str) -> bool {
+    key.split('/').skip(1).any(|it| it == BRANCHES_KEY)
+}
+
+/// A cache's key without the trailing digest of its key files, shared by its versions.
+fn without_digest(key: &str) -> &str {
+    let is_digest = |it: &str| it.len() == DIGEST_LEN && it.bytes().all(|b| b.is_ascii_hexdigit());
+
+    match key.rfind('/') {
+        Some(idx) if is_digest(&key[idx + 1..]) => &key[..idx],
+        None if is_digest(key) => "",
+        _ => key,
+    }
+}
+
+#[inline]
+fn parent(key: &str) -> &str {
+    parent_and_name(key).0
+}
+
+#[inline]
+fn parent_and_name(key: &str) -> (&str, &str) {
+    match key.rfind('/') {
+        Some(idx) => (&key[..idx], &key[idx + 1..]),
+        None => ("", key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+
+    use filetime::{self, FileTime};
+    use url::Url;
+
+    use crate::chunks::ManifestEntry;
+    use crate::snapshot::Entry;
+    use crate::testing::{self, FIXTURES_PATH};
+
+    fn days_ago(days: i64) -> FileTime {
+        let now = FileTime::from_system_time(SystemTime::now()).unix_seconds();
+        FileTime::from_unix_time(now - days * SECS_IN_DAY, 0)
+    }
+
+    fn put(remote: &Path, objects: &[(&str, i64)]) {
+        for (key, days) in objects {
+            let path = remote.join(key);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "content").unwrap();
+            filetime::set_file_mtime(&path, days_ago(*days)).unwrap();
+        }
+    }
+
+    #[test]
+    fn gc() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg).uri(uri.as_str()).unwrap();
+
+        put(
+            remote.as_ref(),
+            &[
+                ("project/a/snapshot.snappy", 1),
+                ("project/a/snapshot.snappy.sig", 1),
+                ("project/b/snapshot.snappy", 10),
+                ("project/c/snapshot.snappy", 20),
+                ("other/snapshot.snappy", 30),
+                ("project/branches/old/snapshot.snappy", 8),
+                ("project/branches/new/snapshot.snappy", 2),
+                ("chunks/0011", 40),
+            ],
+        );
+
+        let exists = |key: &str| remote.as_ref().join(key).exists();
+
+        // every cache has a single version
+        let reclaimed = Gc::new(&storage).keep(1).dry_run(true).run().unwrap();
+        assert_eq!(reclaimed.caches, 0);
+
+        let reclaimed = Gc::new(&storage).branches_older_than(7).run().unwrap();
+        assert_eq!(reclaimed.caches, 1);
+        assert!(!exists("project/branches/old/snapshot.snappy"));
+        assert!(exists("project/branches/new/snapshot.snappy"));
+        assert!(exists("project/b/snapshot.snappy"));
+
+        let reclaimed = Gc::new(&storage).older_than(15).run().unwrap();
+        assert_eq!((reclaimed.caches, reclaimed.bytes), (2, 14));
+        assert!(!exists("project/c/snapshot.snappy"));
+        assert!(!exists("other/snapshot.snappy"));
+        assert!(exists("chunks/0011"));
+    }
+
+    #[test]
+    fn keep_versions_of_each_cache() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let (old, new) = ("0".repeat(DIGEST_LEN), "f".repeat(DIGEST_LEN));
+        let key = |prefix: &str, digest: &str| format!("{}/{}/snapshot.snappy", prefix, digest);
+        let objects = [
+            (key("project", &old), 5),
+            (key("project", &new), 2),
+            (key("project/deps", &old), 4),
+            (key("project/deps", &new), 3),
+            (key("project/build", &old), 6),
+            ("other/snapshot.snappy".to_string(), 9),
+            (key("other", &old), 8),
+        ];
+        let objects = objects
+            .iter()
+            .map(|(key, days)| (key.as_str(), *days))
+            .collect::<Vec<_>>();
+        put(remote.as_ref(), &objects);
+
+        let exists = |key: &str| remote.as_ref().join(key).exists();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .project("project");
+
+        // only older versions of the project's own caches, other projects are out of scope
+        let reclaimed = Gc::new(&storage).keep(1).run().unwrap();
+        assert_eq!(reclaimed.caches, 2);
+        assert!(!exists(&key("project", &old)));
+        assert!(exists(&key("project", &new)));
+        assert!(!exists(&key("project/deps", &old)));
+        assert!(exists(&key("project/deps", &new)));
+        assert!(exists(&key("project/build", &old)));
+        assert!(exists(&key("other", &old)));
+
+        // a snapshot without key files is a version of the same cache
+        let reclaimed = Gc::new(&storage).prefix("other/").keep(1).run().unwrap();
+        assert_eq!(reclaimed.caches, 1);
+        assert!(!exists("other/snapshot.snappy"));
+    }
+
+    #[test]
+    fn delete_unreferenced_chunks() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .project("project");
+
+        let entry = Entry::walk_into_vec(&[FIXTURES_PATH]).unwrap().remove(0);
+        let manifest = |key: &str, chunks: &[&str], days| {
+            let manifest = Manifest {
+                entries: vec![ManifestEntry {
+                    entry: entry.clone(),
+                    chunks: chunks.iter().map(|it| it.to_string()).collect(),
+                }],
+            };
+            let path = remote.as_ref().join(key);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            manifest.write(&path, None).unwrap();
+            filetime::set_file_mtime(&path, days_ago(days)).unwrap();
+        };
+
+        manifest("project/a/manifest.snappy", &["0011"], 1);
+        manifest("project/b/manifest.snappy", &["0022"], 20);
+        // chunks are shared, another project's manifest refers to them as well
+        manifest("other/manifest.snappy", &["0033"], 20);
+        put(
+            remote.as_ref(),
+            &[
+                ("chunks/0011", 40),
+                ("chunks/0022", 40),
+                ("chunks/0033", 40),
+                ("chunks/0044", 40),
+                ("chunks/0055", 1),
+            ],
+        );
+
+        let exists = |key: &str| remote.as_ref().join(key).exists();
+
+        let reclaimed = Gc::new(&storage)
+            .older_than(15)
+            .chunks(&cfg, 7)
+            .dry_run(true)
+            .run()
+            .unwrap();
+        assert_eq!((reclaimed.caches, reclaimed.objects), (1, 3));
+        assert!(exists("chunks/0022"));
+
+        Gc::new(&storage)
+            .older_than(15)
+            .chunks(&cfg, 7)
+            .run()
+            .unwrap();
+        assert!(!exists("project/b/manifest.snappy"));
+        assert!(exists("chunks/0011"));
+        assert!(!exists("chunks/0022"));
+        assert!(exists("chunks/0033"));
+        assert!(!exists("chunks/0044"));
+        // a push may not have uploaded its manifest yet
+        assert!(exists("chunks/0055"));
+
+        // an unreadable manifest keeps all chunks
+        put(remote.as_ref(), &[("project/c/manifest.snappy", 1)]);
+        put(remote.as_ref(), &[("chunks/0066", 40)]);
+        let reclaimed = Gc::new(&storage).chunks(&cfg, 7).run().unwrap();
+        assert_eq!(reclaimed.objects, 0);
+        assert!(exists("chunks/0066"));
+    }
+}
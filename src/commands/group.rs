@@ -0,0 +1,104 @@
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::errors::ResultExt;
+use crate::hashing::md5;
+use crate::snapshot::{self, Entry};
+use crate::{Config, Error};
+
+/// A cached directory packed into its own snapshot, so it's diffed, uploaded
+/// and downloaded independently of the others.
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub dir: PathBuf,
+    pub snapshot_file: PathBuf,
+    pub signature_file: PathBuf,
+}
+
+impl Group {
+    pub fn all<P>(cfg: &Config, dirs: &[P]) -> Result<Vec<Group>, Error>
+    where
+        P: AsRef<Path>,
+    {
+        if !cfg.groups_dir.exists() {
+            fs::create_dir_all(&cfg.groups_dir).io_err(&cfg.groups_dir)?;
+        }
+
+        Ok(dirs.iter().map(|it| Group::new(cfg, it.as_ref())).collect())
+    }
+
+    fn new(cfg: &Config, dir: &Path) -> Self {
+        let name = group_name(dir);
+
+        Group {
+            dir: dir.to_path_buf(),
+            snapshot_file: cfg.groups_dir.join(format!("{}.snappy", name)),
+            signature_file: cfg.groups_dir.join(format!("{}.snappy.sig", name)),
+        }
+    }
+
+    /// The name of the group's snapshot, in the remote location too.
+    pub fn file_name(&self) -> String {
+        let name = self.snapshot_file.file_name().unwrap_or_default();
+        name.to_string_lossy().into_owned()
+    }
+
+    pub fn entries(&self, entries: &[Entry]) -> Vec<Entry> {
+        let dirs = [&self.dir];
+        entries
+            .iter()
+            .filter(|it| snapshot::is_include(&dirs, it.as_ref()))
+            .cloned()
+            .collect()
+    }
+}
+
+// a readable part for humans plus a hash of the full path, so names never collide
+fn group_name(dir: &Path) -> String {
+    let base = dir
+        .file_name()
+        .map(|it| it.to_string_lossy())
+        .unwrap_or_else(|| "root".into())
+        .chars()
+        .map(|it| match it {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => it,
+            _ => '_',
+        })
+        .collect::<String>();
+
+    let hash = md5::bytes(dir.as_os_str().as_bytes());
+
+    format!("{}-{}", base, &hash[..8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{self, FIXTURES_PATH, IS_DIR_PATH};
+
+    #[test]
+    fn group_names() {
+        assert_eq!(group_name(Path::new("/")), "root-6666cd76");
+        assert_eq!(group_name(Path::new("/home/user/.m2")), ".m2-f9768f8a");
+        assert_ne!(
+            group_name(Path::new("/a/.m2")),
+            group_name(Path::new("/b/.m2"))
+        );
+    }
+
+    #[test]
+    fn group_entries() {
+        let work = testing::temp_dir();
+        let cfg = Config::from(&work).unwrap();
+        let dirs = vec![PathBuf::from(FIXTURES_PATH), PathBuf::from(IS_DIR_PATH)];
+
+        let entries = Entry::walk_into_vec(&dirs[..1]).unwrap();
+        let groups = Group::all(&cfg, &dirs).unwrap();
+
+        assert_eq!(groups[0].entries(&entries), entries);
+        assert!(groups[1].entries(&entries).len() < entries.len());
+        assert_ne!(groups[0].snapshot_file, groups[1].snapshot_file);
+    }
+}
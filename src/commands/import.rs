@@ -0,0 +1,308 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use log::{info, warn};
+use tar::{Archive, EntryType};
+
+use crate::commands::export::Format;
+use crate::commands::push;
+use crate::crypto::Signature;
+use crate::errors::ResultExt;
+use crate::hashing::md5;
+use crate::snapshot::{self, Attributes, Entry, Writing};
+use crate::{Config, Error, Storage};
+
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFLNK: u32 = 0o120_000;
+
+#[derive(Debug)]
+pub struct Import<'a, 'b> {
+    cfg: &'a Config,
+    input: PathBuf,
+    format: Format,
+    prefix: Option<PathBuf>,
+    storage: Option<&'b Storage>,
+}
+
+impl<'a, 'b> Import<'a, 'b> {
+    pub fn new<P>(cfg: &'a Config, input: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Import {
+            cfg,
+            format: Format::from_path(&input),
+            input: input.as_ref().to_path_buf(),
+            prefix: None,
+            storage: None,
+        }
+    }
+
+    pub fn format(self, format: Format) -> Self {
+        Import { format, ..self }
+    }
+
+    /// Place tar's paths under the prefix, e.g. '/' for a tar made by `export`.
+    pub fn prefix<P>(self, prefix: Option<P>) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Import {
+            prefix: prefix.map(|it| it.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Upload the snapshot as the cache of a remote location's key.
+    pub fn upload(self, storage: &'b Storage) -> Self {
+        Import {
+            storage: Some(storage),
+            ..self
+        }
+    }
+
+    /// Builds a snapshot from a tar into the snapshot file of the working directory.
+    pub fn run(self) -> Result<usize, Error> {
+        let Self {
+            cfg,
+            input,
+            format,
+            prefix,
+            storage,
+        } = self;
+
+        let file = File::open(&input).io_err(&input)?;
+        let src: Box<dyn Read> = match format {
+            Format::Tar => Box::new(file),
+            Format::TarZst => Box::new(zstd::Decoder::new(file).io_err(&input)?),
+        };
+
+        let written = {
+            let snapshot = Writing::open(&cfg.snapshot_file, cfg.encryption_key.as_ref())?;
+            let tmp_file = cfg.working_dir.join("import.tmp");
+            let res = read_tar(src, &input, prefix, snapshot, &tmp_file);
+
+            if tmp_file.exists() {
+                fs::remove_file(&tmp_file).io_err(&tmp_file)?;
+            }
+            res?
+        };
+
+        info!("Imported {} entries from {:?}", written, input);
+
+        if let Some(storage) = storage {
+            let file = &cfg.snapshot_file;
+            let len = file.metadata().io_err(file)?.len() as usize;
+
+            if let Some(key) = &cfg.signing_key {
+                info!("Signing snapshot ...");
+                Signature::sign(file, key)?.write(&cfg.signature_file)?;
+            }
+
+            info!("Uploading snapshot ...");
+            push::upload(cfg, storage, file, &cfg.signature_file, len)?;
+        }
+
+        Ok(written)
+    }
+}
+
+fn read_tar<R: Read>(
+    src: R,
+    input: &Path,
+    prefix: Option<PathBuf>,
+    mut snapshot: Writing<snapshot::Compressed<File>>,
+    tmp_file: &Path,
+) -> Result<usize, Error> {
+    let has_prefix = prefix.is_some();
+    let prefixed = snapshot::prefixed(prefix);
+    let mut archive = Archive::new(src);
+    let mut written = 0;
+
+    for item in archive.entries().io_err(input)? {
+        let mut item = item.io_err(input)?;
+        let path = item.path().io_err(input)?;
+        check_path(&path, has_prefix)?;
+        let path = prefixed(&path);
+
+        let header = item.header();
+        let mode = header.mode().io_err(input)? & 0o7777;
+        let mtime = header.mtime().io_err(input)? as i64;
+
+        let entry = match header.entry_type() {
+            EntryType::Directory => {
+                Entry::dir(&path, Attributes::new(S_IFDIR | mode, mtime, mtime))
+            }
+            EntryType::Symlink => {
+                let target = match item.link_name().io_err(input)? {
+                    Some(val) => val.into_owned(),
+                    None => return Error::io_err(&path, "Symlink without a target"),
+                };
+                Entry::symlink(&path, target, Attributes::new(S_IFLNK | mode, mtime, mtime))
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                let mut file = File::create(tmp_file).io_err(tmp_file)?;
+                let len = io::copy(&mut item, &mut file).io_err(&path)? as usize;
+                let md5 = md5::path(tmp_file).io_err(tmp_file)?;
+
+                let attr = Attributes::new(S_IFREG | mode, mtime, mtime);
+                let entry = Entry::file(&path, attr, md5, len)?;
+
+                snapshot.write_entry(&entry)?;
+                if len > 0 {
+                    snapshot.write_file(tmp_file, Some(len))?;
+                }
+
+                written += 1;
+                continue;
+            }
+            other => {
+                warn!("Skip {:?}, unsupported entry type {:?}", path, other);
+                continue;
+            }
+        };
+
+        snapshot.write_entry(&entry)?;
+        written += 1;
+    }
+
+    snapshot.finish()?;
+    Ok(written)
+}
+
+/// Unpacking must not write outside of the cached directories, so a path can't go up
+/// and it can be absolute only under a prefix.
+fn check_path(path: &Path, has_prefix: bool) -> Result<(), Error> {
+    let goes_up = path.components().any(|it| it == Component::ParentDir);
+
+    if goes_up || (path.is_absolute() && !has_prefix) {
+        let err = format!("Unsafe path {:?}", path.as_os_str());
+        return Error::snapshot_err("Import failed", err);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    use tar::{Builder, Header};
+    use url::Url;
+
+    use crate::commands::export::Export;
+    use crate::commands::inspect::Source;
+    use crate::snapshot::Reading;
+    use crate::testing::{self, FIXTURES_PATH};
+    use crate::ErrorKind;
+
+    fn entries<P: AsRef<Path>>(path: P) -> HashSet<Entry> {
+        let mut snapshot = Reading::open(path, None).unwrap();
+        let mut entries = HashSet::new();
+
+        while let Some((entry, _)) = snapshot.read_entry().unwrap() {
+            if let Some((_, _, _, len)) = entry.as_file() {
+                snapshot.skip(len).unwrap();
+            }
+            entries.insert(entry);
+        }
+        entries
+    }
+
+    #[test]
+    fn export_and_import() {
+        let work = testing::temp_dir();
+        let other = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+
+        testing::pack(&cfg.snapshot_file, &[FIXTURES_PATH]);
+        let expected = entries(&cfg.snapshot_file);
+
+        for format in &[Format::Tar, Format::TarZst] {
+            let tar = work.as_ref().join(format!("snapshot.{}", format));
+            let source = Source::File(cfg.snapshot_file.clone());
+            let exported = Export::new(&cfg, source, &tar).run().unwrap();
+            assert_eq!(exported, expected.len());
+
+            let other_cfg = Config::from(other.as_ref()).unwrap();
+            let imported = Import::new(&other_cfg, &tar).run().unwrap();
+            assert_eq!(imported, expected.len());
+
+            assert_eq!(entries(&other_cfg.snapshot_file), expected);
+        }
+    }
+
+    #[test]
+    fn import_with_prefix_and_upload() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg).uri(uri.as_str()).unwrap();
+
+        testing::pack(&cfg.snapshot_file, &[FIXTURES_PATH]);
+
+        let tar = work.as_ref().join("snapshot.tar");
+        let source = Source::File(cfg.snapshot_file.clone());
+        Export::new(&cfg, source, &tar).run().unwrap();
+
+        Import::new(&cfg, &tar)
+            .prefix(Some("/prefix"))
+            .upload(&storage)
+            .run()
+            .unwrap();
+
+        let uploaded = remote.as_ref().join(Config::snapshot_file_name());
+        let entries = entries(&uploaded);
+
+        assert!(entries
+            .iter()
+            .all(|it| it.as_path().starts_with("/prefix/tests/fixtures")));
+    }
+
+    #[test]
+    fn reject_unsafe_paths() {
+        let work = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let tar = work.as_ref().join("snapshot.tar");
+
+        // the builder refuses such paths, so they're written into the header directly
+        let write_tar = |path: &str| {
+            let mut header = Header::new_gnu();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_size(4);
+            header.set_cksum();
+
+            let mut builder = Builder::new(File::create(&tar).unwrap());
+            builder.append(&header, &b"evil"[..]).unwrap();
+            builder.finish().unwrap();
+        };
+
+        let params = vec![
+            ("../evil", None, false),
+            ("a/../../evil", Some("/prefix"), false),
+            ("/etc/evil", None, false),
+            ("/etc/evil", Some("/prefix"), true),
+            ("a/evil", None, true),
+        ];
+
+        for (path, prefix, expected) in params {
+            write_tar(path);
+            let res = Import::new(&cfg, &tar).prefix(prefix).run();
+
+            match res {
+                Ok(_) => assert!(expected, "{}", path),
+                Err(err) => match err.kind() {
+                    ErrorKind::Snapshot(_) => assert!(!expected, "{}", path),
+                    other => panic!("{}: {:?}", path, other),
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,498 @@
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use serde_derive::Serialize;
+
+use crate::crypto::Key;
+use crate::errors::ResultExt;
+use crate::pretty;
+use crate::snapshot::{Entry, EntryKind, Reading, VERSION_LEN};
+use crate::{Config, Error, Storage};
+
+const LARGEST_FILES: usize = 10;
+
+/// Where a snapshot to inspect comes from.
+#[derive(Debug)]
+pub enum Source<'b> {
+    File(PathBuf),
+    /// An object under the key prefix of the remote location, e.g. `snapshot.snappy`.
+    Remote(&'b Storage, String),
+}
+
+impl<'b> Source<'b> {
+    pub(super) fn open(&self) -> Result<Box<dyn Read>, Error> {
+        match self {
+            Source::File(path) => Ok(Box::new(File::open(path).io_err(path)?)),
+            Source::Remote(storage, name) => Ok(Box::new(storage.download_stream(name)?)),
+        }
+    }
+
+    /// Reads all entries, skipping files content.
+    pub(super) fn read_entries(&self, key: Option<&Key>) -> Result<Vec<Entry>, Error> {
+        let mut snapshot = Reading::from(self.open()?, key)?;
+        let mut entries = Vec::new();
+
+        while let Some((entry, _)) = snapshot.read_entry()? {
+            if let Some((_, _, _, len)) = entry.as_file() {
+                snapshot.skip(len)?;
+            }
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+}
+
+#[derive(Debug)]
+pub struct Inspect<'a, 'b> {
+    cfg: &'a Config,
+    source: Source<'b>,
+    json: bool,
+    summary: bool,
+    largest: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Row {
+    kind: &'static str,
+    path: PathBuf,
+    mode: String,
+    size: usize,
+    mtime: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    md5: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+struct Total {
+    path: PathBuf,
+    files: usize,
+    bytes: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    files: usize,
+    dirs: usize,
+    symlinks: usize,
+    bytes: usize,
+    unpacked: usize,
+    packed: usize,
+    ratio: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation: Option<u64>,
+    top_level: Vec<Total>,
+    largest: Vec<Largest>,
+}
+
+#[derive(Debug, Serialize)]
+struct Largest {
+    path: PathBuf,
+    size: usize,
+}
+
+impl<'a, 'b> Inspect<'a, 'b> {
+    pub fn new(cfg: &'a Config, source: Source<'b>) -> Self {
+        Inspect {
+            cfg,
+            source,
+            json: false,
+            summary: false,
+            largest: LARGEST_FILES,
+        }
+    }
+
+    pub fn json(self, json: bool) -> Self {
+        Inspect { json, ..self }
+    }
+
+    /// Print totals instead of every entry.
+    pub fn summary(self, summary: bool) -> Self {
+        Inspect { summary, ..self }
+    }
+
+    /// How many of the largest files a summary shows.
+    pub fn largest(self, largest: usize) -> Self {
+        Inspect { largest, ..self }
+    }
+
+    pub fn run<W: Write>(self, out: W) -> Result<(), Error> {
+        let packed = Rc::new(Cell::new(0));
+        let src = Counting::new(self.source.open()?, packed.clone());
+
+        self.inspect(src, &packed, out)
+    }
+
+    fn inspect<R, W>(&self, src: R, packed: &Cell<usize>, mut out: W) -> Result<(), Error>
+    where
+        R: Read,
+        W: Write,
+    {
+        let mut snapshot = Reading::from(src, self.cfg.encryption_key.as_ref())?;
+
+        if self.summary {
+            let mut summary = summarize(&mut snapshot, self.largest)?;
+            summary.packed = packed.get();
+            summary.ratio = ratio(summary.unpacked, summary.packed);
+
+            return if self.json {
+                serde_json::to_writer_pretty(&mut out, &summary)
+                    .snapshot_err("Write summary failed")?;
+                writeln!(out).snapshot_err("Write summary failed")
+            } else {
+                write_summary(&mut out, &summary).snapshot_err("Write summary failed")
+            };
+        }
+
+        if self.json {
+            write!(out, "[").snapshot_err("Write entries failed")?;
+        } else {
+            write_header(&mut out).snapshot_err("Write entries failed")?;
+        }
+
+        let mut first = true;
+        while let Some((entry, _)) = snapshot.read_entry()? {
+            if let Some((_, _, _, len)) = entry.as_file() {
+                snapshot.skip(len)?;
+            }
+
+            let row = Row::from(&entry);
+
+            if self.json {
+                if !first {
+                    write!(out, ",").snapshot_err("Write entries failed")?;
+                }
+                writeln!(out).snapshot_err("Write entries failed")?;
+                serde_json::to_writer(&mut out, &row).snapshot_err("Write entries failed")?;
+            } else {
+                write_row(&mut out, &row).snapshot_err("Write entries failed")?;
+            }
+
+            first = false;
+        }
+
+        if self.json {
+            writeln!(out, "\n]").snapshot_err("Write entries failed")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'e> From<&'e Entry> for Row {
+    fn from(entry: &'e Entry) -> Self {
+        let attr = entry.as_attr();
+        let kind = match entry.kind() {
+            EntryKind::File => "file",
+            EntryKind::Symlink => "symlink",
+            EntryKind::Dir => "dir",
+        };
+
+        Row {
+            kind,
+            path: entry.as_path().to_path_buf(),
+            mode: format!("{:04o}", attr.mode & 0o7777),
+            size: entry.as_file().map_or(0, |(_, _, _, len)| len),
+            mtime: attr.mtime,
+            md5: entry.as_md5().map(String::from),
+            target: entry
+                .as_symlink()
+                .map(|(_, target, _)| target.to_path_buf()),
+        }
+    }
+}
+
+fn summarize<R: Read>(snapshot: &mut Reading<R>, largest: usize) -> Result<Summary, Error> {
+    let mut summary = Summary {
+        unpacked: VERSION_LEN,
+        ..Default::default()
+    };
+    let mut heap = BinaryHeap::new();
+
+    while let Some((entry, len)) = snapshot.read_entry()? {
+        summary.unpacked += len;
+
+        let size = match entry.as_file() {
+            Some((_, _, _, len)) => snapshot.skip(len)?,
+            None => 0,
+        };
+        summary.unpacked += size;
+
+        match entry.kind() {
+            EntryKind::File => summary.files += 1,
+            EntryKind::Symlink => summary.symlinks += 1,
+            EntryKind::Dir => summary.dirs += 1,
+        }
+        summary.bytes += size;
+
+        let path = entry.as_path();
+        let idx = match summary
+            .top_level
+            .iter()
+            .position(|it| path.starts_with(&it.path))
+        {
+            Some(idx) => idx,
+            None => {
+                summary.top_level.push(Total::new(top_level(&entry)));
+                summary.top_level.len() - 1
+            }
+        };
+
+        let total = &mut summary.top_level[idx];
+        if entry.kind() == EntryKind::File {
+            total.files += 1;
+            total.bytes += size;
+
+            heap.push(Reverse((size, path.to_path_buf())));
+            if heap.len() > largest {
+                heap.pop();
+            }
+        }
+    }
+
+    summary.generation = snapshot.footer().map(|it| it.generation);
+    summary.largest = heap
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((size, path))| Largest { path, size })
+        .collect();
+
+    Ok(summary)
+}
+
+/// A snapshot starts with a cached directory itself, entries under it follow.
+fn top_level(entry: &Entry) -> PathBuf {
+    match entry.kind() {
+        EntryKind::Dir => entry.as_path().to_path_buf(),
+        _ => entry
+            .as_path()
+            .parent()
+            .unwrap_or_else(|| entry.as_path())
+            .to_path_buf(),
+    }
+}
+
+fn ratio(unpacked: usize, packed: usize) -> f64 {
+    if packed == 0 {
+        0.0
+    } else {
+        unpacked as f64 / packed as f64
+    }
+}
+
+impl Total {
+    fn new(path: PathBuf) -> Self {
+        Total {
+            path,
+            files: 0,
+            bytes: 0,
+        }
+    }
+}
+
+fn write_header<W: Write>(out: &mut W) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:<7} {:>4} {:>10} {:>10} {:<32} path",
+        "kind", "mode", "size", "mtime", "md5"
+    )
+}
+
+fn write_row<W: Write>(out: &mut W, row: &Row) -> io::Result<()> {
+    write!(
+        out,
+        "{:<7} {:>4} {:>10} {:>10} {:<32} {}",
+        row.kind,
+        row.mode,
+        row.size,
+        row.mtime,
+        row.md5.as_ref().map_or("-", String::as_str),
+        row.path.display()
+    )?;
+
+    match &row.target {
+        Some(target) => writeln!(out, " -> {}", target.display()),
+        None => writeln!(out),
+    }
+}
+
+fn write_summary<W: Write>(out: &mut W, summary: &Summary) -> io::Result<()> {
+    writeln!(out, "files     {}", summary.files)?;
+    writeln!(out, "dirs      {}", summary.dirs)?;
+    writeln!(out, "symlinks  {}", summary.symlinks)?;
+    writeln!(out, "size      {}", pretty::bytes(summary.bytes))?;
+    writeln!(out, "unpacked  {}", pretty::bytes(summary.unpacked))?;
+    writeln!(out, "packed    {}", pretty::bytes(summary.packed))?;
+    writeln!(out, "ratio     {:.2}", summary.ratio)?;
+    if let Some(generation) = summary.generation {
+        writeln!(out, "generation {}", generation)?;
+    }
+
+    writeln!(out, "\ntop-level directories:")?;
+    for total in &summary.top_level {
+        writeln!(
+            out,
+            "{:>10} {:>8} files  {}",
+            pretty::bytes(total.bytes),
+            total.files,
+            total.path.display()
+        )?;
+    }
+
+    writeln!(out, "\nlargest files:")?;
+    for file in &summary.largest {
+        writeln!(
+            out,
+            "{:>10}  {}",
+            pretty::bytes(file.size),
+            file.path.display()
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Counts bytes read from a snapshot before decompression.
+struct Counting<R> {
+    inner: R,
+    count: Rc<Cell<usize>>,
+}
+
+impl<R> Counting<R> {
+    fn new(inner: R, count: Rc<Cell<usize>>) -> Self {
+        Counting { inner, count }
+    }
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use serde_json::Value;
+    use url::Url;
+
+    use crate::testing::{self, A_FILE_PATH, FIXTURES_PATH, IS_SYMLINK_PATH};
+
+    fn packed(cfg: &Config) -> Vec<PathBuf> {
+        let dirs = vec![PathBuf::from(FIXTURES_PATH)];
+        testing::pack(&cfg.snapshot_file, &dirs);
+        dirs
+    }
+
+    fn mode(path: &str) -> u32 {
+        fs::symlink_metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    fn inspect(command: Inspect<'_, '_>) -> String {
+        let mut out = Vec::new();
+        command.run(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn inspect_table() {
+        let work = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+        packed(&cfg);
+
+        let source = Source::File(cfg.snapshot_file.clone());
+        let actual = inspect(Inspect::new(&cfg, source));
+        let lines = actual.lines().collect::<Vec<_>>();
+
+        assert!(lines[0].starts_with("kind"));
+        assert!(lines
+            .iter()
+            .any(|it| it.starts_with("file") && it.ends_with(A_FILE_PATH)));
+        assert!(lines
+            .iter()
+            .any(|it| it.starts_with("symlink") && it.contains(IS_SYMLINK_PATH)));
+    }
+
+    #[test]
+    fn inspect_json() {
+        let work = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+        packed(&cfg);
+
+        let source = Source::File(cfg.snapshot_file.clone());
+        let actual = inspect(Inspect::new(&cfg, source).json(true));
+        let rows: Vec<Value> = serde_json::from_str(&actual).unwrap();
+
+        let a_file = rows.iter().find(|it| it["path"] == A_FILE_PATH).unwrap();
+        assert_eq!(a_file["kind"], "file");
+        assert_eq!(a_file["mode"], format!("{:04o}", mode(A_FILE_PATH)));
+        assert!(a_file["md5"].is_string());
+
+        let symlink = rows
+            .iter()
+            .find(|it| it["path"] == IS_SYMLINK_PATH)
+            .unwrap();
+        assert!(symlink["target"].is_string());
+    }
+
+    #[test]
+    fn inspect_summary() {
+        let work = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+        packed(&cfg);
+
+        let source = Source::File(cfg.snapshot_file.clone());
+        let actual = inspect(
+            Inspect::new(&cfg, source)
+                .summary(true)
+                .json(true)
+                .largest(2),
+        );
+        let summary: Value = serde_json::from_str(&actual).unwrap();
+
+        let files = summary["files"].as_u64().unwrap();
+        let len = cfg.snapshot_file.metadata().unwrap().len();
+
+        assert!(files > 0);
+        assert_eq!(summary["packed"].as_u64().unwrap(), len);
+        assert_eq!(summary["top_level"][0]["path"], FIXTURES_PATH);
+        assert_eq!(summary["top_level"][0]["files"].as_u64().unwrap(), files);
+        assert_eq!(summary["largest"].as_array().unwrap().len(), 2);
+        assert!(summary["ratio"].as_f64().unwrap() > 0.0);
+    }
+
+    #[test]
+    fn inspect_remote() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .uploadable(true);
+        packed(&cfg);
+
+        let len = cfg.snapshot_file.metadata().unwrap().len();
+        storage.upload(&cfg.snapshot_file, len as usize).unwrap();
+
+        let source = Source::Remote(&storage, Config::snapshot_file_name().into());
+        let actual = inspect(Inspect::new(&cfg, source).summary(true));
+
+        assert!(actual.contains(FIXTURES_PATH));
+    }
+}
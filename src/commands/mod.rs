@@ -1,5 +1,25 @@
+mod chain;
+mod conflict;
+mod diff;
+pub mod exec;
+mod export;
+mod extract;
+mod gc;
+mod group;
+mod import;
+mod inspect;
 mod pull;
 mod push;
+pub mod verify;
 
+pub use self::conflict::OnConflict;
+pub use self::diff::{Diff, Target};
+pub use self::exec::Exec;
+pub use self::export::{Export, Format};
+pub use self::extract::Extract;
+pub use self::gc::{Gc, Reclaimed};
+pub use self::import::Import;
+pub use self::inspect::{Inspect, Source};
 pub use self::pull::Pull;
 pub use self::push::Push;
+pub use self::verify::Verify;
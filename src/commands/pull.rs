@@ -1,20 +1,31 @@
+use std::collections::HashSet;
 use std::fs::{self, OpenOptions};
 use std::path::{Path, PathBuf};
 
 use log::{error, info, warn};
+use rayon::prelude::*;
 use serde::Serialize;
 use serde_json;
 
+use crate::chunks::{ChunkStore, Manifest};
+use crate::commands::chain::{self, Chain, Link};
+use crate::commands::conflict;
+use crate::commands::group::Group;
+use crate::crypto::{Signature, TrustedKeys};
 use crate::errors::ResultExt;
 use crate::snapshot::{Reading, Unpack};
-use crate::{Config, Error, Stats, Storage};
+use crate::storage::Layout;
+use crate::{Config, Error, ServiceMessages, Stats, Storage};
 
 #[derive(Debug)]
 pub struct Pull<'a, 'b> {
     cfg: &'a Config,
     storage: &'b Storage,
+    fallback: Option<&'b Storage>,
     cached_dirs: Vec<PathBuf>,
     unpack_prefix: Option<PathBuf>,
+    allow_unsigned: bool,
+    stream: bool,
 }
 
 impl<'a, 'b> Pull<'a, 'b> {
@@ -31,11 +42,35 @@ impl<'a, 'b> Pull<'a, 'b> {
         Pull {
             cfg,
             storage,
+            fallback: None,
             cached_dirs: cached_dirs
                 .iter()
                 .map(|it| it.as_ref().to_path_buf())
                 .collect(),
             unpack_prefix: unpack_prefix.map(|it| it.as_ref().to_path_buf()),
+            allow_unsigned: false,
+            stream: false,
+        }
+    }
+
+    pub fn allow_unsigned(self, allow_unsigned: bool) -> Self {
+        Pull {
+            allow_unsigned,
+            ..self
+        }
+    }
+
+    /// Unpack an archive while it's downloading, without a local copy.
+    pub fn stream(self, stream: bool) -> Self {
+        Pull { stream, ..self }
+    }
+
+    /// Where to pull from while there is no snapshot in the storage yet,
+    /// e.g. the default branch's cache for a new branch.
+    pub fn fallback(self, fallback: &'b Storage) -> Self {
+        Pull {
+            fallback: Some(fallback),
+            ..self
         }
     }
 
@@ -43,19 +78,16 @@ impl<'a, 'b> Pull<'a, 'b> {
         let Self {
             cfg,
             storage,
+            fallback,
             cached_dirs,
             unpack_prefix,
+            allow_unsigned,
+            stream,
         } = self;
 
-        if storage.is_downloable() {
-            if let Err(err) = storage.download(&cfg.snapshot_file) {
-                if cfg.verbose {
-                    error!("{:?}", err);
-                } else {
-                    error!("{}", err);
-                }
-            }
-        }
+        let messages = ServiceMessages::current();
+        let _block = messages.block("Pull cache");
+        messages.progress("Pulling cache ...");
 
         let cached_dirs = cached_dirs
             .into_iter()
@@ -63,23 +95,396 @@ impl<'a, 'b> Pull<'a, 'b> {
             .collect::<Vec<_>>();
 
         write_json(&cfg.cached_dirs_file, &cached_dirs)?;
+        fs::write(&cfg.pulled_file, "").io_err(&cfg.pulled_file)?;
+
+        let fallback = match fallback {
+            Some(val) if storage.is_downloable() && !has_snapshot(cfg, storage) => {
+                info!("No snapshot for the branch yet, pulling the default branch's one");
+                Some(val)
+            }
+            _ => None,
+        };
+
+        let res = pull(
+            cfg,
+            fallback.unwrap_or(storage),
+            &cached_dirs,
+            unpack_prefix,
+            allow_unsigned,
+            stream,
+        );
+
+        match res {
+            Ok(true) => Stats::current().hits().inc(1),
+            Ok(false) => Stats::current().misses().inc(1),
+            Err(_) => {}
+        }
+
+        // the state refers to another key, so the first push uploads everything to its own one
+        if fallback.is_some() {
+            for path in &[&cfg.cached_entries_file, &cfg.chain_file] {
+                if path.exists() {
+                    fs::remove_file(path).io_err(path)?;
+                }
+            }
+        }
+
+        res.map(|_| ())
+    }
+}
+
+fn pull(
+    cfg: &Config,
+    storage: &Storage,
+    cached_dirs: &[PathBuf],
+    unpack_prefix: Option<PathBuf>,
+    allow_unsigned: bool,
+    stream: bool,
+) -> Result<bool, Error> {
+    if let Err(err) = conflict::record(cfg, storage) {
+        warn!("Cannot record the remote snapshot's tag: {}", err);
+    }
+
+    match storage.as_layout() {
+        Layout::Split => {
+            return pull_groups(cfg, storage, cached_dirs, unpack_prefix, allow_unsigned);
+        }
+        Layout::Delta => {
+            return pull_chain(cfg, storage, cached_dirs, unpack_prefix, allow_unsigned);
+        }
+        Layout::Archive if stream && storage.is_downloable() => {
+            if cfg.trusted_keys.is_none() {
+                return pull_stream(cfg, storage, cached_dirs, unpack_prefix);
+            }
+            warn!("Signatures are verified before unpacking, streaming is disabled");
+        }
+        Layout::Archive | Layout::Chunks => {}
+    }
+
+    let (file, signature_file) = match storage.as_layout() {
+        Layout::Archive => (&cfg.snapshot_file, &cfg.signature_file),
+        Layout::Chunks => (&cfg.manifest_file, &cfg.manifest_signature_file),
+        Layout::Split | Layout::Delta => unreachable!("pulled separately"),
+    };
+
+    if storage.is_downloable() {
+        if let Err(err) = download(cfg, storage, file, signature_file) {
+            log_error(cfg, &err);
+        }
+    }
+
+    if !file.exists() {
+        warn!("The previous snapshot wasn't found");
+        return Ok(false);
+    }
+
+    if !is_trusted(cfg, file, signature_file, allow_unsigned)? {
+        return Ok(false);
+    }
+
+    let (entries, _) = match storage.as_layout() {
+        Layout::Archive => {
+            info!("Unpacking snapshot ...");
+
+            let _timer = Stats::current().unpacking().timer();
+            let snapshot = Reading::open(&cfg.snapshot_file, cfg.encryption_key.as_ref())?;
+            snapshot.unpack(unpack_prefix, cached_dirs)?
+        }
+        Layout::Chunks => {
+            let manifest = Manifest::read(&cfg.manifest_file, cfg.encryption_key.as_ref())?;
+            let store = ChunkStore::new(cfg)?;
+
+            if storage.is_downloable() {
+                let ids = manifest.included_chunk_ids(cached_dirs);
+                if let Err(err) = store.download_missing(&ids, storage) {
+                    log_error(cfg, &err);
+                    warn!("Some chunks weren't downloaded, ignoring the snapshot");
+                    fs::remove_file(file).io_err(file)?;
+                    return Ok(false);
+                }
+            }
+
+            info!("Unpacking snapshot ...");
+
+            let unpacked = {
+                let _timer = Stats::current().unpacking().timer();
+                store.unpack(&manifest, unpack_prefix, cached_dirs)?
+            };
+            store.retain(&manifest.chunk_ids())?;
+            unpacked
+        }
+        Layout::Split | Layout::Delta => unreachable!("pulled separately"),
+    };
+
+    write_json(&cfg.cached_entries_file, &entries)?;
+
+    Ok(true)
+}
+
+/// Whether anything was pushed under the storage's key, in any layout.
+/// A failed check is logged and counts as no snapshot.
+fn has_snapshot(cfg: &Config, storage: &Storage) -> bool {
+    let res = match storage.head_file_name() {
+        Some(file_name) => storage.exists(storage.key_prefixed(file_name)),
+        None => storage
+            .list(storage.key_prefixed(""))
+            .map(|it| !it.is_empty()),
+    };
+
+    res.unwrap_or_else(|err| {
+        log_error(cfg, &err);
+        warn!("Cannot check the branch's snapshot");
+        false
+    })
+}
 
-        if !cfg.snapshot_file.exists() {
+fn pull_stream(
+    cfg: &Config,
+    storage: &Storage,
+    cached_dirs: &[PathBuf],
+    unpack_prefix: Option<PathBuf>,
+) -> Result<bool, Error> {
+    let download = match storage.download_stream(Config::snapshot_file_name()) {
+        Ok(val) => val,
+        Err(err) => {
+            log_error(cfg, &err);
             warn!("The previous snapshot wasn't found");
-            return Ok(());
+            return Ok(false);
+        }
+    };
+
+    info!("Unpacking snapshot ...");
+
+    // a failed download fails unpacking, the unpacked entries are removed and not recorded as cached
+    let (entries, _) = {
+        let _timer = Stats::current().unpacking().timer();
+        let snapshot = Reading::from(download, cfg.encryption_key.as_ref())?;
+        snapshot.unpack(unpack_prefix, cached_dirs)?
+    };
+
+    write_json(&cfg.cached_entries_file, &entries).map(|_| true)
+}
+
+fn pull_groups(
+    cfg: &Config,
+    storage: &Storage,
+    cached_dirs: &[PathBuf],
+    unpack_prefix: Option<PathBuf>,
+    allow_unsigned: bool,
+) -> Result<bool, Error> {
+    let groups = Group::all(cfg, cached_dirs)?;
+
+    if storage.is_downloable() {
+        groups.par_iter().for_each(|group| {
+            if let Err(err) = download(cfg, storage, &group.snapshot_file, &group.signature_file) {
+                log_error(cfg, &err);
+            }
+        });
+    }
+
+    let mut entries = Vec::new();
+    let mut unpacked = false;
+
+    for group in &groups {
+        if !group.snapshot_file.exists() {
+            let dir = group.dir.as_os_str();
+            warn!("The previous snapshot of {:?} wasn't found", dir);
+            continue;
+        }
+
+        if !is_trusted(
+            cfg,
+            &group.snapshot_file,
+            &group.signature_file,
+            allow_unsigned,
+        )? {
+            continue;
         }
 
-        info!("Unpacking snapshot ...");
+        info!("Unpacking snapshot of {:?} ...", group.dir.as_os_str());
 
-        let (entries, _) = {
+        let (mut group_entries, _) = {
             let _timer = Stats::current().unpacking().timer();
-            let snapshot = Reading::open(&cfg.snapshot_file)?;
-            snapshot.unpack(unpack_prefix, &cached_dirs)?
+            let snapshot = Reading::open(&group.snapshot_file, cfg.encryption_key.as_ref())?;
+            snapshot.unpack(unpack_prefix.clone(), &[&group.dir])?
         };
 
+        entries.append(&mut group_entries);
+        unpacked = true;
+    }
+
+    if unpacked {
+        // nested cached directories are unpacked more than once
+        let mut seen = HashSet::new();
+        entries.retain(|it| seen.insert(it.as_ref().to_path_buf()));
+
         write_json(&cfg.cached_entries_file, &entries)?;
+    }
+
+    Ok(unpacked)
+}
+
+fn pull_chain(
+    cfg: &Config,
+    storage: &Storage,
+    cached_dirs: &[PathBuf],
+    unpack_prefix: Option<PathBuf>,
+    allow_unsigned: bool,
+) -> Result<bool, Error> {
+    if !cfg.deltas_dir.exists() {
+        fs::create_dir_all(&cfg.deltas_dir).io_err(&cfg.deltas_dir)?;
+    }
+
+    let (file, signature_file) = (&cfg.chain_file, &cfg.chain_signature_file);
+
+    if storage.is_downloable() {
+        if file.exists() {
+            fs::remove_file(file).io_err(file)?;
+        }
+
+        if let Err(err) = download(cfg, storage, file, signature_file) {
+            log_error(cfg, &err);
+        }
+    }
+
+    if !file.exists() {
+        warn!("The previous snapshot wasn't found");
+        return Ok(false);
+    }
+
+    if !is_trusted(cfg, file, signature_file, allow_unsigned)? {
+        return Ok(false);
+    }
+
+    let chain = Chain::read(file)?;
+    let links = chain.links().collect::<Vec<_>>();
+
+    if storage.is_downloable() {
+        // snapshots are immutable, the ones left from previous builds are reused
+        let res = links
+            .par_iter()
+            .filter(|it| !it.snapshot_file(cfg).exists())
+            .map(|it| download_link(cfg, storage, it))
+            .collect::<Result<Vec<_>, Error>>();
+
+        if let Err(err) = res {
+            log_error(cfg, &err);
+            warn!("Some deltas weren't downloaded, ignoring the snapshot");
+            return Ok(false);
+        }
+    }
+
+    for link in &links {
+        let file = link.snapshot_file(cfg);
+        if !file.exists() {
+            warn!(
+                "The snapshot {} wasn't found, ignoring the snapshot",
+                link.name
+            );
+            return Ok(false);
+        }
+
+        if !is_trusted(cfg, &file, &link.signature_file(cfg), allow_unsigned)? {
+            return Ok(false);
+        }
+    }
+
+    chain.retain(cfg)?;
 
-        Ok(())
+    let mut entries = Vec::new();
+
+    for link in &links {
+        info!("Unpacking snapshot {} ...", link.name);
+
+        chain::apply_tombstones(
+            &link.removed,
+            unpack_prefix.clone(),
+            cached_dirs,
+            &mut entries,
+        )?;
+
+        let (unpacked, _) = {
+            let _timer = Stats::current().unpacking().timer();
+            let snapshot = Reading::open(link.snapshot_file(cfg), cfg.encryption_key.as_ref())?;
+            snapshot.unpack(unpack_prefix.clone(), cached_dirs)?
+        };
+
+        chain::merge(&mut entries, unpacked);
+    }
+
+    write_json(&cfg.cached_entries_file, &entries).map(|_| true)
+}
+
+fn download_link(cfg: &Config, storage: &Storage, link: &Link) -> Result<(), Error> {
+    let file = link.snapshot_file(cfg);
+    let tmp = file.with_extension("tmp");
+
+    if cfg.trusted_keys.is_some() {
+        storage.download(link.signature_file(cfg))?;
+    }
+
+    storage.download_object(storage.key_prefixed(&link.name), &tmp)?;
+    fs::rename(&tmp, &file).io_err(&file)
+}
+
+fn download(
+    cfg: &Config,
+    storage: &Storage,
+    file: &Path,
+    signature_file: &Path,
+) -> Result<(), Error> {
+    if cfg.trusted_keys.is_some() {
+        if signature_file.exists() {
+            fs::remove_file(signature_file).io_err(signature_file)?;
+        }
+
+        if let Err(err) = storage.download(signature_file) {
+            warn!("The snapshot's signature wasn't downloaded; {}", err);
+        }
+    }
+
+    storage.download(file)
+}
+
+/// Checks the snapshot's signature when trusted keys are given, an untrusted
+/// snapshot is removed unless unsigned ones are allowed.
+fn is_trusted(
+    cfg: &Config,
+    file: &Path,
+    signature_file: &Path,
+    allow_unsigned: bool,
+) -> Result<bool, Error> {
+    let trusted_keys = match &cfg.trusted_keys {
+        Some(val) => val,
+        None => return Ok(true),
+    };
+
+    if let Err(err) = verify(file, signature_file, trusted_keys) {
+        if !allow_unsigned {
+            warn!("{}, ignoring the snapshot", err);
+            fs::remove_file(file).io_err(file)?;
+            return Ok(false);
+        }
+        warn!("{}, unpacking anyway", err);
+    }
+
+    Ok(true)
+}
+
+fn verify(file: &Path, signature_file: &Path, trusted_keys: &TrustedKeys) -> Result<(), Error> {
+    if !signature_file.exists() {
+        return Err(Error::signature("The snapshot isn't signed"));
+    }
+
+    info!("Verifying snapshot signature ...");
+    Signature::read(signature_file)?.verify(file, trusted_keys)
+}
+
+fn log_error(cfg: &Config, err: &Error) {
+    if cfg.verbose {
+        error!("{:?}", err);
+    } else {
+        error!("{}", err);
     }
 }
 
@@ -131,7 +536,14 @@ fn try_is_cacheable(path: &Path) -> Result<Option<PathBuf>, Error> {
 mod tests {
     use super::*;
 
-    use crate::testing::{self, FIXTURES_PATH};
+    use url::Url;
+
+    use crate::commands::Push;
+    use crate::crypto::SigningKey;
+    use crate::hashing::md5;
+    use crate::testing::{self, A_FILE_PATH, FIXTURES_PATH};
+
+    const TEAMCITY_PATH: &str = "tests/fixtures/teamcity";
 
     #[test]
     fn pull() {
@@ -145,4 +557,329 @@ mod tests {
 
         command.run().unwrap();
     }
+
+    #[test]
+    fn pull_ignores_unsigned() {
+        let work = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let dirs = vec![PathBuf::from(FIXTURES_PATH)];
+
+        let mut cfg = Config::from(work.as_ref()).unwrap();
+        let key = SigningKey::from_hex(hex::encode([1; 32])).unwrap();
+        cfg.trusted_keys(Some(TrustedKeys::from_content(key.public_key()).unwrap()));
+
+        testing::pack(&cfg.snapshot_file, &dirs);
+
+        let storage = Storage::new(&cfg);
+        Pull::new(&cfg, &storage, &dirs, Some(&dst)).run().unwrap();
+
+        assert_eq!(cfg.snapshot_file.exists(), false);
+        assert_eq!(cfg.cached_entries_file.exists(), false);
+    }
+
+    #[test]
+    fn pull_verifies_signature() {
+        let work = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let dirs = vec![PathBuf::from(FIXTURES_PATH)];
+
+        let mut cfg = Config::from(work.as_ref()).unwrap();
+        let key = SigningKey::from_hex(hex::encode([1; 32])).unwrap();
+        cfg.trusted_keys(Some(TrustedKeys::from_content(key.public_key()).unwrap()));
+
+        testing::pack(&cfg.snapshot_file, &dirs);
+        Signature::sign(&cfg.snapshot_file, &key)
+            .unwrap()
+            .write(&cfg.signature_file)
+            .unwrap();
+
+        let storage = Storage::new(&cfg);
+        Pull::new(&cfg, &storage, &dirs, Some(&dst)).run().unwrap();
+
+        assert_eq!(cfg.cached_entries_file.exists(), true);
+    }
+
+    #[test]
+    fn pull_chunks() {
+        let work = testing::temp_dir();
+        let other = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let dirs = vec![PathBuf::from(FIXTURES_PATH)];
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .uploadable(true)
+            .layout(Layout::Chunks);
+
+        Pull::new(&cfg, &storage, &dirs, None::<PathBuf>)
+            .run()
+            .unwrap();
+        let (_, len) = Push::new(&cfg, &storage).run().unwrap();
+        assert!(len.is_some());
+
+        let cfg = Config::from(other.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .layout(Layout::Chunks);
+
+        Pull::new(&cfg, &storage, &dirs, Some(&dst)).run().unwrap();
+
+        let src = Path::new(A_FILE_PATH).canonicalize().unwrap();
+        let unpacked = dst.as_ref().join(src.strip_prefix("/").unwrap());
+
+        assert_eq!(md5::path(&unpacked).unwrap(), md5::path(&src).unwrap());
+        assert_eq!(cfg.cached_entries_file.exists(), true);
+    }
+
+    #[test]
+    fn pull_groups() {
+        let work = testing::temp_dir();
+        let other = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let dirs = vec![PathBuf::from(FIXTURES_PATH), PathBuf::from(TEAMCITY_PATH)];
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .uploadable(true)
+            .layout(Layout::Split);
+
+        Pull::new(&cfg, &storage, &dirs, None::<PathBuf>)
+            .run()
+            .unwrap();
+        let (_, len) = Push::new(&cfg, &storage).run().unwrap();
+        assert!(len.is_some());
+
+        let cfg = Config::from(other.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .uploadable(true)
+            .layout(Layout::Split);
+
+        Pull::new(&cfg, &storage, &dirs, Some(&dst)).run().unwrap();
+
+        let src = Path::new(A_FILE_PATH).canonicalize().unwrap();
+        let unpacked = dst.as_ref().join(src.strip_prefix("/").unwrap());
+        assert_eq!(md5::path(&unpacked).unwrap(), md5::path(&src).unwrap());
+
+        // nothing is changed since the pull, so no group is pushed
+        let (_, len) = Push::new(&cfg, &storage).run().unwrap();
+        assert_eq!(len, None);
+    }
+
+    #[test]
+    fn pull_chain() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let src = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let dirs = vec![src.as_ref().to_path_buf()];
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let write = |name: &str, content: &str| {
+            fs::write(src.as_ref().join(name), content).unwrap();
+        };
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .uploadable(true)
+            .layout(Layout::Delta);
+        let pull = || Pull::new(&cfg, &storage, &dirs, None::<PathBuf>).run();
+        let push = || {
+            Push::new(&cfg, &storage)
+                .max_deltas(2)
+                .max_delta_ratio(10.0)
+                .run()
+        };
+
+        pull().unwrap();
+        write("a", "first");
+        write("b", "second");
+        push().unwrap();
+
+        pull().unwrap();
+        fs::remove_file(src.as_ref().join("b")).unwrap();
+        write("c", "third");
+        push().unwrap();
+
+        let chain = Chain::read(&cfg.chain_file).unwrap();
+        assert!(chain.base.is_some());
+        assert_eq!(chain.deltas.len(), 1);
+        assert_eq!(chain.deltas[0].removed, vec![src.as_ref().join("b")]);
+
+        let other = testing::temp_dir();
+        let other_cfg = Config::from(other.as_ref()).unwrap();
+        let other_storage = Storage::new(&other_cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .layout(Layout::Delta);
+
+        Pull::new(&other_cfg, &other_storage, &dirs, Some(&dst))
+            .run()
+            .unwrap();
+
+        let unpacked = dst.as_ref().join(src.as_ref().strip_prefix("/").unwrap());
+        assert_eq!(fs::read_to_string(unpacked.join("a")).unwrap(), "first");
+        assert_eq!(fs::read_to_string(unpacked.join("c")).unwrap(), "third");
+        assert_eq!(unpacked.join("b").exists(), false);
+
+        // the third delta exceeds the limit, so the chain is compacted
+        pull().unwrap();
+        write("d", "fourth");
+        push().unwrap();
+        pull().unwrap();
+        write("e", "fifth");
+        push().unwrap();
+
+        let chain = Chain::read(&cfg.chain_file).unwrap();
+        assert_eq!(chain.deltas.len(), 0);
+
+        // the replaced base and deltas are deleted remotely as well
+        let mut remote_files = fs::read_dir(remote.as_ref())
+            .unwrap()
+            .map(|it| it.unwrap().file_name().into_string().unwrap())
+            .filter(|it| !it.ends_with(".meta"))
+            .collect::<Vec<_>>();
+        remote_files.sort();
+
+        let base = chain.base.unwrap().name;
+        assert_eq!(remote_files, vec![base.as_str(), Config::chain_file_name()]);
+    }
+
+    #[test]
+    fn pull_pushed_archive() {
+        let work = testing::temp_dir();
+        let other = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let dirs = vec![PathBuf::from(FIXTURES_PATH)];
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+        let key = SigningKey::from_hex(hex::encode([1; 32])).unwrap();
+        let trusted = TrustedKeys::from_content(key.public_key()).unwrap();
+
+        let mut cfg = Config::from(work.as_ref()).unwrap();
+        cfg.signing_key(Some(key));
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .uploadable(true);
+
+        Pull::new(&cfg, &storage, &dirs, None::<PathBuf>)
+            .run()
+            .unwrap();
+        Push::new(&cfg, &storage).run().unwrap();
+
+        // streamed straight into the remote location
+        assert_eq!(cfg.snapshot_file.exists(), false);
+
+        let mut cfg = Config::from(other.as_ref()).unwrap();
+        cfg.trusted_keys(Some(trusted));
+        let storage = Storage::new(&cfg).uri(uri.as_str()).unwrap();
+
+        Pull::new(&cfg, &storage, &dirs, Some(&dst)).run().unwrap();
+
+        let src = Path::new(A_FILE_PATH).canonicalize().unwrap();
+        let unpacked = dst.as_ref().join(src.strip_prefix("/").unwrap());
+        assert_eq!(md5::path(&unpacked).unwrap(), md5::path(&src).unwrap());
+    }
+
+    #[test]
+    fn pull_from_fallback() {
+        let work = testing::temp_dir();
+        let other = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let dirs = vec![PathBuf::from(FIXTURES_PATH)];
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .key_prefix("project")
+            .uploadable(true);
+
+        Pull::new(&cfg, &storage, &dirs, None::<PathBuf>)
+            .run()
+            .unwrap();
+        Push::new(&cfg, &storage).run().unwrap();
+
+        let cfg = Config::from(other.as_ref()).unwrap();
+        let branch = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .key_prefix("project/branches/feature")
+            .uploadable(true);
+
+        Pull::new(&cfg, &branch, &dirs, Some(&dst))
+            .fallback(&storage)
+            .run()
+            .unwrap();
+
+        let src = Path::new(A_FILE_PATH).canonicalize().unwrap();
+        let unpacked = dst.as_ref().join(src.strip_prefix("/").unwrap());
+        assert_eq!(unpacked.exists(), true);
+
+        // nothing has changed, but the branch has no snapshot of its own yet
+        assert_eq!(cfg.cached_entries_file.exists(), false);
+        let (_, pushed) = Push::new(&cfg, &branch).run().unwrap();
+        assert!(pushed.is_some());
+
+        let uploaded = remote
+            .as_ref()
+            .join("project/branches/feature/snapshot.snappy");
+        assert_eq!(uploaded.exists(), true);
+    }
+
+    #[test]
+    fn pull_stream() {
+        let work = testing::temp_dir();
+        let other = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let dst = testing::temp_dir();
+        let dirs = vec![PathBuf::from(FIXTURES_PATH)];
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(other.as_ref()).unwrap();
+        let storage = Storage::new(&cfg).uri(uri.as_str()).unwrap();
+
+        // nothing to stream yet
+        Pull::new(&cfg, &storage, &dirs, Some(&dst))
+            .stream(true)
+            .run()
+            .unwrap();
+        assert_eq!(cfg.cached_entries_file.exists(), false);
+
+        let push_cfg = Config::from(work.as_ref()).unwrap();
+        let push_storage = Storage::new(&push_cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .uploadable(true);
+        let cached_dirs = dirs.iter().filter_map(is_cacheable).collect::<Vec<_>>();
+        write_json(&push_cfg.cached_dirs_file, &cached_dirs).unwrap();
+        Push::new(&push_cfg, &push_storage).run().unwrap();
+
+        Pull::new(&cfg, &storage, &dirs, Some(&dst))
+            .stream(true)
+            .run()
+            .unwrap();
+
+        assert_eq!(cfg.snapshot_file.exists(), false);
+        assert_eq!(cfg.cached_entries_file.exists(), true);
+
+        let src = Path::new(A_FILE_PATH).canonicalize().unwrap();
+        let unpacked = dst.as_ref().join(src.strip_prefix("/").unwrap());
+        assert_eq!(md5::path(&unpacked).unwrap(), md5::path(&src).unwrap());
+    }
 }
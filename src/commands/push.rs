@@ -1,27 +1,93 @@
 use std::collections::HashSet;
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 
+use glob::Pattern;
 use log::{error, info, warn};
 
+use crate::chunks::ChunkStore;
+use crate::commands::chain::{self, Chain, Link};
+use crate::commands::conflict::{self, OnConflict};
+use crate::commands::group::Group;
+use crate::crypto::{Digesting, Signature};
 use crate::errors::ResultExt;
-use crate::snapshot::{self, Diff, Entry, Pack, Writing};
-use crate::{mmap, Config, Error, Stats, Storage};
+use crate::snapshot::{self, Diff, Entry, Footer, Pack, Writing};
+use crate::storage::Layout;
+use crate::{mmap, pretty, Config, Error, ServiceMessages, Stats, Storage};
+
+const DEFAULT_MAX_DELTAS: usize = 10;
+const DEFAULT_MAX_DELTA_RATIO: f64 = 0.5;
 
 pub struct Push<'a, 'b> {
     cfg: &'a Config,
     storage: &'b Storage,
+    max_deltas: usize,
+    max_delta_ratio: f64,
+    excludes: Vec<String>,
+    on_conflict: OnConflict,
 }
 
 impl<'a, 'b> Push<'a, 'b> {
     pub fn new(cfg: &'a Config, storage: &'b Storage) -> Self {
-        Push { cfg, storage }
+        Push {
+            cfg,
+            storage,
+            max_deltas: DEFAULT_MAX_DELTAS,
+            max_delta_ratio: DEFAULT_MAX_DELTA_RATIO,
+            excludes: Vec::new(),
+            on_conflict: OnConflict::default(),
+        }
+    }
+
+    /// What to do when the remote snapshot was replaced since the pull.
+    pub fn on_conflict(self, on_conflict: OnConflict) -> Self {
+        Push {
+            on_conflict,
+            ..self
+        }
+    }
+
+    /// Don't push entries matching any of the glob patterns, nor anything under them.
+    pub fn excludes<S: AsRef<str>>(self, excludes: &[S]) -> Self {
+        Push {
+            excludes: excludes.iter().map(|it| it.as_ref().to_string()).collect(),
+            ..self
+        }
+    }
+
+    /// Compact deltas into a new base snapshot when there are that many of them.
+    pub fn max_deltas(self, max_deltas: usize) -> Self {
+        Push { max_deltas, ..self }
+    }
+
+    /// Compact deltas into a new base snapshot when they're larger than that part of the base.
+    pub fn max_delta_ratio(self, max_delta_ratio: f64) -> Self {
+        Push {
+            max_delta_ratio,
+            ..self
+        }
     }
 
     pub fn run(self) -> Result<(Vec<PathBuf>, Option<usize>), Error> {
-        let Self { cfg, storage } = self;
+        let Self {
+            cfg,
+            storage,
+            max_deltas,
+            max_delta_ratio,
+            excludes,
+            on_conflict,
+        } = self;
         let mut changed = true;
 
+        let messages = ServiceMessages::current();
+        let _block = messages.block("Push cache");
+        messages.progress("Pushing cache ...");
+
+        // pushed by this job, a push without a name doesn't push it again
+        if cfg.pulled_file.exists() {
+            fs::remove_file(&cfg.pulled_file).io_err(&cfg.pulled_file)?;
+        }
+
         let cached_dirs = read_cached_dirs(&cfg.cached_dirs_file)?;
         if cached_dirs.is_empty() {
             warn!("No cached directories found, exiting");
@@ -33,13 +99,48 @@ impl<'a, 'b> Push<'a, 'b> {
             return Ok((cached_dirs, None));
         }
 
-        let previous_entries = read_cached_entries(&cfg.cached_entries_file)?;
+        let excludes = excludes
+            .iter()
+            .map(|it| {
+                Pattern::new(it).map_err(Error::snapshot(format!("Invalid pattern '{}'", it)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let exclude = |entries: Vec<Entry>| -> Vec<Entry> {
+            entries
+                .into_iter()
+                .filter(|it| !snapshot::is_glob_include(&excludes, it.as_path()))
+                .collect()
+        };
+
+        let previous_entries = exclude(read_cached_entries(&cfg.cached_entries_file)?);
         let current_entries = {
             info!("Walking cached directories ...");
             let _timer = Stats::current().walking().timer();
-            Entry::walk_into_vec(&cached_dirs)?
+            exclude(Entry::walk_into_vec(&cached_dirs)?)
         };
 
+        let footer = Footer::now();
+        let storage = &storage.clone().generation(footer.generation);
+
+        if storage.as_layout() == Layout::Split {
+            let (len, pushed) = push_groups(
+                cfg,
+                storage,
+                &cached_dirs,
+                &previous_entries,
+                &current_entries,
+                footer,
+                on_conflict,
+            )?;
+            if let Err(err) = conflict::update(cfg, storage, &pushed) {
+                warn!("Cannot record the remote snapshot's tag: {}", err);
+            }
+            if let Some(len) = len {
+                Stats::current().snapshot().inc(len);
+            }
+            return Ok((cached_dirs, len));
+        }
+
         if previous_entries.is_empty() {
             warn!("No files from a previous snapshot, assume it isn't cached before");
         } else {
@@ -51,30 +152,301 @@ impl<'a, 'b> Push<'a, 'b> {
             return Ok((cached_dirs, None));
         }
 
-        info!("Creating a new snapshot ...");
-        {
-            let _timer = Stats::current().packing().timer();
-            let snapshot = Writing::open(&cfg.snapshot_file)?;
-            snapshot.pack(&cached_dirs)?;
-        }
-
-        let meta = &cfg.snapshot_file.metadata().io_err(&cfg.snapshot_file)?;
-        let len = meta.len() as usize;
-
-        if storage.is_uploadable() {
-            if let Err(err) = storage.upload(&cfg.snapshot_file, len) {
-                if cfg.verbose {
-                    error!("{:?}", err);
-                } else {
-                    error!("{}", err);
-                }
+        let head_file_name = storage.head_file_name().unwrap_or_default();
+        if !conflict::may_replace(cfg, storage, head_file_name, on_conflict)? {
+            return Ok((cached_dirs, None));
+        }
+
+        let (len, uploaded) = match storage.as_layout() {
+            Layout::Archive => push_archive(cfg, storage, &current_entries, footer)?,
+            Layout::Chunks => push_chunks(cfg, storage, &current_entries)?,
+            Layout::Delta => push_delta(
+                cfg,
+                storage,
+                &previous_entries,
+                &current_entries,
+                footer,
+                max_deltas,
+                max_delta_ratio,
+            )?,
+            Layout::Split => unreachable!("groups are pushed separately"),
+        };
+
+        // after a failed upload the remote one is someone else's, a conflict for the next push
+        if uploaded {
+            if let Err(err) = conflict::update(cfg, storage, &[head_file_name]) {
+                warn!("Cannot record the remote snapshot's tag: {}", err);
             }
         }
 
+        Stats::current().snapshot().inc(len);
         Ok((cached_dirs, Some(len)))
     }
 }
 
+/// Packs the snapshot straight into the remote location, without a local copy.
+/// Returns its size and whether it was uploaded.
+fn push_archive(
+    cfg: &Config,
+    storage: &Storage,
+    entries: &[Entry],
+    footer: Footer,
+) -> Result<(usize, bool), Error> {
+    info!("Creating a new snapshot ...");
+
+    let upload = storage.upload_stream(Config::snapshot_file_name())?;
+    let mut digesting = Digesting::new(upload, cfg.signing_key.is_some());
+    {
+        let _timer = Stats::current().packing().timer();
+        let key = cfg.encryption_key.as_ref();
+        let snapshot = Writing::from(&mut digesting, key, storage.as_compression())?;
+        snapshot.footer(footer).pack_with_entries(entries)?;
+    }
+
+    let (upload, digest) = digesting.finish();
+    let len = upload.written();
+
+    let signed = match (&cfg.signing_key, digest) {
+        (Some(key), Some(digest)) => {
+            info!("Signing snapshot ...");
+            Signature::sign_digest(digest, key).write(&cfg.signature_file)?;
+            true
+        }
+        _ => false,
+    };
+
+    // the signature goes last, a snapshot with a stale one is ignored, never trusted
+    let res = upload.finish().and_then(|_| {
+        if signed {
+            let meta = cfg.signature_file.metadata().io_err(&cfg.signature_file)?;
+            storage.upload(&cfg.signature_file, meta.len() as usize)?;
+        }
+        Ok(())
+    });
+
+    Ok((len, uploaded(cfg, res)))
+}
+
+fn push_chunks(cfg: &Config, storage: &Storage, entries: &[Entry]) -> Result<(usize, bool), Error> {
+    info!("Creating a new snapshot ...");
+
+    let store = ChunkStore::new(cfg)?;
+    let manifest = {
+        let _timer = Stats::current().packing().timer();
+        let manifest = store.pack(entries)?;
+        manifest.write(&cfg.manifest_file, cfg.encryption_key.as_ref())?;
+        manifest
+    };
+    store.retain(&manifest.chunk_ids())?;
+
+    let (file, signature_file) = (&cfg.manifest_file, &cfg.manifest_signature_file);
+    let len = file.metadata().io_err(file)?.len() as usize;
+
+    if let Some(key) = &cfg.signing_key {
+        info!("Signing snapshot ...");
+        Signature::sign(file, key)?.write(signature_file)?;
+    }
+
+    // chunks go first, a manifest must never refer to missing ones
+    let res = store
+        .upload_missing(&manifest.chunk_ids(), storage)
+        .and_then(|uploaded| {
+            info!("Chunks uploaded: {}", pretty::bytes(uploaded));
+            upload(cfg, storage, file, signature_file, len)
+        });
+
+    Ok((len, uploaded(cfg, res)))
+}
+
+/// Pushes changed groups, returns their total size and names of the pushed snapshots.
+fn push_groups(
+    cfg: &Config,
+    storage: &Storage,
+    cached_dirs: &[PathBuf],
+    previous_entries: &[Entry],
+    current_entries: &[Entry],
+    footer: Footer,
+    on_conflict: OnConflict,
+) -> Result<(Option<usize>, Vec<String>), Error> {
+    let mut len = None;
+    let mut pushed = Vec::new();
+
+    for group in Group::all(cfg, cached_dirs)? {
+        let previous_entries = group.entries(previous_entries);
+        let current_entries = group.entries(current_entries);
+
+        info!("Checking {:?} ...", group.dir.as_os_str());
+
+        if previous_entries.is_empty() {
+            warn!("No files from a previous snapshot, assume it isn't cached before");
+        } else {
+            let diff = snapshot::diff(&previous_entries, &current_entries);
+            if !detect_changes(&diff, cfg.verbose) {
+                continue;
+            }
+        }
+
+        let file_name = group.file_name();
+        if !conflict::may_replace(cfg, storage, &file_name, on_conflict)? {
+            continue;
+        }
+
+        info!("Creating a new snapshot ...");
+
+        let file = &group.snapshot_file;
+        let group_len = pack_entries(cfg, storage, file, &current_entries, footer)?;
+
+        if let Some(key) = &cfg.signing_key {
+            info!("Signing snapshot ...");
+            Signature::sign(file, key)?.write(&group.signature_file)?;
+        }
+
+        let res = upload(cfg, storage, file, &group.signature_file, group_len);
+        if uploaded(cfg, res) {
+            pushed.push(file_name);
+        }
+
+        len = Some(len.unwrap_or(0) + group_len);
+    }
+
+    Ok((len, pushed))
+}
+
+pub(super) fn upload(
+    cfg: &Config,
+    storage: &Storage,
+    file: &Path,
+    signature_file: &Path,
+    len: usize,
+) -> Result<(), Error> {
+    if cfg.signing_key.is_some() {
+        let meta = signature_file.metadata().io_err(signature_file)?;
+        storage.upload(signature_file, meta.len() as usize)?;
+    }
+
+    storage.upload(file, len)
+}
+
+fn push_delta(
+    cfg: &Config,
+    storage: &Storage,
+    previous_entries: &[Entry],
+    current_entries: &[Entry],
+    footer: Footer,
+    max_deltas: usize,
+    max_delta_ratio: f64,
+) -> Result<(usize, bool), Error> {
+    if !cfg.deltas_dir.exists() {
+        fs::create_dir_all(&cfg.deltas_dir).io_err(&cfg.deltas_dir)?;
+    }
+
+    let mut chain = Chain::read(&cfg.chain_file)?;
+    let previous = chain.clone();
+    let mut delta = None;
+
+    if chain.base.is_some() && !previous_entries.is_empty() && chain.deltas.len() < max_deltas {
+        info!("Creating a delta snapshot ...");
+
+        let (entries, removed) = chain::delta(previous_entries, current_entries);
+        let mut link = Link::new("delta", 0, removed)?;
+        let file = link.snapshot_file(cfg);
+        link.len = pack_entries(cfg, storage, &file, &entries, footer)?;
+
+        if chain.needs_compaction(link.len, max_deltas, max_delta_ratio) {
+            info!("Deltas are too large, compacting ...");
+            fs::remove_file(&file).io_err(&file)?;
+        } else {
+            delta = Some(link);
+        }
+    }
+
+    let link = match delta {
+        Some(link) => {
+            chain.deltas.push(link.clone());
+            link
+        }
+        None => {
+            info!("Creating a new snapshot ...");
+
+            let mut link = Link::new("base", 0, Vec::new())?;
+            link.len = pack_entries(
+                cfg,
+                storage,
+                &link.snapshot_file(cfg),
+                current_entries,
+                footer,
+            )?;
+
+            chain = Chain {
+                base: Some(link.clone()),
+                deltas: Vec::new(),
+            };
+            link
+        }
+    };
+
+    let (file, signature_file) = (link.snapshot_file(cfg), link.signature_file(cfg));
+    chain.write(&cfg.chain_file)?;
+
+    if let Some(key) = &cfg.signing_key {
+        info!("Signing snapshot ...");
+        Signature::sign(&file, key)?.write(&signature_file)?;
+        Signature::sign(&cfg.chain_file, key)?.write(&cfg.chain_signature_file)?;
+    }
+
+    // the chain goes last, it must never refer to missing snapshots
+    let res = upload(cfg, storage, &file, &signature_file, link.len).and_then(|_| {
+        let len = cfg.chain_file.metadata().io_err(&cfg.chain_file)?.len();
+        let signature_file = &cfg.chain_signature_file;
+        upload(cfg, storage, &cfg.chain_file, signature_file, len as usize)
+    });
+    let uploaded = uploaded(cfg, res);
+
+    chain.retain(cfg)?;
+    if uploaded {
+        chain.retain_remote(&previous, storage);
+    }
+
+    Ok((link.len, uploaded))
+}
+
+fn pack_entries(
+    cfg: &Config,
+    storage: &Storage,
+    path: &Path,
+    entries: &[Entry],
+    footer: Footer,
+) -> Result<usize, Error> {
+    {
+        let _timer = Stats::current().packing().timer();
+        let key = cfg.encryption_key.as_ref();
+        let snapshot = Writing::open_compressed(path, key, storage.as_compression())?;
+        snapshot.footer(footer).pack_with_entries(entries)?;
+    }
+
+    let len = path.metadata().io_err(path)?.len();
+    Ok(len as usize)
+}
+
+/// A failed upload is logged, it never fails a build.
+fn uploaded(cfg: &Config, res: Result<(), Error>) -> bool {
+    match res {
+        Ok(()) => true,
+        Err(err) => {
+            log_error(cfg, &err);
+            false
+        }
+    }
+}
+
+fn log_error(cfg: &Config, err: &Error) {
+    if cfg.verbose {
+        error!("{:?}", err);
+    } else {
+        error!("{}", err);
+    }
+}
+
 fn detect_changes(diff: &HashSet<Diff>, verbose: bool) -> bool {
     let next = match diff.iter().next() {
         Some(val) => val,
@@ -119,7 +491,7 @@ fn read_cached_entries(path: &Path) -> Result<Vec<Entry>, Error> {
     serde_json::from_slice(&src).io_err(&path)
 }
 
-fn read_cached_dirs(path: &Path) -> Result<Vec<PathBuf>, Error> {
+pub(super) fn read_cached_dirs(path: &Path) -> Result<Vec<PathBuf>, Error> {
     if !path.exists() {
         return Ok(Vec::new());
     }
@@ -132,8 +504,11 @@ fn read_cached_dirs(path: &Path) -> Result<Vec<PathBuf>, Error> {
 mod tests {
     use super::*;
 
+    use url::Url;
+
+    use crate::commands::inspect::Source;
     use crate::commands::Pull;
-    use crate::testing::{self, FIXTURES_PATH};
+    use crate::testing::{self, A_FILE_PATH, B_FILE_PATH, FIXTURES_PATH};
 
     #[test]
     fn push() {
@@ -157,4 +532,140 @@ mod tests {
 
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn push_excludes() {
+        let work = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let cfg = Config::from(&work).unwrap();
+        let storage = Storage::new(&cfg)
+            .uri(uri.as_str())
+            .unwrap()
+            .uploadable(true);
+
+        let dirs = vec![PathBuf::from(FIXTURES_PATH)];
+        Pull::new(&cfg, &storage, &dirs, None::<PathBuf>)
+            .run()
+            .unwrap();
+        Push::new(&cfg, &storage)
+            .excludes(&["**/a.txt"])
+            .run()
+            .unwrap();
+
+        let uploaded = remote.as_ref().join(Config::snapshot_file_name());
+        let entries = Source::File(uploaded).read_entries(None).unwrap();
+        let has = |path: &str| {
+            let path = Path::new(path).canonicalize().unwrap();
+            entries.iter().any(|it| it.as_path() == path)
+        };
+
+        assert_eq!(has(A_FILE_PATH), false);
+        assert_eq!(has(B_FILE_PATH), true);
+    }
+
+    #[test]
+    fn push_groups_skips_newer() {
+        let first = testing::temp_dir();
+        let second = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let src = testing::temp_dir();
+        let dirs = vec![src.as_ref().join("a"), src.as_ref().join("b")];
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let write = |dir: &Path, content: &str| {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join("file"), content).unwrap();
+        };
+        write(&dirs[0], "a");
+        write(&dirs[1], "b");
+
+        let storage = |cfg: &Config| {
+            Storage::new(cfg)
+                .uri(uri.as_str())
+                .unwrap()
+                .uploadable(true)
+                .layout(Layout::Split)
+        };
+        let (cfg, other) = (
+            Config::from(first.as_ref()).unwrap(),
+            Config::from(second.as_ref()).unwrap(),
+        );
+
+        Pull::new(&cfg, &storage(&cfg), &dirs, None::<PathBuf>)
+            .run()
+            .unwrap();
+        Pull::new(&other, &storage(&other), &dirs, None::<PathBuf>)
+            .run()
+            .unwrap();
+
+        // both builds pulled nothing, the other one pushes first
+        Push::new(&other, &storage(&other)).run().unwrap();
+
+        let groups = Group::all(&cfg, &dirs).unwrap();
+        let generation =
+            |group: &Group| storage(&cfg).remote_generation(group.file_name()).unwrap();
+        let pushed = generation(&groups[0]);
+        assert!(pushed.is_some());
+
+        write(&dirs[0], "changed");
+        let (_, len) = Push::new(&cfg, &storage(&cfg)).run().unwrap();
+        assert_eq!(len, None);
+        assert_eq!(generation(&groups[0]), pushed);
+
+        let (_, len) = Push::new(&cfg, &storage(&cfg))
+            .on_conflict(OnConflict::Ignore)
+            .run()
+            .unwrap();
+        assert!(len.is_some());
+        assert!(generation(&groups[0]) > pushed);
+    }
+
+    #[test]
+    fn failed_upload_keeps_conflicts() {
+        let first = testing::temp_dir();
+        let second = testing::temp_dir();
+        let remote = testing::temp_dir();
+        let dirs = vec![PathBuf::from(FIXTURES_PATH)];
+        let uri = Url::from_directory_path(remote.as_ref()).unwrap();
+
+        let storage = |cfg: &Config| {
+            Storage::new(cfg)
+                .uri(uri.as_str())
+                .unwrap()
+                .uploadable(true)
+        };
+        let (cfg, other) = (
+            Config::from(first.as_ref()).unwrap(),
+            Config::from(second.as_ref()).unwrap(),
+        );
+
+        Pull::new(&cfg, &storage(&cfg), &dirs, None::<PathBuf>)
+            .run()
+            .unwrap();
+        Pull::new(&other, &storage(&other), &dirs, None::<PathBuf>)
+            .run()
+            .unwrap();
+        Push::new(&other, &storage(&other)).run().unwrap();
+
+        let file_name = Config::snapshot_file_name();
+        let generation = || storage(&cfg).remote_generation(file_name).unwrap();
+        let pushed = generation();
+
+        // the file system backend can't create its temporary file
+        let tmp = remote.as_ref().join(format!("{}.tmp", file_name));
+        fs::create_dir(&tmp).unwrap();
+        Push::new(&cfg, &storage(&cfg))
+            .on_conflict(OnConflict::Ignore)
+            .run()
+            .unwrap();
+        assert_eq!(generation(), pushed);
+        fs::remove_dir(&tmp).unwrap();
+
+        // the other build's snapshot is still a conflict, not taken for the pushed one
+        let (_, len) = Push::new(&cfg, &storage(&cfg)).run().unwrap();
+        assert_eq!(len, None);
+        assert_eq!(generation(), pushed);
+    }
 }
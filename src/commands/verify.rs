@@ -0,0 +1,315 @@
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read};
+use std::path::PathBuf;
+
+use log::{error, info};
+
+use crate::commands::diff;
+use crate::commands::inspect::Source;
+use crate::crypto::Key;
+use crate::hashing::md5::Hashing;
+use crate::snapshot::{self, Diff, Entry, Reading};
+use crate::{Config, Error, ErrorKind};
+
+pub const EXIT_OK: i32 = 0;
+/// The snapshot can't be read at all, e.g. a download failed.
+pub const EXIT_FAILED: i32 = 1;
+/// The version header, an entry or the stream itself is broken.
+pub const EXIT_CORRUPTED: i32 = 2;
+/// A file's content doesn't match its md5 or length.
+pub const EXIT_MISMATCH: i32 = 3;
+/// The snapshot is fine but differs from what's on disk.
+pub const EXIT_DIFFERS: i32 = 4;
+
+#[derive(Debug)]
+pub struct Verify<'a, 'b> {
+    cfg: &'a Config,
+    source: Source<'b>,
+    disk: bool,
+}
+
+#[derive(Debug)]
+pub enum Problem {
+    Corrupted(String),
+    Length {
+        path: PathBuf,
+        expected: usize,
+        actual: usize,
+    },
+    Checksum {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    Disk(Diff),
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub entries: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl<'a, 'b> Verify<'a, 'b> {
+    pub fn new(cfg: &'a Config, source: Source<'b>) -> Self {
+        Verify {
+            cfg,
+            source,
+            disk: false,
+        }
+    }
+
+    /// Also compare the snapshot with what's on disk under the cached directories.
+    pub fn disk(self, disk: bool) -> Self {
+        Verify { disk, ..self }
+    }
+
+    pub fn run(self) -> Result<Report, Error> {
+        let Self { cfg, source, disk } = self;
+        let mut report = Report::default();
+
+        let src = source.open()?;
+        let entries = read_snapshot(src, cfg.encryption_key.as_ref(), &mut report)?;
+        report.entries = entries.len();
+
+        if disk && report.is_ok() {
+            compare_disk(cfg, &entries, &mut report)?;
+        }
+
+        for it in &report.problems {
+            error!("{}", it);
+        }
+
+        info!(
+            "Verified {} entries, found {} problems",
+            report.entries,
+            report.problems.len()
+        );
+
+        Ok(report)
+    }
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// The most severe problem decides the code.
+    pub fn exit_code(&self) -> i32 {
+        self.problems
+            .iter()
+            .map(|it| match it {
+                Problem::Corrupted(_) => EXIT_CORRUPTED,
+                Problem::Length { .. } | Problem::Checksum { .. } => EXIT_MISMATCH,
+                Problem::Disk(_) => EXIT_DIFFERS,
+            })
+            .min()
+            .unwrap_or(EXIT_OK)
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Problem::Corrupted(err) => write!(f, "Corrupted snapshot; {}", err),
+            Problem::Length {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Length mismatch at {:?}; expected {}, got {}",
+                path, expected, actual
+            ),
+            Problem::Checksum {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Checksum mismatch at {:?}; expected {}, got {}",
+                path, expected, actual
+            ),
+            Problem::Disk(diff) => write!(f, "Differs from disk; {}", diff),
+        }
+    }
+}
+
+/// Reads the whole snapshot, returns entries read before the first broken one.
+fn read_snapshot<R: Read>(
+    src: R,
+    key: Option<&Key>,
+    report: &mut Report,
+) -> Result<Vec<Entry>, Error> {
+    let mut entries = Vec::new();
+
+    let mut snapshot = match Reading::from(src, key) {
+        Ok(val) => val,
+        Err(err) => {
+            report.problems.push(corrupted(err)?);
+            return Ok(entries);
+        }
+    };
+
+    loop {
+        let entry = match snapshot.read_entry() {
+            Ok(Some((entry, _))) => entry,
+            Ok(None) => break,
+            Err(err) => {
+                report.problems.push(corrupted(err)?);
+                break;
+            }
+        };
+
+        if let Some((path, _, md5, len)) = entry.as_file() {
+            let mut hashing = Hashing::default();
+
+            if let Err(err) = snapshot.copy_to(&mut hashing, len) {
+                let problem = if is_truncated(&err) {
+                    Problem::Length {
+                        path: path.to_path_buf(),
+                        expected: len,
+                        actual: hashing.written(),
+                    }
+                } else {
+                    corrupted(err)?
+                };
+
+                report.problems.push(problem);
+                break;
+            }
+
+            let actual = hashing.finish();
+            if actual != md5 {
+                report.problems.push(Problem::Checksum {
+                    path: path.to_path_buf(),
+                    expected: md5.to_string(),
+                    actual,
+                });
+            }
+        }
+
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+/// Broken content is a problem to report, anything else (e.g. a failed download)
+/// stops verification.
+fn corrupted(err: Error) -> Result<Problem, Error> {
+    match err.kind() {
+        ErrorKind::Snapshot(_) | ErrorKind::Encryption => Ok(Problem::Corrupted(err.to_string())),
+        _ => Err(err),
+    }
+}
+
+/// The stream ended before a file's content did.
+fn is_truncated(err: &Error) -> bool {
+    err.source()
+        .and_then(|it| it.downcast_ref::<IoError>())
+        .map_or(false, |it| it.kind() == IoErrorKind::UnexpectedEof)
+}
+
+fn compare_disk(cfg: &Config, entries: &[Entry], report: &mut Report) -> Result<(), Error> {
+    let (entries, on_disk) = diff::on_disk(cfg, entries)?;
+    let diff = diff::sorted(snapshot::diff(&entries, &on_disk));
+
+    report.problems.extend(diff.into_iter().map(Problem::Disk));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+
+    use crate::commands::push::read_cached_dirs;
+    use crate::commands::Pull;
+    use crate::snapshot::{Attributes, Writing};
+    use crate::testing::{self, FIXTURES_PATH};
+    use crate::Storage;
+
+    #[test]
+    fn verify_snapshot() {
+        let work = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+
+        testing::pack(&cfg.snapshot_file, &[FIXTURES_PATH]);
+
+        let source = Source::File(cfg.snapshot_file.clone());
+        let report = Verify::new(&cfg, source).run().unwrap();
+
+        assert!(report.entries > 0);
+        assert_eq!(report.exit_code(), EXIT_OK);
+    }
+
+    #[test]
+    fn verify_corrupted() {
+        let work = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+
+        testing::pack(&cfg.snapshot_file, &[FIXTURES_PATH]);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&cfg.snapshot_file)
+            .unwrap();
+        file.seek(SeekFrom::Start(20)).unwrap();
+        file.write_all(&[0xff; 16]).unwrap();
+
+        let source = Source::File(cfg.snapshot_file.clone());
+        let report = Verify::new(&cfg, source).run().unwrap();
+
+        assert_eq!(report.exit_code(), EXIT_CORRUPTED);
+    }
+
+    #[test]
+    fn verify_checksum() {
+        let work = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let attr = Attributes::new(0o644, 0, 0);
+
+        let mut snapshot = Writing::open(&cfg.snapshot_file, None).unwrap();
+        let entry = Entry::file("a.txt", attr, "not a md5", 1).unwrap();
+        snapshot.write_entry(&entry).unwrap();
+        snapshot.write_file(testing::A_FILE_PATH, Some(1)).unwrap();
+        snapshot.finish().unwrap();
+
+        let source = Source::File(cfg.snapshot_file.clone());
+        let report = Verify::new(&cfg, source).run().unwrap();
+
+        assert_eq!(report.exit_code(), EXIT_MISMATCH);
+    }
+
+    #[test]
+    fn verify_disk() {
+        let work = testing::temp_dir();
+        let cached = testing::temp_dir();
+        let cached_file = cached.as_ref().join("file");
+        fs::write(&cached_file, "content").unwrap();
+
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg);
+        Pull::new(&cfg, &storage, &[cached.as_ref()], None::<PathBuf>)
+            .run()
+            .unwrap();
+
+        let dirs = read_cached_dirs(&cfg.cached_dirs_file).unwrap();
+        testing::pack(&cfg.snapshot_file, &dirs);
+
+        let source = || Source::File(cfg.snapshot_file.clone());
+        let report = Verify::new(&cfg, source()).disk(true).run().unwrap();
+        assert_eq!(report.exit_code(), EXIT_OK);
+
+        fs::write(&cached_file, "changed").unwrap();
+
+        let report = Verify::new(&cfg, source()).disk(true).run().unwrap();
+        assert_eq!(report.exit_code(), EXIT_DIFFERS);
+        assert_eq!(report.problems.len(), 1);
+    }
+}
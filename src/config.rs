@@ -2,18 +2,38 @@ use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+use crate::crypto::{Key, SigningKey, TrustedKeys};
 use crate::errors::ResultExt;
-use crate::Error;
+use crate::{Error, Project, DEFAULT_CACHE};
 
 const WORK_DIR: &str = ".tc-cache";
+const CACHES_DIR: &str = "caches";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
+    /// A cache's name, the default one keeps its state right in the working directory.
+    pub name: String,
     pub working_dir: PathBuf,
     pub cached_dirs_file: PathBuf,
     pub cached_entries_file: PathBuf,
     pub snapshot_file: PathBuf,
+    pub signature_file: PathBuf,
+    pub manifest_file: PathBuf,
+    pub manifest_signature_file: PathBuf,
+    pub chunks_dir: PathBuf,
+    pub groups_dir: PathBuf,
+    pub deltas_dir: PathBuf,
+    pub chain_file: PathBuf,
+    pub chain_signature_file: PathBuf,
     pub storage_file: PathBuf,
+    pub remote_file: PathBuf,
+    /// Exists from a pull till the next push, a push without a name pushes such caches.
+    pub pulled_file: PathBuf,
+    pub lock_file: PathBuf,
+    pub encryption_key: Option<Key>,
+    pub signing_key: Option<SigningKey>,
+    pub trusted_keys: Option<TrustedKeys>,
+    pub project: Project,
     pub verbose: bool,
 }
 
@@ -45,24 +65,213 @@ impl Config {
         let mut snapshot_file = working_dir.clone();
         snapshot_file.push(Config::snapshot_file_name());
 
+        let mut signature_file = working_dir.clone();
+        signature_file.push(Config::signature_file_name());
+
+        let mut manifest_file = working_dir.clone();
+        manifest_file.push(Config::manifest_file_name());
+
+        let mut manifest_signature_file = working_dir.clone();
+        manifest_signature_file.push(Config::manifest_signature_file_name());
+
+        let mut chunks_dir = working_dir.clone();
+        chunks_dir.push("chunks");
+
+        let mut groups_dir = working_dir.clone();
+        groups_dir.push("groups");
+
+        let mut deltas_dir = working_dir.clone();
+        deltas_dir.push("deltas");
+
+        let chain_file = deltas_dir.join(Config::chain_file_name());
+        let chain_signature_file = deltas_dir.join(Config::chain_signature_file_name());
+
         let mut storage_file = working_dir.clone();
         storage_file.push("storage.json");
 
+        let remote_file = working_dir.join("remote.json");
+        let pulled_file = working_dir.join("pulled");
+        let lock_file = working_dir.join("lock");
+
         Ok(Config {
+            name: DEFAULT_CACHE.to_string(),
             working_dir,
             cached_dirs_file,
             cached_entries_file,
             snapshot_file,
+            signature_file,
+            manifest_file,
+            manifest_signature_file,
+            chunks_dir,
+            groups_dir,
+            deltas_dir,
+            chain_file,
+            chain_signature_file,
             storage_file,
+            remote_file,
+            pulled_file,
+            lock_file,
+            encryption_key: None,
+            signing_key: None,
+            trusted_keys: None,
+            project: Project::default(),
             verbose: false,
         })
     }
 
+    /// A config of a named cache, which state is kept in a subdirectory of the working
+    /// directory, so several caches can be pulled and pushed in the same job.
+    pub fn named(&self, name: &str) -> Result<Self, Error> {
+        if name == DEFAULT_CACHE {
+            return Ok(self.clone());
+        }
+
+        if !is_valid_name(name) {
+            return Err(Error::usage(format!("Invalid cache name '{}'", name)));
+        }
+
+        let caches_dir = self.working_dir.join(CACHES_DIR);
+        let named = Config::from(caches_dir.join(name))?;
+        Ok(Config {
+            name: name.to_string(),
+            encryption_key: self.encryption_key.clone(),
+            signing_key: self.signing_key.clone(),
+            trusted_keys: self.trusted_keys.clone(),
+            project: self.project.clone(),
+            verbose: self.verbose,
+            ..named
+        })
+    }
+
+    /// Names of caches that were pulled into the working directory and not pushed since,
+    /// caches left by other jobs were pushed by them.
+    pub fn pulled_names(&self) -> Result<Vec<String>, Error> {
+        let is_pulled = |cfg: &Config| cfg.pulled_file.exists() && cfg.storage_file.exists();
+        let mut names = Vec::new();
+
+        if is_pulled(self) {
+            names.push(DEFAULT_CACHE.to_string());
+        }
+
+        let caches_dir = self.working_dir.join(CACHES_DIR);
+        if caches_dir.exists() {
+            let mut named = Vec::new();
+            for item in fs::read_dir(&caches_dir).io_err(&caches_dir)? {
+                let path = item.io_err(&caches_dir)?.path();
+                let name = match path.file_name().and_then(|it| it.to_str()) {
+                    Some(name) if path.is_dir() && is_valid_name(name) => name,
+                    _ => continue,
+                };
+
+                if is_pulled(&self.named(name)?) {
+                    named.push(name.to_string());
+                }
+            }
+            named.sort();
+            names.extend(named);
+        }
+
+        Ok(names)
+    }
+
     pub fn snapshot_file_name() -> &'static str {
         "snapshot.snappy"
     }
 
+    pub fn signature_file_name() -> &'static str {
+        "snapshot.snappy.sig"
+    }
+
+    pub fn manifest_file_name() -> &'static str {
+        "manifest.snappy"
+    }
+
+    pub fn manifest_signature_file_name() -> &'static str {
+        "manifest.snappy.sig"
+    }
+
+    pub fn chain_file_name() -> &'static str {
+        "chain.json"
+    }
+
+    pub fn chain_signature_file_name() -> &'static str {
+        "chain.json.sig"
+    }
+
     pub fn verbose(&mut self, verbose: bool) {
         self.verbose = verbose;
     }
+
+    pub fn encryption_key(&mut self, key: Option<Key>) {
+        self.encryption_key = key;
+    }
+
+    pub fn signing_key(&mut self, key: Option<SigningKey>) {
+        self.signing_key = key;
+    }
+
+    pub fn trusted_keys(&mut self, keys: Option<TrustedKeys>) {
+        self.trusted_keys = keys;
+    }
+
+    pub fn project(&mut self, project: Project) {
+        self.project = project;
+    }
+}
+
+/// A name of a directory in the working one, not a path nor a hidden file.
+fn is_valid_name(name: &str) -> bool {
+    let is_valid = |ch: char| ch.is_ascii_alphanumeric() || ch == '_' || ch == '-' || ch == '.';
+    !name.is_empty() && !name.starts_with('.') && name.chars().all(is_valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::errors::ErrorKind;
+    use crate::testing;
+
+    #[test]
+    fn named_caches() {
+        let work = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+
+        let default = cfg.named(DEFAULT_CACHE).unwrap();
+        assert_eq!(default.snapshot_file, cfg.snapshot_file);
+
+        let deps = cfg.named("deps").unwrap();
+        assert_eq!(deps.name, "deps");
+        assert_eq!(deps.working_dir, cfg.working_dir.join("caches/deps"));
+        assert_ne!(deps.storage_file, cfg.storage_file);
+
+        match cfg.named("../deps") {
+            Err(err) => match err.kind() {
+                ErrorKind::Usage => {}
+                other => unreachable!("{:?}", other),
+            },
+            Ok(ok) => unreachable!("{:?}", ok),
+        }
+        assert!(cfg.named("").is_err());
+
+        assert_eq!(cfg.pulled_names().unwrap(), Vec::<String>::new());
+
+        for cfg in &[&cfg, &deps] {
+            fs::write(&cfg.storage_file, "{}").unwrap();
+            fs::write(&cfg.pulled_file, "").unwrap();
+        }
+        cfg.named("build").unwrap();
+
+        // already pushed, e.g. by another job
+        let stale = cfg.named("stale").unwrap();
+        fs::write(&stale.storage_file, "{}").unwrap();
+
+        // entries which aren't directories or valid names are skipped
+        let caches_dir = cfg.working_dir.join(CACHES_DIR);
+        fs::write(caches_dir.join("file"), "").unwrap();
+        fs::create_dir(caches_dir.join(".hidden")).unwrap();
+        fs::create_dir(caches_dir.join("white space")).unwrap();
+
+        assert_eq!(cfg.pulled_names().unwrap(), vec![DEFAULT_CACHE, "deps"]);
+    }
 }
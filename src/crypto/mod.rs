@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::fs;
+use std::path::Path;
+
+mod signature;
+mod stream;
+
+pub use self::signature::{Digesting, Signature, SigningKey, TrustedKeys};
+pub use self::stream::{Opening, Sealing};
+use crate::errors::ResultExt;
+use crate::Error;
+
+const ENCRYPTION_KEY: &str = "TC_CACHE_ENCRYPTION_KEY";
+const ENCRYPTION_KEY_FILE: &str = "TC_CACHE_ENCRYPTION_KEY_FILE";
+
+pub const KEY_LEN: usize = 32;
+
+#[derive(Clone, PartialEq)]
+pub struct Key([u8; KEY_LEN]);
+
+impl Key {
+    pub fn from_env(env: &HashMap<String, String>) -> Result<Option<Self>, Error> {
+        match read_secret(env, ENCRYPTION_KEY, ENCRYPTION_KEY_FILE)? {
+            Some(hex) => Key::from_hex(hex).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn from_hex<S>(hex: S) -> Result<Self, Error>
+    where
+        S: AsRef<str>,
+    {
+        let bytes = hex::decode(hex.as_ref().trim()).map_err(Error::encryption)?;
+
+        if bytes.len() != KEY_LEN {
+            let err = format!(
+                "Key must be {} bytes long (hex encoded), got {}",
+                KEY_LEN,
+                bytes.len()
+            );
+            return Err(Error::encryption(err));
+        }
+
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(&bytes);
+
+        Ok(Key(key))
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "Key(..)")
+    }
+}
+
+/// Reads a secret either from the environment variable itself or from a file
+/// pointed by the second one, the variable wins when both are set.
+pub fn read_secret(
+    env: &HashMap<String, String>,
+    var: &str,
+    file_var: &str,
+) -> Result<Option<String>, Error> {
+    if let Some(val) = env.get(var) {
+        return Ok(Some(val.trim().to_string()));
+    }
+
+    if let Some(path) = env.get(file_var) {
+        let path = Path::new(path);
+        let content = fs::read_to_string(&path).io_err(&path)?;
+        return Ok(Some(content.trim().to_string()));
+    }
+
+    Ok(None)
+}
+
+pub fn random_bytes(buf: &mut [u8]) -> Result<(), Error> {
+    getrandom::getrandom(buf).map_err(|err| Error::encryption(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use crate::testing;
+
+    const HEX_KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn key_from_env() {
+        let mut env = HashMap::new();
+
+        assert_eq!(Key::from_env(&env).unwrap(), None);
+
+        env.insert(ENCRYPTION_KEY.into(), HEX_KEY.into());
+        let key = Key::from_env(&env).unwrap().unwrap();
+
+        assert_eq!(key.as_bytes()[31], 0x1f);
+        assert_eq!(format!("{:?}", key), "Key(..)");
+    }
+
+    #[test]
+    fn key_from_file() {
+        let file = testing::temp_file(".key");
+        fs::File::create(&file)
+            .unwrap()
+            .write_all(format!("{}\n", HEX_KEY).as_bytes())
+            .unwrap();
+
+        let mut env = HashMap::new();
+        env.insert(
+            ENCRYPTION_KEY_FILE.into(),
+            file.as_ref().to_str().unwrap().into(),
+        );
+
+        let key = Key::from_env(&env).unwrap().unwrap();
+        assert_eq!(key, Key::from_hex(HEX_KEY).unwrap());
+    }
+
+    #[test]
+    fn key_with_wrong_len() {
+        let err = Key::from_hex("0001").unwrap_err();
+        assert!(err.to_string().contains("must be 32 bytes"), "{}", err);
+    }
+}
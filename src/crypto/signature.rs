@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::fs::{File, OpenOptions};
+use std::io::{Error as IoError, Write};
+use std::path::Path;
+
+use ed25519_dalek::{self as ed25519, Signer};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::crypto::read_secret;
+use crate::errors::ResultExt;
+use crate::{mmap, Error};
+
+const SIGNING_KEY: &str = "TC_CACHE_SIGNING_KEY";
+const SIGNING_KEY_FILE: &str = "TC_CACHE_SIGNING_KEY_FILE";
+const TRUSTED_KEYS: &str = "TC_CACHE_TRUSTED_KEYS";
+const TRUSTED_KEYS_FILE: &str = "TC_CACHE_TRUSTED_KEYS_FILE";
+
+#[derive(Clone)]
+pub struct SigningKey(ed25519::SigningKey);
+
+impl SigningKey {
+    pub fn from_env(env: &HashMap<String, String>) -> Result<Option<Self>, Error> {
+        match read_secret(env, SIGNING_KEY, SIGNING_KEY_FILE)? {
+            Some(hex) => SigningKey::from_hex(hex).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn from_hex<S>(hex: S) -> Result<Self, Error>
+    where
+        S: AsRef<str>,
+    {
+        let bytes = decode_hex(hex.as_ref())?;
+        Ok(SigningKey(ed25519::SigningKey::from_bytes(&bytes)))
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.0.verifying_key().as_bytes())
+    }
+}
+
+impl Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "SigningKey({})", self.public_key())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrustedKeys(Vec<ed25519::VerifyingKey>);
+
+impl TrustedKeys {
+    pub fn from_env(env: &HashMap<String, String>) -> Result<Option<Self>, Error> {
+        match read_secret(env, TRUSTED_KEYS, TRUSTED_KEYS_FILE)? {
+            Some(content) => TrustedKeys::from_content(content).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Accepts hex encoded public keys separated by commas or new lines,
+    /// lines starting with '#' are ignored.
+    pub fn from_content<S>(content: S) -> Result<Self, Error>
+    where
+        S: AsRef<str>,
+    {
+        let keys = content
+            .as_ref()
+            .lines()
+            .map(str::trim)
+            .filter(|it| !it.starts_with('#'))
+            .flat_map(|it| it.split(','))
+            .map(str::trim)
+            .filter(|it| !it.is_empty())
+            .map(|it| {
+                let bytes = decode_hex(it)?;
+                ed25519::VerifyingKey::from_bytes(&bytes).map_err(Error::signature)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        if keys.is_empty() {
+            return Err(Error::signature("No trusted keys found"));
+        }
+
+        Ok(TrustedKeys(keys))
+    }
+
+    fn find(&self, public_key: &[u8]) -> Option<&ed25519::VerifyingKey> {
+        self.0.iter().find(|it| it.as_bytes() == public_key)
+    }
+}
+
+/// A detached signature of the snapshot's SHA-256 digest.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Signature {
+    digest: String,
+    public_key: String,
+    signature: String,
+}
+
+impl Signature {
+    pub fn sign<P>(path: P, key: &SigningKey) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        digest(&path).map(|it| Signature::sign_digest(it, key))
+    }
+
+    pub fn sign_digest(digest: [u8; 32], key: &SigningKey) -> Self {
+        let signature = key.0.sign(&digest);
+
+        Signature {
+            digest: hex::encode(digest),
+            public_key: key.public_key(),
+            signature: hex::encode(signature.to_bytes().as_ref()),
+        }
+    }
+
+    pub fn verify<P>(&self, path: P, trusted: &TrustedKeys) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let public_key = hex::decode(&self.public_key).map_err(Error::signature)?;
+        let public_key = match trusted.find(&public_key) {
+            Some(key) => key,
+            None => {
+                let err = format!("Signed by untrusted key {}", self.public_key);
+                return Err(Error::signature(err));
+            }
+        };
+
+        let digest = digest(&path)?;
+        if hex::encode(digest) != self.digest {
+            return Err(Error::signature("Digest mismatch"));
+        }
+
+        let signature = hex::decode(&self.signature).map_err(Error::signature)?;
+        let signature = ed25519::Signature::from_slice(&signature).map_err(Error::signature)?;
+
+        public_key
+            .verify_strict(&digest, &signature)
+            .map_err(Error::signature)
+    }
+
+    pub fn read<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::open(&path).io_err(&path)?;
+        serde_json::from_reader(&file).io_err(&path)
+    }
+
+    pub fn write<P>(&self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let mut opts = OpenOptions::new();
+        let file = opts
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&path)
+            .io_err(&path)?;
+
+        serde_json::to_writer(&file, self).io_err(&path)
+    }
+}
+
+/// Computes the SHA-256 digest of everything written through it, when enabled.
+pub struct Digesting<W: Write> {
+    inner: W,
+    hasher: Option<Sha256>,
+}
+
+impl<W: Write> Digesting<W> {
+    pub fn new(inner: W, enabled: bool) -> Self {
+        let hasher = if enabled { Some(Sha256::new()) } else { None };
+        Digesting { inner, hasher }
+    }
+
+    pub fn finish(self) -> (W, Option<[u8; 32]>) {
+        let digest = self.hasher.map(|it| it.finalize().into());
+        (self.inner, digest)
+    }
+}
+
+impl<W: Write> Write for Digesting<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let len = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+
+pub fn digest<P>(path: P) -> Result<[u8; 32], Error>
+where
+    P: AsRef<Path>,
+{
+    let mut hasher = Sha256::new();
+
+    let len = path.as_ref().metadata().io_err(&path)?.len();
+    if len > 0 {
+        let (_, _, src) = mmap::read(&path, None)?;
+        hasher.update(&src);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+fn decode_hex(hex: &str) -> Result<[u8; 32], Error> {
+    let bytes = hex::decode(hex.trim()).map_err(Error::signature)?;
+
+    if bytes.len() != 32 {
+        let err = format!(
+            "Key must be 32 bytes long (hex encoded), got {}",
+            bytes.len()
+        );
+        return Err(Error::signature(err));
+    }
+
+    let mut key = [0; 32];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::testing::{self, A_FILE_PATH, B_FILE_PATH};
+    use crate::ErrorKind;
+
+    fn keys() -> (SigningKey, TrustedKeys) {
+        let key = SigningKey::from_hex(hex::encode([1; 32])).unwrap();
+        let trusted = TrustedKeys::from_content(key.public_key()).unwrap();
+        (key, trusted)
+    }
+
+    fn assert_signature_err(res: Result<(), Error>) {
+        match res.unwrap_err().kind() {
+            ErrorKind::Signature => {}
+            kind => panic!("expected signature error, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let (key, trusted) = keys();
+        let dst = testing::temp_file(".sig");
+
+        Signature::sign(B_FILE_PATH, &key)
+            .unwrap()
+            .write(&dst)
+            .unwrap();
+
+        let signature = Signature::read(&dst).unwrap();
+        signature.verify(B_FILE_PATH, &trusted).unwrap();
+    }
+
+    #[test]
+    fn sign_written_digest() {
+        let (key, trusted) = keys();
+        let src = fs::read(B_FILE_PATH).unwrap();
+
+        let mut digesting = Digesting::new(Vec::new(), true);
+        digesting.write_all(&src).unwrap();
+        let (written, digest) = digesting.finish();
+
+        assert_eq!(written, src);
+
+        let signature = Signature::sign_digest(digest.unwrap(), &key);
+        signature.verify(B_FILE_PATH, &trusted).unwrap();
+    }
+
+    #[test]
+    fn reject_other_content() {
+        let (key, trusted) = keys();
+        let signature = Signature::sign(B_FILE_PATH, &key).unwrap();
+
+        assert_signature_err(signature.verify(A_FILE_PATH, &trusted));
+    }
+
+    #[test]
+    fn reject_forged_digest() {
+        let (key, trusted) = keys();
+        let src = testing::temp_file(".sn");
+        fs::copy(B_FILE_PATH, &src).unwrap();
+
+        let mut signature = Signature::sign(&src, &key).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&src)
+            .unwrap()
+            .write_all(b"injected")
+            .unwrap();
+        signature.digest = hex::encode(digest(&src).unwrap());
+
+        assert_signature_err(signature.verify(&src, &trusted));
+    }
+
+    #[test]
+    fn reject_untrusted_key() {
+        let (_, trusted) = keys();
+        let other = SigningKey::from_hex(hex::encode([2; 32])).unwrap();
+        let signature = Signature::sign(B_FILE_PATH, &other).unwrap();
+
+        assert_signature_err(signature.verify(B_FILE_PATH, &trusted));
+    }
+
+    #[test]
+    fn trusted_keys_from_content() {
+        let first = SigningKey::from_hex(hex::encode([1; 32])).unwrap();
+        let second = SigningKey::from_hex(hex::encode([2; 32])).unwrap();
+        let content = format!(
+            "# ci agents\n{}, {}\n\n",
+            first.public_key(),
+            second.public_key()
+        );
+
+        let trusted = TrustedKeys::from_content(content).unwrap();
+        assert_eq!(trusted.0.len(), 2);
+
+        assert!(TrustedKeys::from_content("# nothing").is_err());
+        assert!(TrustedKeys::from_content("0001").is_err());
+    }
+}
@@ -0,0 +1,370 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+use crate::bytes::IntoLeBytes;
+use crate::crypto::{random_bytes, Key};
+use crate::Error;
+
+// Encrypted stream layout:
+//
+//   MAGIC | nonce prefix (7 bytes) | frame | frame | ... | last frame
+//
+// where each frame is a little endian u32 length of the sealed payload (the high bit
+// marks the last frame) followed by the ChaCha20-Poly1305 sealed payload. Nonces are
+// `prefix | counter (u32, big endian) | last flag`, so reordered, dropped or truncated
+// frames fail authentication.
+
+pub const MAGIC: &[u8; MAGIC_LEN] = &[0xA0, 0xF1, 0xB2, 0xE1];
+const MAGIC_LEN: usize = 4;
+const PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+const FRAME_LEN: usize = 64 * 1024; // 64kb
+const LAST_FRAME: u32 = 1 << 31;
+
+struct Cipher {
+    aead: ChaCha20Poly1305,
+    prefix: [u8; PREFIX_LEN],
+    counter: u32,
+}
+
+impl Cipher {
+    fn new(key: &Key, prefix: [u8; PREFIX_LEN]) -> Self {
+        let aead = ChaCha20Poly1305::new_from_slice(key.as_bytes()).expect("valid key length");
+        Cipher {
+            aead,
+            prefix,
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self, last: bool) -> Result<Nonce, Error> {
+        let mut nonce = Nonce::default();
+        nonce[..PREFIX_LEN].copy_from_slice(&self.prefix);
+        nonce[PREFIX_LEN..PREFIX_LEN + 4].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[PREFIX_LEN + 4] = last as u8;
+
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| Error::encryption("Too many frames in a stream"))?;
+
+        Ok(nonce)
+    }
+
+    fn seal(&mut self, buf: &[u8], last: bool) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce(last)?;
+        self.aead
+            .encrypt(&nonce, buf)
+            .map_err(|_| Error::encryption("Seal frame failed"))
+    }
+
+    fn open(&mut self, buf: &[u8], last: bool) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce(last)?;
+        self.aead
+            .decrypt(&nonce, buf)
+            .map_err(|_| Error::encryption("Authentication failed (wrong key or tampered data)"))
+    }
+}
+
+/// Encrypts everything written into it, or passes bytes through as is without a key.
+/// `finish` must be called to write the last frame, otherwise the stream is unreadable.
+pub struct Sealing<W: Write> {
+    inner: W,
+    cipher: Option<Cipher>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Sealing<W> {
+    pub fn new(mut inner: W, key: Option<&Key>) -> Result<Self, Error> {
+        let cipher = match key {
+            Some(key) => {
+                let mut prefix = [0; PREFIX_LEN];
+                random_bytes(&mut prefix)?;
+
+                inner.write_all(MAGIC).map_err(Error::encryption)?;
+                inner.write_all(&prefix).map_err(Error::encryption)?;
+
+                Some(Cipher::new(key, prefix))
+            }
+            None => None,
+        };
+
+        Ok(Sealing {
+            inner,
+            cipher,
+            buf: Vec::with_capacity(FRAME_LEN),
+        })
+    }
+
+    pub fn finish(mut self) -> Result<W, Error> {
+        if self.cipher.is_some() {
+            self.write_frame(true).map_err(Error::encryption)?;
+        }
+        self.inner.flush().map_err(Error::encryption)?;
+
+        Ok(self.inner)
+    }
+
+    fn write_frame(&mut self, last: bool) -> Result<(), IoError> {
+        let cipher = self.cipher.as_mut().expect("cipher must be");
+        let sealed = cipher.seal(&self.buf, last).map_err(into_io_error)?;
+
+        let mut len = sealed.len() as u32;
+        if last {
+            len |= LAST_FRAME;
+        }
+
+        self.inner.write_all(&len.into_le_bytes())?;
+        self.inner.write_all(&sealed)?;
+        self.buf.clear();
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Sealing<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        if self.cipher.is_none() {
+            return self.inner.write(buf);
+        }
+
+        if self.buf.len() == FRAME_LEN {
+            self.write_frame(false)?;
+        }
+
+        let len = buf.len().min(FRAME_LEN - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts and authenticates a stream written by `Sealing`. A stream without
+/// the magic header is passed through as is, but only when no key is given.
+pub struct Opening<R: Read> {
+    inner: R,
+    cipher: Option<Cipher>,
+    buf: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> Opening<R> {
+    pub fn new(mut inner: R, key: Option<&Key>) -> Result<Self, Error> {
+        let mut magic = [0; MAGIC_LEN];
+        let len = read_full(&mut inner, &mut magic).map_err(Error::encryption)?;
+        let is_sealed = len == MAGIC_LEN && magic == *MAGIC;
+
+        let cipher = match (key, is_sealed) {
+            (Some(key), true) => {
+                let mut prefix = [0; PREFIX_LEN];
+                inner
+                    .read_exact(&mut prefix)
+                    .map_err(|_| Error::encryption("Read nonce prefix failed"))?;
+                Some(Cipher::new(key, prefix))
+            }
+            (None, true) => {
+                return Err(Error::encryption("Snapshot is encrypted, but no key given"));
+            }
+            (Some(_), false) => {
+                return Err(Error::encryption("Snapshot isn't encrypted"));
+            }
+            (None, false) => None,
+        };
+
+        let buf = if is_sealed {
+            Vec::new()
+        } else {
+            magic[..len].to_vec()
+        };
+
+        Ok(Opening {
+            inner,
+            cipher,
+            buf,
+            pos: 0,
+            finished: false,
+        })
+    }
+
+    fn read_frame(&mut self) -> Result<(), Error> {
+        let mut len_buf = [0; 4];
+        let len = read_full(&mut self.inner, &mut len_buf).map_err(Error::encryption)?;
+
+        if self.finished {
+            return if len == 0 {
+                Ok(())
+            } else {
+                Err(Error::encryption("Unexpected data after the last frame"))
+            };
+        }
+
+        if len != len_buf.len() {
+            return Err(Error::encryption("Stream is truncated"));
+        }
+
+        let len = u32::from_le_bytes(len_buf);
+        let last = len & LAST_FRAME != 0;
+        let len = (len & !LAST_FRAME) as usize;
+
+        if !(TAG_LEN..=FRAME_LEN + TAG_LEN).contains(&len) {
+            let err = format!("Unexpected frame length {}", len);
+            return Err(Error::encryption(err));
+        }
+
+        let mut sealed = vec![0; len];
+        self.inner
+            .read_exact(&mut sealed)
+            .map_err(|_| Error::encryption("Stream is truncated"))?;
+
+        let cipher = self.cipher.as_mut().expect("cipher must be");
+        self.buf = cipher.open(&sealed, last)?;
+        self.pos = 0;
+        self.finished = last;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Opening<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.pos < self.buf.len() {
+            let len = buf.len().min(self.buf.len() - self.pos);
+            buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+            self.pos += len;
+            return Ok(len);
+        }
+
+        if self.cipher.is_none() {
+            return self.inner.read(buf);
+        }
+
+        if self.finished {
+            self.read_frame().map_err(into_io_error)?;
+            return Ok(0);
+        }
+
+        // frames may be empty, the last one is usually
+        self.read_frame().map_err(into_io_error)?;
+        self.read(buf)
+    }
+}
+
+// Snappy's reader treats `UnexpectedEof` as the end of stream, so never use it here.
+fn into_io_error(err: Error) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, err)
+}
+
+fn read_full<R: Read>(src: &mut R, buf: &mut [u8]) -> Result<usize, IoError> {
+    let mut read = 0;
+
+    while read < buf.len() {
+        match src.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref err) if err.kind() == IoErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ErrorKind;
+
+    fn key(n: u8) -> Key {
+        Key::from_hex(hex::encode([n; 32])).unwrap()
+    }
+
+    fn seal(src: &[u8], key: Option<&Key>) -> Vec<u8> {
+        let mut sealing = Sealing::new(Vec::new(), key).unwrap();
+        sealing.write_all(src).unwrap();
+        sealing.finish().unwrap()
+    }
+
+    fn open(src: &[u8], key: Option<&Key>) -> Result<Vec<u8>, Error> {
+        let mut opening = Opening::new(src, key)?;
+        let mut buf = Vec::new();
+        opening
+            .read_to_end(&mut buf)
+            .map_err(Error::snapshot_io("Read failed"))?;
+        Ok(buf)
+    }
+
+    fn assert_encryption_err(res: Result<Vec<u8>, Error>) {
+        match res {
+            Err(ref err) => match err.kind() {
+                ErrorKind::Encryption => {}
+                kind => panic!("expected encryption error, got {:?}", kind),
+            },
+            Ok(ok) => panic!("expected encryption error, got {:?}", ok),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let key = key(1);
+
+        for len in &[0, 1, FRAME_LEN - 1, FRAME_LEN, FRAME_LEN * 3 + 7] {
+            let src = (0..*len).map(|it| (it % 251) as u8).collect::<Vec<_>>();
+            let sealed = seal(&src, Some(&key));
+
+            assert_eq!(&sealed[..MAGIC_LEN], MAGIC);
+            assert_ne!(&sealed[MAGIC_LEN + PREFIX_LEN..], src.as_slice());
+            assert_eq!(open(&sealed, Some(&key)).unwrap(), src);
+        }
+    }
+
+    #[test]
+    fn pass_through_without_key() {
+        let src = b"plain bytes".to_vec();
+        let sealed = seal(&src, None);
+
+        assert_eq!(sealed, src);
+        assert_eq!(open(&sealed, None).unwrap(), src);
+    }
+
+    #[test]
+    fn reject_wrong_key() {
+        let sealed = seal(b"secret", Some(&key(1)));
+        assert_encryption_err(open(&sealed, Some(&key(2))));
+    }
+
+    #[test]
+    fn reject_tampered() {
+        let mut sealed = seal(b"secret", Some(&key(1)));
+        let idx = sealed.len() - 1;
+        sealed[idx] ^= 0x01;
+
+        assert_encryption_err(open(&sealed, Some(&key(1))));
+    }
+
+    #[test]
+    fn reject_truncated() {
+        let src = vec![7; FRAME_LEN * 2];
+        let sealed = seal(&src, Some(&key(1)));
+        let first_frame = MAGIC_LEN + PREFIX_LEN + 4 + FRAME_LEN + TAG_LEN;
+
+        assert_encryption_err(open(&sealed[..first_frame], Some(&key(1))));
+        assert_encryption_err(open(&sealed[..sealed.len() - 1], Some(&key(1))));
+    }
+
+    #[test]
+    fn reject_mismatched_mode() {
+        let sealed = seal(b"secret", Some(&key(1)));
+        assert_encryption_err(open(&sealed, None));
+
+        let plain = seal(b"plain", None);
+        assert_encryption_err(open(&plain, Some(&key(1))));
+    }
+}
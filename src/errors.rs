@@ -1,5 +1,6 @@
 use std::error::Error as StdError;
 use std::fmt::{self, Display};
+use std::io::Error as IoError;
 use std::path::{Path, PathBuf};
 
 type Cause = Box<dyn StdError + Send + Sync + 'static>;
@@ -10,6 +11,12 @@ pub enum ErrorKind {
     Snapshot(String),
     UnrecognizedService,
     Storage,
+    Encryption,
+    Signature,
+    /// An invalid project file and the line of the problem.
+    Config(PathBuf, usize),
+    /// An invalid argument, e.g. a cache name.
+    Usage,
 }
 
 #[derive(Debug)]
@@ -19,6 +26,10 @@ pub struct Error {
 }
 
 impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
     pub fn storage<E>(err: E) -> Error
     where
         E: Into<Cause>,
@@ -39,6 +50,47 @@ impl Error {
         }
     }
 
+    pub fn encryption<E>(err: E) -> Error
+    where
+        E: Into<Cause>,
+    {
+        Error {
+            kind: ErrorKind::Encryption,
+            cause: Some(err.into()),
+        }
+    }
+
+    pub fn signature<E>(err: E) -> Error
+    where
+        E: Into<Cause>,
+    {
+        Error {
+            kind: ErrorKind::Signature,
+            cause: Some(err.into()),
+        }
+    }
+
+    pub fn usage<E>(err: E) -> Error
+    where
+        E: Into<Cause>,
+    {
+        Error {
+            kind: ErrorKind::Usage,
+            cause: Some(err.into()),
+        }
+    }
+
+    pub fn config<T, E>(path: T, line: usize, err: E) -> Error
+    where
+        T: AsRef<Path>,
+        E: Into<Cause>,
+    {
+        Error {
+            kind: ErrorKind::Config(path.as_ref().to_path_buf(), line),
+            cause: Some(err.into()),
+        }
+    }
+
     pub fn io<T, E>(path: T) -> impl FnOnce(E) -> Error
     where
         T: AsRef<Path>,
@@ -62,6 +114,22 @@ impl Error {
         }
     }
 
+    /// Same as `snapshot`, but unwraps an `Error` that was passed through `io::Error`
+    /// by one of the stream adapters, so its kind isn't lost.
+    pub fn snapshot_io<T>(message: T) -> impl FnOnce(IoError) -> Error
+    where
+        T: Into<String>,
+    {
+        |err: IoError| {
+            if err.get_ref().map_or(false, |it| it.is::<Error>()) {
+                let inner = err.into_inner().unwrap();
+                *inner.downcast::<Error>().unwrap()
+            } else {
+                Error::snapshot(message)(err)
+            }
+        }
+    }
+
     pub fn io_err<T, R, E>(path: T, err: E) -> Result<R, Error>
     where
         T: AsRef<Path>,
@@ -92,6 +160,16 @@ impl Display for Error {
             ErrorKind::Snapshot(message) => write!(f, "{}; {}", self.description(), message)?,
             ErrorKind::UnrecognizedService => write!(f, "{}", self.description())?,
             ErrorKind::Storage => write!(f, "{}", self.description())?,
+            ErrorKind::Encryption => write!(f, "{}", self.description())?,
+            ErrorKind::Signature => write!(f, "{}", self.description())?,
+            ErrorKind::Usage => write!(f, "{}", self.description())?,
+            ErrorKind::Config(path, line) => write!(
+                f,
+                "{} at {:?}:{}",
+                self.description(),
+                path.as_os_str(),
+                line
+            )?,
         };
 
         let mut cause = self.source();
@@ -111,6 +189,10 @@ impl StdError for Error {
             ErrorKind::Snapshot(_) => "Snapshot error",
             ErrorKind::UnrecognizedService => "Unrecognized service",
             ErrorKind::Storage => "Storage error",
+            ErrorKind::Encryption => "Encryption error",
+            ErrorKind::Signature => "Signature error",
+            ErrorKind::Config(_, _) => "Config error",
+            ErrorKind::Usage => "Usage error",
         }
     }
 
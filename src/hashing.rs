@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
 use std::path::Path;
 
 use digest_md5::{Digest, Md5};
@@ -36,6 +36,36 @@ pub mod md5 {
         let hasher = md5::Md5::new();
         hash_bytes(src, hasher)
     }
+
+    /// Hashes everything written into it, for content that is only available as a stream.
+    #[derive(Default)]
+    pub struct Hashing {
+        hasher: Md5,
+        len: usize,
+    }
+
+    impl Hashing {
+        pub fn written(&self) -> usize {
+            self.len
+        }
+
+        pub fn finish(self) -> String {
+            Stats::current().hashing().inc(self.len);
+            hex::encode(&self.hasher.result())
+        }
+    }
+
+    impl Write for Hashing {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+            self.hasher.input(buf);
+            self.len += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), IoError> {
+            Ok(())
+        }
+    }
 }
 
 #[inline]
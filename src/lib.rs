@@ -3,12 +3,17 @@
 #![allow(unstable_name_collisions)]
 
 mod bytes;
+mod chunks;
 mod commands;
 mod config;
+mod crypto;
 mod errors;
 mod hashing;
+mod lock;
+mod messages;
 mod mmap;
 mod pretty;
+mod project;
 mod services;
 mod snapshot;
 mod stats;
@@ -17,9 +22,16 @@ mod storage;
 #[cfg(test)]
 mod testing;
 
-pub use self::commands::{Pull, Push};
+pub use self::commands::{
+    exec, verify, Diff, Exec, Export, Extract, Format, Gc, Import, Inspect, OnConflict, Pull, Push,
+    Reclaimed, Source, Target, Verify,
+};
 pub use self::config::Config;
+pub use self::crypto::{Key, SigningKey, TrustedKeys};
 pub use self::errors::{Error, ErrorKind};
+pub use self::lock::Lock;
+pub use self::messages::ServiceMessages;
+pub use self::project::{Cache, Project, Upload, DEFAULT_CACHE, PROJECT_FILE};
 pub use self::services::{Service, ServiceFactory};
 pub use self::stats::Stats;
-pub use self::storage::Storage;
+pub use self::storage::{Layout, Storage};
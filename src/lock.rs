@@ -0,0 +1,108 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use crate::errors::ResultExt;
+use crate::Error;
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An advisory lock of a working directory, released when dropped.
+///
+/// Commands changing the state take an exclusive lock, commands only reading it
+/// take a shared one, so builds sharing a working directory wait for each other.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+    path: PathBuf,
+}
+
+impl Lock {
+    pub fn exclusive<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Self, Error> {
+        Lock::acquire(path.as_ref(), libc::LOCK_EX, timeout)
+    }
+
+    pub fn shared<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Self, Error> {
+        Lock::acquire(path.as_ref(), libc::LOCK_SH, timeout)
+    }
+
+    fn acquire(path: &Path, operation: libc::c_int, timeout: Duration) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .io_err(path)?;
+
+        let started = Instant::now();
+        let mut waiting = false;
+
+        loop {
+            let res = unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) };
+            if res == 0 {
+                debug!("Locked {:?}", path);
+                return Ok(Lock {
+                    file,
+                    path: path.to_path_buf(),
+                });
+            }
+
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(Error::io(path)(err));
+            }
+
+            if started.elapsed() >= timeout {
+                let err = format!("Timed out after {:?} waiting for a lock", timeout);
+                return Error::io_err(path, err);
+            }
+
+            if !waiting {
+                info!("Waiting for another process holding {:?} ...", path);
+                waiting = true;
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+        debug!("Unlocked {:?}", self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing;
+
+    #[test]
+    fn lock_with_timeout() {
+        let work = testing::temp_dir();
+        let path = work.as_ref().join("lock");
+        let timeout = Duration::from_millis(200);
+
+        let first = Lock::shared(&path, timeout).unwrap();
+        let second = Lock::shared(&path, timeout).unwrap();
+
+        let err = Lock::exclusive(&path, timeout).unwrap_err();
+        assert!(err.to_string().contains("Timed out"), "{}", err);
+
+        drop(first);
+        drop(second);
+
+        let exclusive = Lock::exclusive(&path, timeout).unwrap();
+        assert!(Lock::shared(&path, timeout).is_err());
+
+        drop(exclusive);
+        assert!(Lock::shared(&path, timeout).is_ok());
+    }
+}
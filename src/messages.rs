@@ -0,0 +1,186 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+
+use crate::stats::Counter;
+use crate::{Error, Stats};
+
+const MICROS_IN_SEC: f64 = 1_000_000.0;
+const PROBLEM_IDENTITY: &str = "tc-cache";
+
+lazy_static! {
+    static ref MESSAGES: ServiceMessages = ServiceMessages::default();
+}
+
+/// TeamCity service messages, written to stdout once enabled for a build running on TeamCity.
+///
+/// Failures are reported as build problems in the strict mode only, otherwise the cache
+/// doesn't fail a build.
+#[derive(Debug, Default)]
+pub struct ServiceMessages {
+    enabled: AtomicBool,
+    strict: AtomicBool,
+}
+
+/// An opened block, closed when dropped.
+#[derive(Debug)]
+pub struct Block<'a> {
+    messages: &'a ServiceMessages,
+    name: String,
+}
+
+impl ServiceMessages {
+    #[inline]
+    pub fn current() -> &'static Self {
+        &MESSAGES
+    }
+
+    pub fn enable(&self, strict: bool) {
+        self.enabled.store(true, Ordering::SeqCst);
+        self.strict.store(strict, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    pub fn progress(&self, text: &str) {
+        self.emit(&message("progressMessage", &[("", text)]));
+    }
+
+    pub fn block(&self, name: &str) -> Block<'_> {
+        self.emit(&message("blockOpened", &[("name", name)]));
+        Block {
+            messages: self,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn statistic<V: Display>(&self, key: &str, value: V) {
+        let value = value.to_string();
+        let attrs = [("key", key), ("value", value.as_str())];
+        self.emit(&message("buildStatisticValue", &attrs));
+    }
+
+    /// Reports a failure as a build problem, in the strict mode only.
+    pub fn problem(&self, err: &Error) {
+        if !self.strict.load(Ordering::Acquire) {
+            return;
+        }
+
+        let description = err.to_string();
+        let attrs = [
+            ("description", description.as_str()),
+            ("identity", PROBLEM_IDENTITY),
+        ];
+        self.emit(&message("buildProblem", &attrs));
+    }
+
+    /// Transfers, hits and misses of pulls and the size of pushed snapshots.
+    pub fn stats(&self, stats: &Stats) {
+        let transfers = [
+            ("tc.cache.download", stats.download()),
+            ("tc.cache.upload", stats.upload()),
+        ];
+        for (key, counter) in &transfers {
+            if !counter.is_empty() {
+                self.statistic(&format!("{}.bytes", key), counter.counter());
+                self.statistic(&format!("{}.secs", key), secs(counter));
+            }
+        }
+
+        if !stats.hits().is_empty() || !stats.misses().is_empty() {
+            self.statistic("tc.cache.hits", stats.hits().counter());
+            self.statistic("tc.cache.misses", stats.misses().counter());
+        }
+
+        if !stats.snapshot().is_empty() {
+            self.statistic("tc.cache.snapshot.bytes", stats.snapshot().counter());
+        }
+    }
+
+    fn emit(&self, message: &str) {
+        if self.is_enabled() {
+            println!("{}", message);
+        }
+    }
+}
+
+impl<'a> Drop for Block<'a> {
+    fn drop(&mut self) {
+        let msg = message("blockClosed", &[("name", self.name.as_str())]);
+        self.messages.emit(&msg);
+    }
+}
+
+#[inline]
+fn secs(counter: &Counter) -> String {
+    format!("{:.3}", counter.micros() as f64 / MICROS_IN_SEC)
+}
+
+/// Formats a message, an attribute without a name is the single value one.
+fn message(name: &str, attrs: &[(&str, &str)]) -> String {
+    let mut msg = format!("##teamcity[{}", name);
+    for (key, value) in attrs {
+        if key.is_empty() {
+            msg.push_str(&format!(" '{}'", escape(value)));
+        } else {
+            msg.push_str(&format!(" {}='{}'", key, escape(value)));
+        }
+    }
+    msg.push(']');
+    msg
+}
+
+fn escape(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '|' => res.push_str("||"),
+            '\'' => res.push_str("|'"),
+            '\n' => res.push_str("|n"),
+            '\r' => res.push_str("|r"),
+            '[' => res.push_str("|["),
+            ']' => res.push_str("|]"),
+            '\u{0085}' => res.push_str("|x"),
+            '\u{2028}' => res.push_str("|l"),
+            '\u{2029}' => res.push_str("|p"),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_messages() {
+        assert_eq!(
+            message("progressMessage", &[("", "Pulling cache ...")]),
+            "##teamcity[progressMessage 'Pulling cache ...']"
+        );
+        assert_eq!(
+            message(
+                "buildStatisticValue",
+                &[("key", "tc.cache.hits"), ("value", "1")]
+            ),
+            "##teamcity[buildStatisticValue key='tc.cache.hits' value='1']"
+        );
+        assert_eq!(
+            message("buildProblem", &[("description", "Can't read 'a|b' [1]\n")]),
+            "##teamcity[buildProblem description='Can|'t read |'a||b|' |[1|]|n']"
+        );
+    }
+
+    #[test]
+    fn transfer_secs() {
+        let counter = Counter::default();
+        counter.elapsed(&std::time::Duration::from_millis(1500));
+
+        assert_eq!(secs(&counter), "1.500");
+    }
+}
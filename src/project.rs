@@ -0,0 +1,619 @@
+//! A checked-in `.tc-cache.toml` declaring named caches, e.g.
+//!
+//! ```toml
+//! [caches.default]
+//! dirs = ["~/.gradle/caches", "build/deps"]
+//! excludes = ["**/*.lock"]
+//! key_files = ["build.gradle", "gradle/wrapper/gradle-wrapper.properties"]
+//! compression = "snappy"
+//! layout = "chunks"
+//! remote_url = "s3://bucket/cache"
+//! upload = "default-branch"
+//! branches = true
+//! ```
+//!
+//! Relative paths are relative to the project file's directory, `~/` ones to `HOME`.
+//!
+//! Settings are merged with the following precedence, the first one wins:
+//! command line options, `TC_CACHE_*` environment variables, the project file
+//! and finally what the CI service (e.g. TeamCity build properties) reports.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use glob::Pattern;
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde_derive::Deserialize;
+use toml::{Spanned, Value};
+
+use crate::errors::ResultExt;
+use crate::hashing::md5::Hashing;
+use crate::snapshot::Compression;
+use crate::storage::Layout;
+use crate::{Error, Service};
+
+pub const PROJECT_FILE: &str = ".tc-cache.toml";
+pub const DEFAULT_CACHE: &str = "default";
+/// Caches of feature branches are kept under `<project>/branches/<branch>`.
+pub const BRANCHES_KEY: &str = "branches";
+
+const REMOTE_URL: &str = "TC_CACHE_REMOTE_URL";
+const UPLOAD: &str = "TC_CACHE_UPLOAD";
+const BRANCHES: &str = "TC_CACHE_BRANCHES";
+
+type EnvMap = HashMap<String, String>;
+
+/// When a cache is pushed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Upload {
+    Always,
+    /// Only from the default branch, as the CI service reports it.
+    DefaultBranch,
+    Never,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Cache {
+    pub name: String,
+    pub dirs: Vec<PathBuf>,
+    /// Glob patterns of absolute paths that aren't pushed, e.g. `**/build/tmp`.
+    pub excludes: Vec<String>,
+    /// Files which content is a part of the storage key.
+    pub key_files: Vec<PathBuf>,
+    /// How snapshots are compressed, `none` for already compressed content.
+    pub compression: Option<Compression>,
+    pub layout: Option<Layout>,
+    pub remote_url: Option<String>,
+    pub upload: Option<Upload>,
+    /// Feature branches push to their own keys, pulling the default branch's cache until then.
+    pub branches: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Project {
+    pub path: Option<PathBuf>,
+    pub caches: Vec<Cache>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectFile {
+    #[serde(default)]
+    caches: BTreeMap<String, Spanned<Table>>,
+}
+
+/// Keys and values of a cache's table in the file's order, a map would drop duplicate keys.
+#[derive(Debug)]
+struct Table(Vec<(String, Spanned<Value>)>);
+
+impl Upload {
+    pub fn variants() -> &'static [&'static str] {
+        &["always", "default-branch", "never"]
+    }
+}
+
+impl FromStr for Upload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" | "true" => Ok(Upload::Always),
+            "default-branch" => Ok(Upload::DefaultBranch),
+            "never" | "false" => Ok(Upload::Never),
+            _ => Err(format!(
+                "Unknown upload policy '{}', expected one of {:?}",
+                s,
+                Upload::variants()
+            )),
+        }
+    }
+}
+
+impl Display for Upload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let value = match self {
+            Upload::Always => "always",
+            Upload::DefaultBranch => "default-branch",
+            Upload::Never => "never",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+impl Cache {
+    /// `TC_CACHE_REMOTE_URL`, then the file's `remote_url`, then the service's one.
+    pub fn remote_url<'a>(&'a self, env: &'a EnvMap, service: &'a dyn Service) -> &'a str {
+        env.get(REMOTE_URL)
+            .or(self.remote_url.as_ref())
+            .map(String::as_str)
+            .unwrap_or_else(|| service.remote_url())
+    }
+
+    /// `TC_CACHE_UPLOAD`, then a feature branch with a cache of its own uploads unless
+    /// the `upload` policy is `never`, otherwise the policy, then the service's branch check.
+    /// The policy is the file's `upload`, then the one set in the service's build settings.
+    pub fn is_uploadable(&self, env: &EnvMap, service: &dyn Service) -> bool {
+        if let Some(val) = env.get(UPLOAD) {
+            return val == "1" || val == "true";
+        }
+
+        let upload = self.upload.or_else(|| service.upload());
+
+        if self.branch_key(env, service).is_some() {
+            return upload != Some(Upload::Never);
+        }
+
+        match upload {
+            Some(Upload::Always) => true,
+            Some(Upload::Never) => false,
+            Some(Upload::DefaultBranch) | None => service.is_uploadable(),
+        }
+    }
+
+    /// A key of the feature branch's own cache, when `TC_CACHE_BRANCHES` or the file's
+    /// `branches` enables them, e.g. `branches/feature-foo` for `feature/foo`.
+    pub fn branch_key(&self, env: &EnvMap, service: &dyn Service) -> Option<String> {
+        let enabled = match env.get(BRANCHES) {
+            Some(val) => val == "1" || val == "true",
+            None => self.branches,
+        };
+        if !enabled {
+            return None;
+        }
+
+        // a branch is a single key segment, a nested one would look like another cache
+        let branch = service
+            .feature_branch()?
+            .chars()
+            .map(|it| match it {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => it,
+                _ => '-',
+            })
+            .collect::<String>();
+
+        Some(format!("{}/{}", BRANCHES_KEY, branch))
+    }
+
+    /// A digest of the key files' content, `None` when there are no key files.
+    pub fn key(&self) -> Result<Option<String>, Error> {
+        if self.key_files.is_empty() {
+            return Ok(None);
+        }
+
+        let mut hashing = Hashing::default();
+        for path in &self.key_files {
+            let mut file = File::open(path).io_err(path)?;
+            io::copy(&mut file, &mut hashing).io_err(path)?;
+        }
+
+        Ok(Some(hashing.finish()))
+    }
+}
+
+impl Project {
+    /// Loads a project file, an empty project when the file doesn't exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        if !path.as_ref().exists() {
+            return Ok(Project::default());
+        }
+        Project::from_path(path)
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut it| it.read_to_string(&mut content))
+            .io_err(path)?;
+
+        Project::from_content(path, &content)
+    }
+
+    /// Parses a project file, relative paths in it are relative to the file's directory.
+    pub fn from_content<P: AsRef<Path>>(path: P, content: &str) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let err = |offset, msg: String| Error::config(path, line(content, offset), msg);
+
+        let file: ProjectFile = toml::from_str(content).map_err(|err| {
+            let line = err.line_col().map_or(0, |(line, _)| line + 1);
+            Error::config(path, line, err.to_string())
+        })?;
+
+        let mut tables = file.caches.into_iter().collect::<Vec<_>>();
+        tables.sort_by_key(|(_, table)| table.start());
+
+        let mut caches = Vec::with_capacity(tables.len());
+        for (name, table) in tables {
+            let offset = table.start();
+            let mut cache = Cache {
+                name,
+                ..Cache::default()
+            };
+
+            let mut seen_keys = Vec::new();
+            for (key, value) in table.into_inner().0 {
+                let at = value.start();
+                if seen_keys.contains(&key) {
+                    return Err(err(at, format!("Duplicate key '{}'", key)));
+                }
+
+                set(&mut cache, &key, value.into_inner(), base_dir).map_err(|msg| err(at, msg))?;
+                seen_keys.push(key);
+            }
+
+            if cache.dirs.is_empty() {
+                let msg = format!("Cache '{}' has no dirs", cache.name);
+                return Err(err(offset, msg));
+            }
+            caches.push(cache);
+        }
+
+        Ok(Project {
+            path: Some(path.to_path_buf()),
+            caches,
+        })
+    }
+
+    pub fn cache(&self, name: &str) -> Option<&Cache> {
+        self.caches.iter().find(|it| it.name == name)
+    }
+}
+
+impl<'de> Deserialize<'de> for Table {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TableVisitor;
+
+        impl<'de> Visitor<'de> for TableVisitor {
+            type Value = Table;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a table")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Table, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(Table(entries))
+            }
+        }
+
+        deserializer.deserialize_map(TableVisitor)
+    }
+}
+
+fn set(cache: &mut Cache, key: &str, value: Value, base_dir: &Path) -> Result<(), String> {
+    let paths = |value: Value| -> Result<Vec<PathBuf>, String> {
+        let paths = strings(key, value)?;
+        Ok(paths.iter().map(|it| resolve(base_dir, it)).collect())
+    };
+
+    match key {
+        "dirs" => cache.dirs = paths(value)?,
+        "key_files" => cache.key_files = paths(value)?,
+        "excludes" => {
+            let excludes = strings(key, value)?;
+            for it in &excludes {
+                Pattern::new(it).map_err(|err| format!("Invalid pattern '{}'; {}", it, err))?;
+            }
+            cache.excludes = excludes;
+        }
+        "compression" => {
+            let compression = string(key, value)?;
+            cache.compression = Some(compression.parse().map_err(|err: Error| err.to_string())?);
+        }
+        "layout" => {
+            let layout = string(key, value)?;
+            cache.layout = Some(layout.parse().map_err(|err: Error| err.to_string())?);
+        }
+        "remote_url" => cache.remote_url = Some(string(key, value)?),
+        "branches" => match value {
+            Value::Boolean(val) => cache.branches = val,
+            other => return Err(mismatch(key, "a boolean", &other)),
+        },
+        "upload" => {
+            let upload = match value {
+                Value::Boolean(true) => Upload::Always,
+                Value::Boolean(false) => Upload::Never,
+                other => string(key, other)?.parse()?,
+            };
+            cache.upload = Some(upload);
+        }
+        _ => return Err(format!("Unknown key '{}'", key)),
+    }
+
+    Ok(())
+}
+
+fn string(key: &str, value: Value) -> Result<String, String> {
+    match value {
+        Value::String(val) => Ok(val),
+        other => Err(mismatch(key, "a string", &other)),
+    }
+}
+
+fn strings(key: &str, value: Value) -> Result<Vec<String>, String> {
+    match value {
+        Value::Array(values) => values.into_iter().map(|it| string(key, it)).collect(),
+        other => Err(mismatch(key, "an array of strings", &other)),
+    }
+}
+
+#[inline]
+fn mismatch(key: &str, expected: &str, value: &Value) -> String {
+    format!("'{}' must be {}, not {}", key, expected, value.type_str())
+}
+
+/// A path relative to the file's directory, or to `HOME` when it starts with `~/`.
+fn resolve(base_dir: &Path, path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(path) => {
+            let home = env::var("HOME").unwrap_or_else(|_| ".".into());
+            Path::new(home.as_str()).join(path)
+        }
+        None => base_dir.join(path),
+    }
+}
+
+/// A 1-based line of a byte offset.
+#[inline]
+fn line(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{PROJECT_FILE_PATH, TEAMCITY_BUILD_PROPS_PATH};
+
+    struct Fixed(bool, Option<&'static str>);
+
+    impl Display for Fixed {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+            write!(f, "Fixed")
+        }
+    }
+
+    impl Service for Fixed {
+        fn project_id(&self) -> &str {
+            "project"
+        }
+
+        fn is_uploadable(&self) -> bool {
+            self.0
+        }
+
+        fn remote_url(&self) -> &str {
+            "file:///service"
+        }
+
+        fn feature_branch(&self) -> Option<&str> {
+            self.1
+        }
+
+        fn into_box(self) -> Box<dyn Service> {
+            Box::new(self)
+        }
+    }
+
+    fn parse_err(content: &str) -> String {
+        match Project::from_content(PROJECT_FILE, content) {
+            Ok(ok) => unreachable!("{:?}", ok),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_project_file() {
+        let project = Project::from_path(PROJECT_FILE_PATH).unwrap();
+        let base_dir = Path::new(PROJECT_FILE_PATH).parent().unwrap();
+
+        assert_eq!(project.caches.len(), 2);
+
+        let cache = project.cache(DEFAULT_CACHE).unwrap();
+        assert_eq!(
+            cache.dirs,
+            vec![base_dir.join("snapshot"), base_dir.join("build # deps")]
+        );
+        assert_eq!(cache.excludes, vec!["**/*.lock", "**/tmp"]);
+        assert_eq!(cache.key_files, vec![base_dir.join("snapshot/a.txt")]);
+        assert_eq!(cache.compression, Some(Compression::None));
+        assert_eq!(cache.layout, Some(Layout::Chunks));
+        assert_eq!(cache.remote_url.as_ref().unwrap(), "file:///remote");
+        assert_eq!(cache.upload, Some(Upload::DefaultBranch));
+        assert_eq!(cache.branches, true);
+
+        let home = env::var("HOME").unwrap();
+        let cache = project.cache("tools").unwrap();
+        assert_eq!(
+            cache.dirs,
+            vec![base_dir.join("tools"), Path::new(&home).join(".tools")]
+        );
+        assert_eq!(cache.upload, Some(Upload::Never));
+        assert_eq!(cache.layout, None);
+        assert_eq!(cache.branches, false);
+    }
+
+    #[test]
+    fn report_line_numbers() {
+        let params = vec![
+            ("dirs = ['a']", "unknown field `dirs`", 1),
+            (
+                "[caches.a]\ndirs = ['a']\n[other]",
+                "unknown field `other`",
+                3,
+            ),
+            (
+                "[caches.a]\n\ndirs = 'a'",
+                "'dirs' must be an array of strings",
+                3,
+            ),
+            (
+                "[caches.a]\ndirs = [\n'a',\n1]",
+                "'dirs' must be a string",
+                2,
+            ),
+            ("[caches.a]\ndirs = ['a']\nfoo = 1", "Unknown key 'foo'", 3),
+            ("[caches.a]\ndirs = ['a'\n", "expected a right bracket", 3),
+            ("[caches.a]\ndirs = ['a]", "unterminated string", 2),
+            ("[caches.a]\n# comment\n\n", "Cache 'a' has no dirs", 1),
+            ("[caches.a]\ndirs = ['a'] b", "expected newline", 2),
+            (
+                "[caches.a]\ndirs = ['a']\nupload = 'sometimes'",
+                "Unknown upload",
+                3,
+            ),
+            (
+                "[caches.a]\ndirs = ['a']\nbranches = 'yes'",
+                "'branches' must be a boolean",
+                3,
+            ),
+            (
+                "[caches.a]\ndirs = ['a']\ncompression = 'lz4'",
+                "Unsupported compression 'lz4'",
+                3,
+            ),
+            (
+                "[caches.a]\ndirs = ['a']\n[caches.a]",
+                "redefinition of table `caches.a`",
+                3,
+            ),
+            (
+                "[caches.a]\ndirs = ['a']\ndirs = []",
+                "Duplicate key 'dirs'",
+                3,
+            ),
+        ];
+
+        for (content, message, line) in params {
+            let err = parse_err(content);
+            let at = format!("Config error at {:?}:{};", PROJECT_FILE, line);
+
+            assert!(err.starts_with(&at), "{} doesn't start with {}", err, at);
+            assert!(err.contains(message), "{} doesn't contain {}", err, message);
+        }
+    }
+
+    #[test]
+    fn merge_with_env_and_service() {
+        let mut env = HashMap::new();
+        let service = Fixed(true, None);
+
+        let mut cache = Cache::default();
+        assert_eq!(cache.remote_url(&env, &service), "file:///service");
+        assert_eq!(cache.is_uploadable(&env, &service), true);
+
+        cache.remote_url = Some("file:///project".into());
+        cache.upload = Some(Upload::Never);
+        assert_eq!(cache.remote_url(&env, &service), "file:///project");
+        assert_eq!(cache.is_uploadable(&env, &service), false);
+
+        cache.upload = Some(Upload::DefaultBranch);
+        assert_eq!(cache.is_uploadable(&env, &Fixed(false, None)), false);
+
+        env.insert(REMOTE_URL.into(), "file:///env".into());
+        env.insert(UPLOAD.into(), "true".into());
+        assert_eq!(cache.remote_url(&env, &service), "file:///env");
+        assert_eq!(cache.is_uploadable(&env, &Fixed(false, None)), true);
+    }
+
+    #[test]
+    fn upload_policy_of_service() {
+        struct Policy(Upload);
+
+        impl Display for Policy {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+                write!(f, "Policy")
+            }
+        }
+
+        impl Service for Policy {
+            fn project_id(&self) -> &str {
+                "project"
+            }
+
+            fn is_uploadable(&self) -> bool {
+                false
+            }
+
+            fn remote_url(&self) -> &str {
+                "file:///service"
+            }
+
+            fn feature_branch(&self) -> Option<&str> {
+                None
+            }
+
+            fn into_box(self) -> Box<dyn Service> {
+                Box::new(self)
+            }
+
+            fn upload(&self) -> Option<Upload> {
+                Some(self.0)
+            }
+        }
+
+        let env = HashMap::new();
+        let mut cache = Cache::default();
+        assert_eq!(cache.is_uploadable(&env, &Policy(Upload::Always)), true);
+        assert_eq!(
+            cache.is_uploadable(&env, &Policy(Upload::DefaultBranch)),
+            false
+        );
+
+        cache.upload = Some(Upload::Never);
+        assert_eq!(cache.is_uploadable(&env, &Policy(Upload::Always)), false);
+    }
+
+    #[test]
+    fn feature_branch_keys() {
+        let mut env = HashMap::new();
+        let feature = Fixed(false, Some("feature/foo"));
+
+        let mut cache = Cache::default();
+        assert_eq!(cache.branch_key(&env, &feature), None);
+        assert_eq!(cache.is_uploadable(&env, &feature), false);
+
+        cache.branches = true;
+        assert_eq!(
+            cache.branch_key(&env, &feature).unwrap(),
+            "branches/feature-foo"
+        );
+        assert_eq!(cache.is_uploadable(&env, &feature), true);
+        assert_eq!(cache.branch_key(&env, &Fixed(true, None)), None);
+
+        cache.upload = Some(Upload::Never);
+        assert_eq!(cache.is_uploadable(&env, &feature), false);
+
+        env.insert(UPLOAD.into(), "1".into());
+        assert_eq!(cache.is_uploadable(&env, &feature), true);
+
+        cache.upload = None;
+        env.insert(UPLOAD.into(), "0".into());
+        assert_eq!(cache.is_uploadable(&env, &feature), false);
+
+        env.insert(BRANCHES.into(), "0".into());
+        assert_eq!(cache.branch_key(&env, &feature), None);
+    }
+
+    #[test]
+    fn key_of_key_files() {
+        let mut cache = Cache::default();
+        assert_eq!(cache.key().unwrap(), None);
+
+        cache.key_files = vec![PathBuf::from(TEAMCITY_BUILD_PROPS_PATH)];
+        let key = cache.key().unwrap().unwrap();
+        assert_eq!(key.len(), 32);
+
+        cache.key_files.push(PathBuf::from("not_exists"));
+        assert!(cache.key().is_err());
+    }
+}
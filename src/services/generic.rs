@@ -1,55 +1,47 @@
-use std::collections::HashMap;
 use std::fmt::{self, Display};
 
 const PROJECT_ID: &str = "TC_CACHE_PROJECT_ID";
 const UPLOAD: &str = "TC_CACHE_UPLOAD";
-const REMOTE_URL: &str = "TC_CACHE_REMOTE_URL";
+const BRANCH: &str = "TC_CACHE_BRANCH";
 
-use crate::services::Service;
+use crate::services::{required_var, EnvMap, Service, DEFAULT_BRANCH, REMOTE_URL};
 use crate::Error;
 
+/// Configured by `TC_CACHE_*` environment variables, for any CI.
+///
+/// A build is on a feature branch when `TC_CACHE_BRANCH` differs from
+/// `TC_CACHE_DEFAULT_BRANCH`, or when it doesn't upload if the latter isn't set.
 #[derive(Debug)]
 pub struct Generic {
     project_id: String,
     upload: bool,
     remote_url: String,
+    feature_branch: Option<String>,
 }
 
 impl Generic {
     #[inline]
-    pub fn is_available(env: &HashMap<String, String>) -> bool {
+    pub fn is_available(env: &EnvMap) -> bool {
         env.contains_key(PROJECT_ID) && env.contains_key(UPLOAD) && env.contains_key(REMOTE_URL)
     }
 
-    pub fn from_env(env: &HashMap<String, String>) -> Result<Self, Error> {
-        let project_id = match env.get(PROJECT_ID) {
-            Some(ok) => ok.to_string(),
-            None => {
-                let err = format!("Environment variable '{}' was not found", PROJECT_ID);
-                return Err(Error::unrecognized_service(err));
-            }
-        };
-
-        let upload = match env.get(UPLOAD) {
-            Some(val) => val == "1" || val == "true",
-            None => {
-                let err = format!("Environment variable '{}' was not found", UPLOAD);
-                return Err(Error::unrecognized_service(err));
-            }
-        };
+    pub fn from_env(env: &EnvMap) -> Result<Self, Error> {
+        let project_id = required_var(env, PROJECT_ID)?;
+        let upload = required_var(env, UPLOAD)?;
+        let upload = upload == "1" || upload == "true";
+        let remote_url = required_var(env, REMOTE_URL)?;
 
-        let remote_url = match env.get(REMOTE_URL) {
-            Some(ok) => ok.to_string(),
-            None => {
-                let err = format!("Environment variable '{}' was not found", REMOTE_URL);
-                return Err(Error::unrecognized_service(err));
-            }
+        let feature_branch = match (env.get(BRANCH), env.get(DEFAULT_BRANCH)) {
+            (Some(branch), Some(default)) if branch != default => Some(branch.to_string()),
+            (Some(branch), None) if !upload => Some(branch.to_string()),
+            _ => None,
         };
 
         Ok(Generic {
             project_id,
             upload,
             remote_url,
+            feature_branch,
         })
     }
 }
@@ -80,6 +72,11 @@ impl Service for Generic {
         self.remote_url.as_str()
     }
 
+    #[inline]
+    fn feature_branch(&self) -> Option<&str> {
+        self.feature_branch.as_deref()
+    }
+
     #[inline]
     fn into_box(self) -> Box<dyn Service> {
         Box::new(self)
@@ -90,6 +87,8 @@ impl Service for Generic {
 mod tests {
     use super::*;
 
+    use std::collections::HashMap;
+
     #[test]
     fn from_env() {
         let mut env = HashMap::new();
@@ -97,7 +96,7 @@ mod tests {
         assert_eq!(Generic::is_available(&env), false);
 
         match Generic::from_env(&env) {
-            Err(err) => assert!(err.to_string().contains("was not found")),
+            Err(err) => assert!(err.to_string().contains("wasn't found")),
             Ok(ok) => unreachable!("{:?}", ok),
         }
 
@@ -116,5 +115,25 @@ mod tests {
             generic.to_string(),
             "Env(project=projectId, upload=true, remote_url=http://example.com)"
         );
+        assert_eq!(generic.feature_branch(), None);
+    }
+
+    #[test]
+    fn feature_branch() {
+        let mut env = HashMap::new();
+        env.insert(PROJECT_ID.into(), "projectId".into());
+        env.insert(UPLOAD.into(), "0".into());
+        env.insert(REMOTE_URL.into(), "http://example.com".into());
+        env.insert(BRANCH.into(), "feature".into());
+
+        let feature_branch = |env: &HashMap<_, _>| Generic::from_env(env).unwrap().feature_branch;
+        assert_eq!(feature_branch(&env).unwrap(), "feature");
+
+        env.insert(DEFAULT_BRANCH.into(), "feature".into());
+        assert_eq!(feature_branch(&env), None);
+
+        env.insert(DEFAULT_BRANCH.into(), "master".into());
+        env.insert(UPLOAD.into(), "1".into());
+        assert_eq!(feature_branch(&env).unwrap(), "feature");
     }
 }
@@ -0,0 +1,204 @@
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::errors::ResultExt;
+use crate::services::{required_var, EnvMap, Service, REMOTE_URL};
+use crate::Error;
+
+const GITHUB_ACTIONS: &str = "GITHUB_ACTIONS";
+const GITHUB_REPOSITORY: &str = "GITHUB_REPOSITORY";
+const GITHUB_REF: &str = "GITHUB_REF";
+const GITHUB_HEAD_REF: &str = "GITHUB_HEAD_REF";
+const GITHUB_EVENT_PATH: &str = "GITHUB_EVENT_PATH";
+const GITHUB_WORKFLOW: &str = "GITHUB_WORKFLOW";
+// inputs of an action are passed to it as `INPUT_<NAME>` variables
+const REMOTE_URL_INPUT: &str = "INPUT_TC_CACHE_REMOTE_URL";
+const BRANCH_REF_PREFIX: &str = "refs/heads/";
+
+#[derive(Debug)]
+pub struct GithubActions {
+    project_id: String,
+    workflow: Option<String>,
+    git_ref: String,
+    branch: Option<String>,
+    is_default_branch: bool,
+    remote_url: String,
+}
+
+impl GithubActions {
+    #[inline]
+    pub fn is_available(env: &EnvMap) -> bool {
+        env.get(GITHUB_ACTIONS).map(String::as_str) == Some("true")
+    }
+
+    pub fn from_env(env: &EnvMap) -> Result<Self, Error> {
+        let project_id = required_var(env, GITHUB_REPOSITORY)?;
+        let git_ref = required_var(env, GITHUB_REF)?;
+        let remote_url =
+            required_var(env, REMOTE_URL).or_else(|_| required_var(env, REMOTE_URL_INPUT))?;
+
+        let default_branch = match env.get(GITHUB_EVENT_PATH) {
+            Some(path) => default_branch(path)?,
+            None => None,
+        };
+
+        // a pull request is built from a merge ref, its branch is the head one
+        let branch = match env.get(GITHUB_HEAD_REF) {
+            Some(head_ref) if !head_ref.is_empty() => Some(head_ref.to_string()),
+            _ if git_ref.starts_with(BRANCH_REF_PREFIX) => {
+                Some(git_ref[BRANCH_REF_PREFIX.len()..].to_string())
+            }
+            _ => None,
+        };
+
+        let is_default_branch = match default_branch {
+            Some(default) => git_ref == format!("{}{}", BRANCH_REF_PREFIX, default),
+            None => false,
+        };
+
+        Ok(GithubActions {
+            project_id,
+            workflow: env.get(GITHUB_WORKFLOW).cloned(),
+            git_ref,
+            branch,
+            is_default_branch,
+            remote_url,
+        })
+    }
+}
+
+/// The repository's default branch from the payload of the event that triggered the workflow.
+fn default_branch<P: AsRef<Path>>(path: P) -> Result<Option<String>, Error> {
+    let file = File::open(&path).io_err(&path)?;
+    let event: Value = serde_json::from_reader(&file).io_err(&path)?;
+
+    Ok(event
+        .pointer("/repository/default_branch")
+        .and_then(Value::as_str)
+        .map(str::to_string))
+}
+
+impl Display for GithubActions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match &self.workflow {
+            Some(workflow) => write!(
+                f,
+                "GitHub Actions {} '{}' at {}",
+                self.project_id, workflow, self.git_ref
+            ),
+            None => write!(f, "GitHub Actions {} at {}", self.project_id, self.git_ref),
+        }
+    }
+}
+
+impl Service for GithubActions {
+    #[inline]
+    fn project_id(&self) -> &str {
+        self.project_id.as_str()
+    }
+
+    #[inline]
+    fn is_uploadable(&self) -> bool {
+        self.is_default_branch
+    }
+
+    #[inline]
+    fn remote_url(&self) -> &str {
+        self.remote_url.as_str()
+    }
+
+    #[inline]
+    fn feature_branch(&self) -> Option<&str> {
+        if self.is_default_branch {
+            None
+        } else {
+            self.branch.as_deref()
+        }
+    }
+
+    #[inline]
+    fn into_box(self) -> Box<dyn Service> {
+        Box::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::testing::{GITHUB_PULL_REQUEST_EVENT_PATH, GITHUB_PUSH_EVENT_PATH};
+
+    fn env(git_ref: &str, event_path: &str) -> EnvMap {
+        let mut env = HashMap::new();
+        env.insert(GITHUB_ACTIONS.into(), "true".into());
+        env.insert(GITHUB_REPOSITORY.into(), "example/example".into());
+        env.insert(GITHUB_REF.into(), git_ref.into());
+        env.insert(GITHUB_EVENT_PATH.into(), event_path.into());
+        env.insert(REMOTE_URL.into(), "s3://bucket/cache".into());
+        env
+    }
+
+    #[test]
+    fn from_env() {
+        let mut env = env("refs/heads/master", GITHUB_PUSH_EVENT_PATH);
+        env.insert(GITHUB_WORKFLOW.into(), "build".into());
+
+        assert_eq!(GithubActions::is_available(&env), true);
+
+        let github = GithubActions::from_env(&env).unwrap();
+        assert_eq!(
+            github.to_string(),
+            "GitHub Actions example/example 'build' at refs/heads/master"
+        );
+        assert_eq!(github.project_id(), "example/example");
+        assert_eq!(github.is_uploadable(), true);
+        assert_eq!(github.feature_branch(), None);
+        assert_eq!(github.remote_url(), "s3://bucket/cache");
+    }
+
+    #[test]
+    fn from_env_on_feature_branch() {
+        let env = env("refs/heads/feature/foo", GITHUB_PUSH_EVENT_PATH);
+        let github = GithubActions::from_env(&env).unwrap();
+
+        assert_eq!(github.is_uploadable(), false);
+        assert_eq!(github.feature_branch(), Some("feature/foo"));
+    }
+
+    #[test]
+    fn from_env_on_pull_request() {
+        let mut env = env("refs/pull/7/merge", GITHUB_PULL_REQUEST_EVENT_PATH);
+        env.insert(GITHUB_HEAD_REF.into(), "feature/foo".into());
+        env.remove(REMOTE_URL);
+        env.insert(REMOTE_URL_INPUT.into(), "s3://bucket/input".into());
+
+        let github = GithubActions::from_env(&env).unwrap();
+
+        assert_eq!(github.is_uploadable(), false);
+        assert_eq!(github.feature_branch(), Some("feature/foo"));
+        assert_eq!(github.remote_url(), "s3://bucket/input");
+    }
+
+    #[test]
+    fn from_env_without_required() {
+        let mut env = env("refs/tags/v1.0", GITHUB_PUSH_EVENT_PATH);
+
+        let github = GithubActions::from_env(&env).unwrap();
+        assert_eq!(github.is_uploadable(), false);
+        assert_eq!(github.feature_branch(), None);
+
+        env.remove(GITHUB_REPOSITORY);
+        match GithubActions::from_env(&env) {
+            Err(err) => assert!(err.to_string().contains(GITHUB_REPOSITORY)),
+            Ok(ok) => unreachable!("{:?}", ok),
+        }
+
+        env.insert(GITHUB_ACTIONS.into(), "false".into());
+        assert_eq!(GithubActions::is_available(&env), false);
+    }
+}
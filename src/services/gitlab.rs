@@ -0,0 +1,178 @@
+use std::fmt::{self, Display};
+
+use crate::services::{required_var, EnvMap, Service, REMOTE_URL};
+use crate::Error;
+
+const GITLAB_CI: &str = "GITLAB_CI";
+const CI_PROJECT_PATH: &str = "CI_PROJECT_PATH";
+const CI_COMMIT_REF_NAME: &str = "CI_COMMIT_REF_NAME";
+const CI_COMMIT_TAG: &str = "CI_COMMIT_TAG";
+const CI_DEFAULT_BRANCH: &str = "CI_DEFAULT_BRANCH";
+const CI_MERGE_REQUEST_IID: &str = "CI_MERGE_REQUEST_IID";
+const CI_MERGE_REQUEST_SOURCE_BRANCH_NAME: &str = "CI_MERGE_REQUEST_SOURCE_BRANCH_NAME";
+const CI_PIPELINE_SOURCE: &str = "CI_PIPELINE_SOURCE";
+const CI_SERVER_URL: &str = "CI_SERVER_URL";
+const CI_PIPELINE_ID: &str = "CI_PIPELINE_ID";
+
+#[derive(Debug)]
+pub struct GitLab {
+    project_id: String,
+    server_url: String,
+    pipeline_id: String,
+    branch: Option<String>,
+    is_default_branch: bool,
+    remote_url: String,
+}
+
+impl GitLab {
+    #[inline]
+    pub fn is_available(env: &EnvMap) -> bool {
+        env.get(GITLAB_CI).map(String::as_str) == Some("true")
+    }
+
+    pub fn from_env(env: &EnvMap) -> Result<Self, Error> {
+        let project_id = required_var(env, CI_PROJECT_PATH)?;
+        let server_url = required_var(env, CI_SERVER_URL)?;
+        let pipeline_id = required_var(env, CI_PIPELINE_ID)?;
+        let remote_url = required_var(env, REMOTE_URL)?;
+        let ref_name = required_var(env, CI_COMMIT_REF_NAME)?;
+
+        // a merge request pipeline may run on the default branch's ref, but never uploads
+        let is_merge_request = env.contains_key(CI_MERGE_REQUEST_IID)
+            || env.get(CI_PIPELINE_SOURCE).map(String::as_str) == Some("merge_request_event");
+
+        let branch = match env.get(CI_MERGE_REQUEST_SOURCE_BRANCH_NAME) {
+            Some(source) if is_merge_request => Some(source.to_string()),
+            _ if env.contains_key(CI_COMMIT_TAG) => None,
+            _ => Some(ref_name.clone()),
+        };
+
+        let is_default_branch = !is_merge_request && env.get(CI_DEFAULT_BRANCH) == Some(&ref_name);
+
+        Ok(GitLab {
+            project_id,
+            server_url,
+            pipeline_id,
+            branch,
+            is_default_branch,
+            remote_url,
+        })
+    }
+}
+
+impl Display for GitLab {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "GitLab at {}, pipeline #{}",
+            self.server_url, self.pipeline_id
+        )
+    }
+}
+
+impl Service for GitLab {
+    #[inline]
+    fn project_id(&self) -> &str {
+        self.project_id.as_str()
+    }
+
+    #[inline]
+    fn is_uploadable(&self) -> bool {
+        self.is_default_branch
+    }
+
+    #[inline]
+    fn remote_url(&self) -> &str {
+        self.remote_url.as_str()
+    }
+
+    #[inline]
+    fn feature_branch(&self) -> Option<&str> {
+        if self.is_default_branch {
+            None
+        } else {
+            self.branch.as_deref()
+        }
+    }
+
+    #[inline]
+    fn into_box(self) -> Box<dyn Service> {
+        Box::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    fn env(ref_name: &str) -> EnvMap {
+        let mut env = HashMap::new();
+        env.insert(GITLAB_CI.into(), "true".into());
+        env.insert(CI_PROJECT_PATH.into(), "group/example".into());
+        env.insert(CI_SERVER_URL.into(), "https://gitlab.example.com".into());
+        env.insert(CI_PIPELINE_ID.into(), "1024".into());
+        env.insert(CI_COMMIT_REF_NAME.into(), ref_name.into());
+        env.insert(CI_DEFAULT_BRANCH.into(), "master".into());
+        env.insert(REMOTE_URL.into(), "s3://bucket/cache".into());
+        env
+    }
+
+    #[test]
+    fn from_env() {
+        let env = env("master");
+        assert_eq!(GitLab::is_available(&env), true);
+
+        let gitlab = GitLab::from_env(&env).unwrap();
+        assert_eq!(
+            gitlab.to_string(),
+            "GitLab at https://gitlab.example.com, pipeline #1024"
+        );
+        assert_eq!(gitlab.project_id(), "group/example");
+        assert_eq!(gitlab.is_uploadable(), true);
+        assert_eq!(gitlab.feature_branch(), None);
+        assert_eq!(gitlab.remote_url(), "s3://bucket/cache");
+    }
+
+    #[test]
+    fn from_env_on_feature_branch() {
+        let mut env = env("feature/foo");
+
+        let gitlab = GitLab::from_env(&env).unwrap();
+        assert_eq!(gitlab.is_uploadable(), false);
+        assert_eq!(gitlab.feature_branch(), Some("feature/foo"));
+
+        env.insert(CI_COMMIT_REF_NAME.into(), "v1.0".into());
+        env.insert(CI_COMMIT_TAG.into(), "v1.0".into());
+
+        let gitlab = GitLab::from_env(&env).unwrap();
+        assert_eq!(gitlab.is_uploadable(), false);
+        assert_eq!(gitlab.feature_branch(), None);
+    }
+
+    #[test]
+    fn from_env_on_merge_request() {
+        let mut env = env("master");
+        env.insert(CI_MERGE_REQUEST_IID.into(), "7".into());
+        env.insert(
+            CI_MERGE_REQUEST_SOURCE_BRANCH_NAME.into(),
+            "feature/foo".into(),
+        );
+
+        let gitlab = GitLab::from_env(&env).unwrap();
+        assert_eq!(gitlab.is_uploadable(), false);
+        assert_eq!(gitlab.feature_branch(), Some("feature/foo"));
+
+        env.remove(CI_MERGE_REQUEST_IID);
+        env.insert(CI_PIPELINE_SOURCE.into(), "merge_request_event".into());
+        let gitlab = GitLab::from_env(&env).unwrap();
+        assert_eq!(gitlab.is_uploadable(), false);
+
+        env.remove(CI_PIPELINE_ID);
+        match GitLab::from_env(&env) {
+            Err(err) => assert!(err.to_string().contains(CI_PIPELINE_ID)),
+            Ok(ok) => unreachable!("{:?}", ok),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::fmt::{self, Display};
+
+use crate::services::{required_var, EnvMap, Service, DEFAULT_BRANCH, REMOTE_URL};
+use crate::Error;
+
+const JENKINS_URL: &str = "JENKINS_URL";
+const BUILD_ID: &str = "BUILD_ID";
+const JOB_NAME: &str = "JOB_NAME";
+const BRANCH_NAME: &str = "BRANCH_NAME";
+const CHANGE_ID: &str = "CHANGE_ID";
+const CHANGE_BRANCH: &str = "CHANGE_BRANCH";
+const DEFAULT_BRANCH_NAME: &str = "master";
+
+/// A Jenkins job, a multibranch pipeline shares one project id for all its branches.
+///
+/// Only builds of `TC_CACHE_DEFAULT_BRANCH` (`master` if it isn't set) upload,
+/// a job which isn't a multibranch one always does.
+#[derive(Debug)]
+pub struct Jenkins {
+    project_id: String,
+    server_url: String,
+    build_id: String,
+    branch: Option<String>,
+    is_default_branch: bool,
+    remote_url: String,
+}
+
+impl Jenkins {
+    #[inline]
+    pub fn is_available(env: &EnvMap) -> bool {
+        env.contains_key(JENKINS_URL) && env.contains_key(BUILD_ID)
+    }
+
+    pub fn from_env(env: &EnvMap) -> Result<Self, Error> {
+        let server_url = required_var(env, JENKINS_URL)?;
+        let build_id = required_var(env, BUILD_ID)?;
+        let job_name = required_var(env, JOB_NAME)?;
+        let remote_url = required_var(env, REMOTE_URL)?;
+
+        let default_branch = env
+            .get(DEFAULT_BRANCH)
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_BRANCH_NAME);
+
+        let (project_id, branch, is_default_branch) = match env.get(BRANCH_NAME) {
+            Some(branch_name) => {
+                let project_id = project_id(&job_name);
+                // a change request is built as `PR-<id>`, its branch is the source one
+                let branch = match env.get(CHANGE_ID) {
+                    Some(_) => env.get(CHANGE_BRANCH).unwrap_or(branch_name),
+                    None => branch_name,
+                };
+                let is_default =
+                    !env.contains_key(CHANGE_ID) && branch_name.as_str() == default_branch;
+                (project_id, Some(branch.to_string()), is_default)
+            }
+            None => (job_name, None, !env.contains_key(CHANGE_ID)),
+        };
+
+        Ok(Jenkins {
+            project_id,
+            server_url,
+            build_id,
+            branch,
+            is_default_branch,
+            remote_url,
+        })
+    }
+}
+
+/// The job name of a multibranch pipeline without its last segment, the (encoded) branch.
+fn project_id(job_name: &str) -> String {
+    match job_name.rfind('/') {
+        Some(idx) => job_name[..idx].to_string(),
+        None => job_name.to_string(),
+    }
+}
+
+impl Display for Jenkins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "Jenkins at {}, {} build #{}",
+            self.server_url, self.project_id, self.build_id
+        )
+    }
+}
+
+impl Service for Jenkins {
+    #[inline]
+    fn project_id(&self) -> &str {
+        self.project_id.as_str()
+    }
+
+    #[inline]
+    fn is_uploadable(&self) -> bool {
+        self.is_default_branch
+    }
+
+    #[inline]
+    fn remote_url(&self) -> &str {
+        self.remote_url.as_str()
+    }
+
+    #[inline]
+    fn feature_branch(&self) -> Option<&str> {
+        if self.is_default_branch {
+            None
+        } else {
+            self.branch.as_deref()
+        }
+    }
+
+    #[inline]
+    fn into_box(self) -> Box<dyn Service> {
+        Box::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    fn env(job_name: &str) -> EnvMap {
+        let mut env = HashMap::new();
+        env.insert(JENKINS_URL.into(), "https://jenkins.example.com/".into());
+        env.insert(BUILD_ID.into(), "42".into());
+        env.insert(JOB_NAME.into(), job_name.into());
+        env.insert(REMOTE_URL.into(), "s3://bucket/cache".into());
+        env
+    }
+
+    #[test]
+    fn from_env() {
+        let env = env("example");
+        assert_eq!(Jenkins::is_available(&env), true);
+
+        let jenkins = Jenkins::from_env(&env).unwrap();
+        assert_eq!(
+            jenkins.to_string(),
+            "Jenkins at https://jenkins.example.com/, example build #42"
+        );
+        assert_eq!(jenkins.project_id(), "example");
+        assert_eq!(jenkins.is_uploadable(), true);
+        assert_eq!(jenkins.feature_branch(), None);
+        assert_eq!(jenkins.remote_url(), "s3://bucket/cache");
+    }
+
+    #[test]
+    fn from_env_multibranch() {
+        let mut env = env("folder/example/master");
+        env.insert(BRANCH_NAME.into(), "master".into());
+
+        let jenkins = Jenkins::from_env(&env).unwrap();
+        assert_eq!(jenkins.project_id(), "folder/example");
+        assert_eq!(jenkins.is_uploadable(), true);
+        assert_eq!(jenkins.feature_branch(), None);
+
+        env.insert(JOB_NAME.into(), "folder/example/feature%2Ffoo".into());
+        env.insert(BRANCH_NAME.into(), "feature/foo".into());
+
+        let jenkins = Jenkins::from_env(&env).unwrap();
+        assert_eq!(jenkins.project_id(), "folder/example");
+        assert_eq!(jenkins.is_uploadable(), false);
+        assert_eq!(jenkins.feature_branch(), Some("feature/foo"));
+
+        env.insert(DEFAULT_BRANCH.into(), "feature/foo".into());
+        let jenkins = Jenkins::from_env(&env).unwrap();
+        assert_eq!(jenkins.is_uploadable(), true);
+    }
+
+    #[test]
+    fn from_env_on_change_request() {
+        let mut env = env("example/PR-7");
+        env.insert(BRANCH_NAME.into(), "PR-7".into());
+        env.insert(CHANGE_ID.into(), "7".into());
+        env.insert(CHANGE_BRANCH.into(), "feature/foo".into());
+        env.insert(DEFAULT_BRANCH.into(), "PR-7".into());
+
+        let jenkins = Jenkins::from_env(&env).unwrap();
+        assert_eq!(jenkins.project_id(), "example");
+        assert_eq!(jenkins.is_uploadable(), false);
+        assert_eq!(jenkins.feature_branch(), Some("feature/foo"));
+
+        env.remove(JOB_NAME);
+        match Jenkins::from_env(&env) {
+            Err(err) => assert!(err.to_string().contains(JOB_NAME)),
+            Ok(ok) => unreachable!("{:?}", ok),
+        }
+    }
+}
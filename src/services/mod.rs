@@ -1,27 +1,64 @@
 use std::collections::HashMap;
 use std::fmt::Display;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 mod generic;
+mod github_actions;
+mod gitlab;
+mod jenkins;
 mod teamcity;
 
 use self::generic::Generic;
+use self::github_actions::GithubActions;
+use self::gitlab::GitLab;
+use self::jenkins::Jenkins;
 use self::teamcity::TeamCity;
-use crate::Error;
+use crate::{Error, Upload};
+
+/// The remote location for services which don't set it in build settings.
+const REMOTE_URL: &str = "TC_CACHE_REMOTE_URL";
+const DEFAULT_BRANCH: &str = "TC_CACHE_DEFAULT_BRANCH";
+
+type EnvMap = HashMap<String, String>;
 
 pub trait Service: Display {
     fn project_id(&self) -> &str;
     fn is_uploadable(&self) -> bool;
     fn remote_url(&self) -> &str;
+    /// The build's branch when it isn't the default one.
+    fn feature_branch(&self) -> Option<&str>;
     fn into_box(self) -> Box<dyn Service>;
+
+    /// The upload policy set in the service's build settings, if it has them.
+    fn upload(&self) -> Option<Upload> {
+        None
+    }
+
+    /// A key prefix set in the service's build settings.
+    fn key(&self) -> Option<&str> {
+        None
+    }
+
+    /// Directories to cache set in the service's build settings.
+    fn dirs(&self) -> &[PathBuf] {
+        &[]
+    }
 }
 
 #[derive(Debug)]
 pub struct ServiceFactory;
 
 impl ServiceFactory {
+    /// Detects the service the build runs on, the first available one wins:
+    ///
+    /// 1. TeamCity, when the path to its build properties is given explicitly;
+    /// 2. generic, configured by `TC_CACHE_*` variables;
+    /// 3. TeamCity (`TEAMCITY_BUILD_PROPERTIES_FILE`);
+    /// 4. GitHub Actions (`GITHUB_ACTIONS`);
+    /// 5. GitLab (`GITLAB_CI`);
+    /// 6. Jenkins (`JENKINS_URL` and `BUILD_ID`).
     pub fn from_env<P>(
-        env: &HashMap<String, String>,
+        env: &EnvMap,
         teamcity_build_properties_path: Option<P>,
     ) -> Result<Box<dyn Service>, Error>
     where
@@ -40,7 +77,30 @@ impl ServiceFactory {
             return TeamCity::from_env(&env).map(Service::into_box);
         }
 
+        if GithubActions::is_available(&env) {
+            return GithubActions::from_env(&env).map(Service::into_box);
+        }
+
+        if GitLab::is_available(&env) {
+            return GitLab::from_env(&env).map(Service::into_box);
+        }
+
+        if Jenkins::is_available(&env) {
+            return Jenkins::from_env(&env).map(Service::into_box);
+        }
+
         let err = format!("Unable to detect service");
         return Err(Error::unrecognized_service(err));
     }
 }
+
+/// A variable the service can't be detected without.
+fn required_var(env: &EnvMap, name: &str) -> Result<String, Error> {
+    match env.get(name) {
+        Some(val) => Ok(val.to_string()),
+        None => {
+            let err = format!("Environment variable '{}' wasn't found", name);
+            Err(Error::unrecognized_service(err))
+        }
+    }
+}
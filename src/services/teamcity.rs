@@ -2,28 +2,51 @@ use std::collections::HashMap;
 use std::fmt::{self, Display};
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::iter;
+use std::path::{Path, PathBuf};
 
 use crate::errors::ResultExt;
-use crate::{Error, Service};
+use crate::services::{required_var, EnvMap};
+use crate::{Error, Service, Upload};
 
 const TEAMCITY_VERSION: &str = "teamcity.version";
 const TEAMCITY_SERVER_URL: &str = "teamcity.serverUrl";
 const TEAMCITY_PROJECT_ID: &str = "teamcity.project.id";
+const TEAMCITY_BUILD_BRANCH: &str = "teamcity.build.branch";
 const TEAMCITY_BUILD_BRANCH_IS_DEFAULT: &str = "teamcity.build.branch.is_default";
+const TEAMCITY_BUILD_TYPE_ID: &str = "teamcity.buildType.id";
+const TEAMCITY_AGENT_OS_NAME: &str = "teamcity.agent.jvm.os.name";
+const TEAMCITY_AGENT_OS_ARCH: &str = "teamcity.agent.jvm.os.arch";
 const TC_CACHE_REMOTE_URL: &str = "tc.cache.remote.url";
+const TC_CACHE_UPLOAD: &str = "tc.cache.upload";
+const TC_CACHE_KEY: &str = "tc.cache.key";
+const TC_CACHE_KEY_INCLUDE: &str = "tc.cache.key.include";
+const TC_CACHE_DIRS: &str = "tc.cache.dirs";
+const TC_CACHE_PREFIX: &str = "tc.cache.";
 const TEAMCITY_BUILD_PROPERTIES_FILE: &str = "TEAMCITY_BUILD_PROPERTIES_FILE";
 const TEAMCITY_CONFIGURATION_PROPERTIES_FILE: &str = "teamcity.configuration.properties.file";
-
+// whitespace of the .properties format
+const WHITESPACE: &[char] = &[' ', '\t', '\x0c'];
+
+/// A TeamCity build, configured by `tc.cache.*` parameters of the build configuration:
+///
+/// * `tc.cache.remote.url` - where caches are kept;
+/// * `tc.cache.upload` - `always`, `default-branch` or `never`;
+/// * `tc.cache.dirs` - directories to cache, separated by commas or new lines;
+/// * `tc.cache.key` - a key prefix;
+/// * `tc.cache.key.include` - what else is a part of the key, separated by commas:
+///   `build-type`, the agent's `os` and `arch`, or values of other `tc.cache.*` parameters.
 pub struct TeamCity {
     name: String,
     project_id: String,
+    branch: Option<String>,
     is_default_branch: bool,
     remote_url: String,
+    upload: Option<Upload>,
+    key: Option<String>,
+    dirs: Vec<PathBuf>,
 }
 
-type EnvMap = HashMap<String, String>;
-
 impl TeamCity {
     #[inline]
     pub fn is_available(env: &EnvMap) -> bool {
@@ -31,18 +54,7 @@ impl TeamCity {
     }
 
     pub fn from_env(env: &EnvMap) -> Result<Self, Error> {
-        let props_path = match env.get(TEAMCITY_BUILD_PROPERTIES_FILE) {
-            Some(val) => val,
-            None => {
-                let err = format!(
-                    "Environment variable '{}' wasn't found",
-                    TEAMCITY_BUILD_PROPERTIES_FILE
-                );
-                return Err(Error::unrecognized_service(err));
-            }
-        };
-
-        let props_path = Path::new(props_path);
+        let props_path = required_var(env, TEAMCITY_BUILD_PROPERTIES_FILE)?;
         TeamCity::from_path(env, props_path)
     }
 
@@ -50,11 +62,11 @@ impl TeamCity {
     where
         P: AsRef<Path>,
     {
-        let props = Props::from_path(&path)?;
-        let version = props.key(TEAMCITY_VERSION)?;
-        let remote_url = props.key(TC_CACHE_REMOTE_URL).map(str::to_string)?;
+        let build_props = Props::from_path(&path)?;
+        let version = build_props.key(TEAMCITY_VERSION)?;
+        let remote_url = build_props.key(TC_CACHE_REMOTE_URL).map(str::to_string)?;
 
-        let config_path = props.key(TEAMCITY_CONFIGURATION_PROPERTIES_FILE)?;
+        let config_path = build_props.key(TEAMCITY_CONFIGURATION_PROPERTIES_FILE)?;
         let props = Props::from_path(config_path)?;
 
         let server_url = props.key(TEAMCITY_SERVER_URL)?;
@@ -67,17 +79,81 @@ impl TeamCity {
             .map(|it| it == "true")
             .unwrap_or(false);
 
+        let branch = env
+            .get(TEAMCITY_BUILD_BRANCH)
+            .map(|it| it.as_str())
+            .or_else(|| props.key(TEAMCITY_BUILD_BRANCH).ok())
+            .map(str::to_string);
+
+        // system parameters are in the build properties, configuration ones in the other file
+        let param = |name: &str| build_props.key(name).or_else(|_| props.key(name));
+
+        let upload = match param(TC_CACHE_UPLOAD).ok() {
+            Some(val) => Some(val.parse::<Upload>().map_err(Error::unrecognized_service)?),
+            None => None,
+        };
+
+        let mut key_parts = Vec::new();
+        if let Ok(include) = param(TC_CACHE_KEY_INCLUDE) {
+            for name in split_list(include) {
+                let value = match name {
+                    "build-type" => param(TEAMCITY_BUILD_TYPE_ID)?,
+                    "os" => param(TEAMCITY_AGENT_OS_NAME)?,
+                    "arch" => param(TEAMCITY_AGENT_OS_ARCH)?,
+                    _ if name.starts_with(TC_CACHE_PREFIX) => param(name)?,
+                    _ => {
+                        let err = format!("Unknown part '{}' of '{}'", name, TC_CACHE_KEY_INCLUDE);
+                        return Err(Error::unrecognized_service(err));
+                    }
+                };
+                key_parts.push(key_segment(value));
+            }
+        }
+
+        let key_parts = Some(key_parts.join("-")).filter(|it| !it.is_empty());
+        let key = match (key_parts, param(TC_CACHE_KEY).ok()) {
+            (Some(parts), Some(key)) => Some(format!("{}/{}", key, parts)),
+            (parts, key) => key.map(str::to_string).or(parts),
+        };
+
+        let dirs = param(TC_CACHE_DIRS)
+            .map(|it| split_list(it).map(PathBuf::from).collect())
+            .unwrap_or_default();
+
         let name = format!("{} at {}", version, server_url);
 
         Ok(TeamCity {
             name,
             project_id,
+            branch,
             is_default_branch,
             remote_url,
+            upload,
+            key,
+            dirs,
         })
     }
 }
 
+/// Items of a parameter's value separated by commas or new lines, e.g. a multi-line text one.
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(&[',', '\n'][..])
+        .map(str::trim)
+        .filter(|it| !it.is_empty())
+}
+
+/// A value as a part of a single key segment, e.g. `Mac-OS-X` for `Mac OS X`.
+fn key_segment(value: &str) -> String {
+    value
+        .chars()
+        .map(|it| match it {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => it,
+            _ => '-',
+        })
+        .collect()
+}
+
 impl Display for TeamCity {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         write!(f, "TeamCity {}", self.name)
@@ -100,10 +176,34 @@ impl Service for TeamCity {
         self.remote_url.as_str()
     }
 
+    #[inline]
+    fn feature_branch(&self) -> Option<&str> {
+        if self.is_default_branch {
+            None
+        } else {
+            self.branch.as_deref()
+        }
+    }
+
     #[inline]
     fn into_box(self) -> Box<dyn Service> {
         Box::new(self)
     }
+
+    #[inline]
+    fn upload(&self) -> Option<Upload> {
+        self.upload
+    }
+
+    #[inline]
+    fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    #[inline]
+    fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
 }
 
 #[derive(Debug)]
@@ -126,11 +226,29 @@ impl Props {
         Ok(Props::from_content(content.as_str()))
     }
 
+    /// Joins lines continued by a trailing backslash, skips blank lines and comments.
     fn from_content(content: &str) -> Self {
-        let props = content
-            .lines()
-            .filter_map(Props::parse)
-            .collect::<HashMap<String, String>>();
+        let mut props = HashMap::new();
+        let mut lines = content.lines();
+
+        while let Some(line) = lines.next() {
+            let mut line = line.trim_start_matches(WHITESPACE).to_string();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+                continue;
+            }
+
+            while is_continued(&line) {
+                line.pop();
+                match lines.next() {
+                    Some(next) => line.push_str(next.trim_start_matches(WHITESPACE)),
+                    None => break,
+                }
+            }
+
+            if let Some((key, value)) = Props::parse(&line) {
+                props.insert(key, value);
+            }
+        }
 
         Props(props)
     }
@@ -151,25 +269,101 @@ impl Props {
         }
     }
 
+    /// Parses a logical line, the key ends at the first unescaped '=', ':' or whitespace.
+    /// Keys with an empty value are skipped, as if they weren't set.
     fn parse(line: &str) -> Option<(String, String)> {
-        let line = line.trim();
-        if line.starts_with('#') {
+        let mut chars = line.char_indices();
+        let mut end = line.len();
+
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '=' | ':' => {
+                    end = idx;
+                    break;
+                }
+                _ if WHITESPACE.contains(&c) => {
+                    end = idx;
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        let rest = line[end..].trim_start_matches(WHITESPACE);
+        let rest = match rest.chars().next() {
+            Some('=') | Some(':') => rest[1..].trim_start_matches(WHITESPACE),
+            _ => rest,
+        };
+
+        let key = unescape(&line[..end]);
+        let value = unescape(rest);
+
+        if key.is_empty() || value.is_empty() {
             return None;
         }
 
-        if let Some(idx) = line.find('=') {
-            let key = line[0..idx].trim().to_string();
-            let value = line[(idx + 1)..].trim().to_string();
-            let value = value.replace("\\:", ":").replace("\\=", "=");
+        Some((key, value))
+    }
+}
 
-            if key.is_empty() || value.is_empty() {
-                return None;
-            }
+/// Whether a line ends with an odd number of backslashes, the last one isn't escaped.
+fn is_continued(line: &str) -> bool {
+    line.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1
+}
+
+/// Replaces `\t`, `\n`, `\r`, `\f` and `\uXXXX` escapes, any other escaped char stands for itself.
+fn unescape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
 
-            return Some((key, value));
+        match chars.next() {
+            Some('t') => res.push('\t'),
+            Some('n') => res.push('\n'),
+            Some('r') => res.push('\r'),
+            Some('f') => res.push('\x0c'),
+            Some('u') => match unicode(chars.as_str()) {
+                Some((decoded, len)) => {
+                    res.push(decoded);
+                    chars.nth(len - 1);
+                }
+                None => res.push('u'),
+            },
+            Some(other) => res.push(other),
+            None => {}
         }
+    }
+
+    res
+}
 
-        None
+/// Decodes hex digits after `\u`, a supplementary character is escaped as a UTF-16
+/// surrogate pair, e.g. `\ud83d\ude00`. Returns the char and how many chars it takes.
+fn unicode(s: &str) -> Option<(char, usize)> {
+    let unit = |s: &str| {
+        s.get(..4)
+            .filter(|hex| hex.chars().all(|it| it.is_ascii_hexdigit()))
+            .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+    };
+
+    let high = unit(s)?;
+    let low = s
+        .get(4..)
+        .and_then(|it| it.strip_prefix("\\u"))
+        .and_then(unit);
+
+    match char::decode_utf16(iter::once(high).chain(low)).next()? {
+        Ok(decoded) if decoded.len_utf16() == 2 => Some((decoded, 10)),
+        Ok(decoded) => Some((decoded, 4)),
+        Err(_) => None,
     }
 }
 
@@ -177,7 +371,10 @@ impl Props {
 mod tests {
     use super::*;
 
-    use crate::testing::{TEAMCITY_BUILD_PROPS_PATH, TEAMCITY_CONFIG_PROPS_PATH};
+    use crate::testing::{
+        TEAMCITY_BUILD_PROPS_PATH, TEAMCITY_CONFIG_PROPS_PATH, TEAMCITY_ESCAPES_PROPS_PATH,
+        TEAMCITY_PARAMS_PROPS_PATH,
+    };
 
     #[test]
     fn parse_build_properties() {
@@ -233,6 +430,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_escapes_and_continuations() {
+        let props = Props::from_path(TEAMCITY_ESCAPES_PROPS_PATH).unwrap();
+        let key = |key| props.key(key).ok();
+
+        assert_eq!(key("agent.home.dir"), Some("C:\\BuildAgent"));
+        assert_eq!(key("agent.work.dir"), Some("C:\\BuildAgent\\work"));
+        assert_eq!(key(TEAMCITY_BUILD_BRANCH), Some("feature/фича"));
+        assert_eq!(
+            key("teamcity.build.checkoutDir"),
+            Some("C:\\BuildAgent\\work\\acf9e5af1d1dbbb")
+        );
+        assert_eq!(key("teamcity.buildConfName"), Some("cli::build"));
+        assert_eq!(
+            key("teamcity.build.vcs.changes.message"),
+            Some("Fix build\n\tand tests")
+        );
+        assert_eq!(
+            key(TC_CACHE_REMOTE_URL),
+            Some("s3://teamcity/cache?endpoint=http://127.0.0.1:9000&region=eu-west-1")
+        );
+        assert_eq!(
+            key("teamcity.runner.properties.file"),
+            Some("/home/ubuntu/temp/buildTmp/teamcity.runner6186839756084378121.properties")
+        );
+        assert_eq!(key("key with spaces"), Some("value"));
+        assert_eq!(key("emoji"), Some("\u{1f600}"));
+        assert_eq!(key("tc.cache.dirs"), Some("a\\\\"));
+        assert_eq!(key("tc.cache.empty"), None);
+        assert_eq!(key("not.a.comment"), Some("true"));
+        assert_eq!(key("! written by the agent on Windows"), None);
+    }
+
+    #[test]
+    fn parse_malformed_escapes() {
+        let props = Props::from_content("a=\\u00e9\\uzz\\q\nc=\\ud83d!\nb=c\\");
+
+        assert_eq!(props.key("a").ok(), Some("éuzzq"));
+        assert_eq!(props.key("b").ok(), Some("c"));
+        assert_eq!(props.key("c").ok(), Some("ud83d!"));
+    }
+
     #[test]
     fn from_env() {
         let env = {
@@ -250,9 +489,48 @@ mod tests {
         );
         assert_eq!(env.project_id(), "Github_Example_Example");
         assert_eq!(env.is_uploadable(), true);
+        assert_eq!(env.feature_branch(), None);
         assert_eq!(
             env.remote_url(),
             "s3://teamcity/cache?endpoint=http://127.0.0.1:9000"
         );
+        assert_eq!(env.upload(), None);
+        assert_eq!(env.key(), None);
+        assert_eq!(env.dirs().is_empty(), true);
+    }
+
+    #[test]
+    fn cache_params_from_path() {
+        let env = HashMap::new();
+        let teamcity = TeamCity::from_path(&env, TEAMCITY_PARAMS_PROPS_PATH).unwrap();
+
+        assert_eq!(teamcity.upload(), Some(Upload::Always));
+        assert_eq!(
+            teamcity.key(),
+            Some("v2/Github_Example_Example_CliBuild_BuildType-Linux-amd64-Open-JDK-17")
+        );
+        assert_eq!(
+            teamcity.dirs(),
+            &[
+                PathBuf::from("build/deps"),
+                PathBuf::from(".gradle/caches"),
+                PathBuf::from("node_modules"),
+            ]
+        );
+    }
+
+    #[test]
+    fn feature_branch_from_env() {
+        let mut env = HashMap::new();
+        env.insert(
+            TEAMCITY_BUILD_PROPERTIES_FILE.into(),
+            TEAMCITY_BUILD_PROPS_PATH.into(),
+        );
+        env.insert(TEAMCITY_BUILD_BRANCH_IS_DEFAULT.into(), "false".into());
+        env.insert(TEAMCITY_BUILD_BRANCH.into(), "feature/foo".into());
+
+        let teamcity = TeamCity::from_env(&env).unwrap();
+        assert_eq!(teamcity.is_uploadable(), false);
+        assert_eq!(teamcity.feature_branch(), Some("feature/foo"));
     }
 }
@@ -0,0 +1,136 @@
+use std::fmt::{self, Display};
+use std::io::{self, Chain, Cursor, Read, Write};
+use std::str::FromStr;
+
+use crate::crypto::{Opening, Sealing};
+use crate::errors::ResultExt;
+use crate::Error;
+
+/// The first byte of a snappy stream, its identifier chunk. An uncompressed
+/// snapshot starts with the version header instead.
+const SNAPPY_STREAM_ID: u8 = 0xFF;
+
+/// How a snapshot is compressed, readers tell it by the snapshot's first byte.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Snappy,
+    /// For already compressed content, e.g. jars or docker layers.
+    None,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Snappy
+    }
+}
+
+impl Compression {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Compression::Snappy => "snappy",
+            Compression::None => "none",
+        }
+    }
+
+    pub fn variants() -> &'static [&'static str] {
+        &["snappy", "none"]
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snappy" => Ok(Compression::Snappy),
+            "none" => Ok(Compression::None),
+            _ => {
+                let err = format!(
+                    "Unsupported compression '{}', expected one of {:?}",
+                    s,
+                    Compression::variants()
+                );
+                Error::snapshot_err("Unknown compression", err)
+            }
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+pub enum Compressed<W: Write> {
+    Snappy(Box<snap::Writer<Sealing<W>>>),
+    None(Sealing<W>),
+}
+
+impl<W: Write> Compressed<W> {
+    pub fn new(writer: Sealing<W>, compression: Compression) -> Self {
+        match compression {
+            Compression::Snappy => Compressed::Snappy(Box::new(snap::Writer::new(writer))),
+            Compression::None => Compressed::None(writer),
+        }
+    }
+
+    /// Flushes the compressed stream, the sealing one is left to finish.
+    pub fn into_inner(self) -> Result<Sealing<W>, Error> {
+        match self {
+            Compressed::Snappy(writer) => writer
+                .into_inner()
+                .map_err(|err| err.to_string())
+                .snapshot_err("Flush failed"),
+            Compressed::None(writer) => Ok(writer),
+        }
+    }
+}
+
+impl<W: Write> Write for Compressed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Compressed::Snappy(writer) => writer.write(buf),
+            Compressed::None(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Compressed::Snappy(writer) => writer.flush(),
+            Compressed::None(writer) => writer.flush(),
+        }
+    }
+}
+
+type Peeked<R> = Chain<Cursor<[u8; 1]>, Opening<R>>;
+
+pub enum Decompressed<R: Read> {
+    Snappy(snap::Reader<Peeked<R>>),
+    None(Peeked<R>),
+}
+
+impl<R: Read> Decompressed<R> {
+    pub fn new(mut reader: Opening<R>) -> Result<Self, Error> {
+        let mut first = [0; 1];
+        reader
+            .read_exact(&mut first)
+            .map_err(Error::snapshot_io("Read version header failed"))?;
+
+        let peeked = Cursor::new(first).chain(reader);
+        if first[0] == SNAPPY_STREAM_ID {
+            Ok(Decompressed::Snappy(snap::Reader::new(peeked)))
+        } else {
+            Ok(Decompressed::None(peeked))
+        }
+    }
+}
+
+impl<R: Read> Read for Decompressed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decompressed::Snappy(reader) => reader.read(buf),
+            Decompressed::None(reader) => reader.read(buf),
+        }
+    }
+}
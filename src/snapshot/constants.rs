@@ -1,3 +1,8 @@
 pub const VERSION_LEN: usize = 4;
-pub const VERSION: &[u8; VERSION_LEN] = &[0xA0, 0xF1, 0xB2, 0x01];
+/// Snapshots may end with a footer, older readers reject them.
+pub const VERSION: &[u8; VERSION_LEN] = &[0xA0, 0xF1, 0xB2, 0x02];
+/// Snapshots without a footer, still readable.
+pub const VERSION_WITHOUT_FOOTER: &[u8; VERSION_LEN] = &[0xA0, 0xF1, 0xB2, 0x01];
 pub const BUFFER_SIZE: usize = 64 * 1024; // 64kb
+/// Takes the place of an entry's length, the footer follows it instead of an entry.
+pub const FOOTER_MARKER: u32 = 0xFFFF_FFFF;
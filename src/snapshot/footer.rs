@@ -0,0 +1,21 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Written after the last entry, snapshots pushed before it was added have none.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Footer {
+    /// A build timestamp in milliseconds, a snapshot with a greater one was pushed later.
+    pub generation: u64,
+}
+
+impl Footer {
+    pub fn now() -> Self {
+        let generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|it| it.as_millis() as u64)
+            .unwrap_or(0);
+
+        Footer { generation }
+    }
+}
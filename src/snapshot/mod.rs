@@ -1,15 +1,19 @@
+mod compression;
 mod constants;
 mod diff;
 mod entry;
+mod footer;
 mod pack;
 mod reading;
 mod unpack;
 mod writing;
 
+pub use self::compression::{Compressed, Compression, Decompressed};
 pub use self::constants::*;
 pub use self::diff::{diff, Diff};
 pub use self::entry::{Attributes, Entry, EntryKind};
+pub use self::footer::Footer;
 pub use self::pack::Pack;
 pub use self::reading::Reading;
-pub use self::unpack::Unpack;
+pub use self::unpack::{is_glob_include, is_include, prefixed, restore_attributes, Unpack};
 pub use self::writing::Writing;
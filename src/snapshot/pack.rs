@@ -1,26 +1,13 @@
 use std::io::Write;
-use std::path::Path;
 
-use crate::snapshot::{Entry, Writing};
+use crate::snapshot::{Compressed, Entry, Writing};
 use crate::Error;
 
 pub trait Pack {
-    fn pack<P>(self, dirs: &[P]) -> Result<usize, Error>
-    where
-        P: AsRef<Path>;
-
     fn pack_with_entries(self, entries: &[Entry]) -> Result<usize, Error>;
 }
 
-impl<W: Write> Pack for Writing<W> {
-    fn pack<P>(self, dirs: &[P]) -> Result<usize, Error>
-    where
-        P: AsRef<Path>,
-    {
-        let entries = Entry::walk_into_vec(&dirs)?;
-        self.pack_with_entries(&entries)
-    }
-
+impl<W: Write> Pack for Writing<Compressed<W>> {
     fn pack_with_entries(mut self, entries: &[Entry]) -> Result<usize, Error> {
         let mut written = 0_usize;
 
@@ -33,7 +20,7 @@ impl<W: Write> Pack for Writing<W> {
                 }
             }
         }
-        self.flush()?;
+        self.finish()?;
 
         Ok(written)
     }
@@ -43,6 +30,8 @@ impl<W: Write> Pack for Writing<W> {
 mod tests {
     use super::*;
 
+    use std::path::Path;
+
     use crate::testing::{temp_file, FIXTURES_PATH, IS_DIR_PATH};
 
     #[test]
@@ -50,8 +39,9 @@ mod tests {
         let dst = temp_file(".sn");
         let src = vec![Path::new(FIXTURES_PATH), Path::new(IS_DIR_PATH)];
 
-        let snapshot = Writing::open(&dst).unwrap();
-        let written = snapshot.pack(&src).unwrap();
+        let entries = Entry::walk_into_vec(&src).unwrap();
+        let snapshot = Writing::open(&dst, None).unwrap();
+        let written = snapshot.pack_with_entries(&entries).unwrap();
 
         assert_eq!(written, 83947);
     }
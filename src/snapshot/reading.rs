@@ -1,31 +1,39 @@
 use std::io::ErrorKind::UnexpectedEof;
-use std::io::{Cursor, Error as IoError, Read, Write};
+use std::io::{Cursor, Error as IoError, Read, Take, Write};
 use std::path::Path;
 
 use crate::bytes::FromLeBytes;
-use crate::errors::ResultExt;
+use crate::crypto::{Key, Opening};
 use crate::mmap::Mmap;
-use crate::snapshot::{Entry, BUFFER_SIZE, VERSION, VERSION_LEN};
+use crate::snapshot::{
+    Decompressed, Entry, Footer, BUFFER_SIZE, FOOTER_MARKER, VERSION, VERSION_LEN,
+    VERSION_WITHOUT_FOOTER,
+};
 use crate::{mmap, Error, Stats};
 
 #[derive(Debug)]
 pub struct Reading<R = ()> {
     reader: R,
+    footer: Option<Footer>,
 }
 
 impl Reading {
-    pub fn from<R: Read>(reader: R) -> Result<Reading<snap::Reader<R>>, Error> {
+    pub fn from<R: Read>(reader: R, key: Option<&Key>) -> Result<Reading<Decompressed<R>>, Error> {
         let mut reader = Reading {
-            reader: snap::Reader::new(reader),
+            reader: Decompressed::new(Opening::new(reader, key)?)?,
+            footer: None,
         };
 
         reader.check_version()?;
         Ok(reader)
     }
 
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Reading<snap::Reader<Cursor<Mmap>>>, Error> {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        key: Option<&Key>,
+    ) -> Result<Reading<Decompressed<Cursor<Mmap>>>, Error> {
         let (_, _, src) = mmap::read(&path, None)?;
-        Reading::from(Cursor::new(src))
+        Reading::from(Cursor::new(src), key)
     }
 }
 
@@ -37,15 +45,21 @@ impl<R: Read> Reading<R> {
         let mut buf: [u8; VERSION_LEN] = [0; VERSION_LEN];
 
         src.read_exact(&mut buf)
-            .snapshot_err("Read version header failed")?;
+            .map_err(Error::snapshot_io("Read version header failed"))?;
 
-        if VERSION != &buf {
+        if VERSION != &buf && VERSION_WITHOUT_FOOTER != &buf {
             let err = format!("Expected {:?}, got {:?}", VERSION, buf);
             Error::snapshot_err("Version header mismatch", err)
         } else {
             Ok(())
         }
     }
+
+    /// The footer, known once `read_entry` has returned the last entry.
+    pub fn footer(&self) -> Option<Footer> {
+        self.footer
+    }
+
     pub fn read_entry(&mut self) -> Result<Option<(Entry, usize)>, Error> {
         let src = &mut self.reader;
         let mut buf: [u8; 4] = [0; 4];
@@ -54,15 +68,21 @@ impl<R: Read> Reading<R> {
             if err.kind() == UnexpectedEof {
                 return Ok(None);
             } else {
-                return Err(Error::snapshot("Read entry size failed")(err));
+                return Err(Error::snapshot_io("Read entry size failed")(err));
             }
         }
-        let len = u32::from_le_bytes(buf) as usize;
-        let mut buf = vec![0u8; len];
+        let len = u32::from_le_bytes(buf);
+        if len == FOOTER_MARKER {
+            self.read_footer()?;
+            return Ok(None);
+        }
 
-        src.read_exact(&mut buf).snapshot_err("Read entry failed")?;
+        let mut buf = vec![0u8; len as usize];
 
-        let entry = serde_cbor::from_slice(&buf).snapshot_err("Read entry failed")?;
+        src.read_exact(&mut buf)
+            .map_err(Error::snapshot_io("Read entry failed"))?;
+
+        let entry = serde_cbor::from_slice(&buf).map_err(Error::snapshot("Read entry failed"))?;
         let len = buf.len() + 4;
 
         Stats::current().unpacking().inc(len);
@@ -70,6 +90,24 @@ impl<R: Read> Reading<R> {
         Ok(Some((entry, len)))
     }
 
+    fn read_footer(&mut self) -> Result<(), Error> {
+        let src = &mut self.reader;
+        let mut buf: [u8; 4] = [0; 4];
+
+        src.read_exact(&mut buf)
+            .map_err(Error::snapshot_io("Read footer size failed"))?;
+
+        let mut buf = vec![0u8; u32::from_le_bytes(buf) as usize];
+        src.read_exact(&mut buf)
+            .map_err(Error::snapshot_io("Read footer failed"))?;
+
+        let footer = serde_cbor::from_slice(&buf).map_err(Error::snapshot("Read footer failed"))?;
+        Stats::current().unpacking().inc(buf.len() + 8);
+
+        self.footer = Some(footer);
+        Ok(())
+    }
+
     pub fn copy_to<W: Write>(&mut self, dst: &mut W, mut len: usize) -> Result<usize, Error> {
         Stats::current().unpacking().inc(len);
 
@@ -85,14 +123,21 @@ impl<R: Read> Reading<R> {
             let chunk = BUFFER_SIZE.min(len);
 
             src.read_exact(&mut buf[..chunk])
-                .snapshot_err("Copy failed")?;
-            dst.write_all(&buf[..chunk]).snapshot_err("Copy failed")?;
+                .map_err(Error::snapshot_io("Copy failed"))?;
+            dst.write_all(&buf[..chunk])
+                .map_err(Error::snapshot("Copy failed"))?;
 
             written += chunk;
             len -= chunk;
         }
     }
 
+    /// A reader of the next `len` bytes, e.g. a file's content.
+    pub fn take(&mut self, len: usize) -> Take<&mut R> {
+        Stats::current().unpacking().inc(len);
+        (&mut self.reader).take(len as u64)
+    }
+
     pub fn skip(&mut self, len: usize) -> Result<usize, Error> {
         Stats::current().unpacking().inc(len);
 
@@ -117,29 +162,31 @@ impl Write for Null {
 mod tests {
     use super::*;
 
+    use std::fs;
     use std::path::Path;
 
     use crate::hashing;
-    use crate::snapshot::{Entry, Writing};
+    use crate::snapshot::{Compression, Entry, Writing};
     use crate::testing::{self, B_FILE_PATH};
+    use crate::ErrorKind;
 
     #[test]
     fn read_file_entry() {
         let dst = testing::temp_file(".snappy");
 
         {
-            let mut snapshot = Writing::open(&dst).unwrap();
+            let mut snapshot = Writing::open(&dst, None).unwrap();
 
             let file_entry = Entry::try_from_path(B_FILE_PATH).unwrap();
             snapshot.write_entry(&file_entry).unwrap();
 
             let (path, _, _, len) = file_entry.as_file().unwrap();
             snapshot.write_file(&path, Some(len)).unwrap();
-            snapshot.flush().unwrap();
+            snapshot.finish().unwrap();
         }
 
         {
-            let mut snapshot = Reading::open(&dst).unwrap();
+            let mut snapshot = Reading::open(&dst, None).unwrap();
             let (file_entry, _) = snapshot.read_entry().unwrap().unwrap();
             let (path, _, md5, len) = file_entry.as_file().unwrap();
 
@@ -154,6 +201,119 @@ mod tests {
             assert_eq!(md5, actual);
 
             assert_eq!(snapshot.read_entry().unwrap().is_none(), true);
+            assert_eq!(snapshot.footer(), None);
+        }
+    }
+
+    #[test]
+    fn read_footer() {
+        let dst = testing::temp_file(".snappy");
+        let footer = Footer::now();
+
+        {
+            let mut snapshot = Writing::open(&dst, None).unwrap().footer(footer);
+            let dir_entry = Entry::try_from_path(testing::IS_DIR_PATH).unwrap();
+            snapshot.write_entry(&dir_entry).unwrap();
+            snapshot.finish().unwrap();
+        }
+
+        let mut snapshot = Reading::open(&dst, None).unwrap();
+        assert_eq!(snapshot.read_entry().unwrap().is_some(), true);
+        assert_eq!(snapshot.footer(), None);
+
+        assert_eq!(snapshot.read_entry().unwrap().is_none(), true);
+        assert_eq!(snapshot.footer(), Some(footer));
+    }
+
+    #[test]
+    fn read_uncompressed() {
+        let dst = testing::temp_file(".snappy");
+        let key = Key::from_hex(hex::encode([7; 32])).unwrap();
+
+        for key in &[None, Some(&key)] {
+            {
+                let mut snapshot = Writing::open_compressed(&dst, *key, Compression::None).unwrap();
+                let file_entry = Entry::try_from_path(B_FILE_PATH).unwrap();
+                snapshot.write_entry(&file_entry).unwrap();
+
+                let (path, _, _, len) = file_entry.as_file().unwrap();
+                snapshot.write_file(&path, Some(len)).unwrap();
+                snapshot.finish().unwrap();
+            }
+
+            if key.is_none() {
+                assert_eq!(&fs::read(&dst).unwrap()[..VERSION_LEN], VERSION);
+            }
+
+            let mut snapshot = Reading::open(&dst, *key).unwrap();
+            let (file_entry, _) = snapshot.read_entry().unwrap().unwrap();
+            let (_, _, md5, len) = file_entry.as_file().unwrap();
+
+            let mut buf = Vec::new();
+            snapshot.copy_to(&mut buf, len).unwrap();
+            assert_eq!(md5, hashing::md5::bytes(&buf));
+            assert_eq!(snapshot.read_entry().unwrap().is_none(), true);
+        }
+    }
+
+    #[test]
+    fn read_versions() {
+        let dst = testing::temp_file(".snappy");
+
+        {
+            let mut snapshot = Writing::open_compressed(&dst, None, Compression::None).unwrap();
+            let dir_entry = Entry::try_from_path(testing::IS_DIR_PATH).unwrap();
+            snapshot.write_entry(&dir_entry).unwrap();
+            snapshot.finish().unwrap();
+        }
+
+        let mut bytes = fs::read(&dst).unwrap();
+        let params = vec![
+            (VERSION_WITHOUT_FOOTER, true),
+            (&[0xA0, 0xF1, 0xB2, 0x03], false),
+        ];
+
+        for (version, expected) in params {
+            bytes[..VERSION_LEN].copy_from_slice(version);
+            fs::write(&dst, &bytes).unwrap();
+
+            match Reading::open(&dst, None) {
+                Ok(mut snapshot) => {
+                    assert!(expected, "{:?}", version);
+                    assert_eq!(snapshot.read_entry().unwrap().is_some(), true);
+                }
+                Err(err) => match err.kind() {
+                    ErrorKind::Snapshot(_) => assert!(!expected, "{:?}", version),
+                    other => panic!("{:?}", other),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn read_encrypted() {
+        let dst = testing::temp_file(".snappy");
+        let key = Key::from_hex(hex::encode([7; 32])).unwrap();
+        let wrong_key = Key::from_hex(hex::encode([8; 32])).unwrap();
+
+        {
+            let mut snapshot = Writing::open(&dst, Some(&key)).unwrap();
+            let file_entry = Entry::try_from_path(B_FILE_PATH).unwrap();
+            snapshot.write_entry(&file_entry).unwrap();
+            snapshot.finish().unwrap();
+        }
+
+        {
+            let mut snapshot = Reading::open(&dst, Some(&key)).unwrap();
+            let (file_entry, _) = snapshot.read_entry().unwrap().unwrap();
+            assert_eq!(file_entry.as_path(), Path::new(B_FILE_PATH));
+        }
+
+        for key in &[None, Some(&wrong_key)] {
+            match Reading::open(&dst, *key).map(|_| ()).unwrap_err().kind() {
+                ErrorKind::Encryption => {}
+                kind => panic!("expected encryption error, got {:?}", kind),
+            }
         }
     }
 }
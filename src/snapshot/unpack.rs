@@ -1,9 +1,12 @@
+use std::collections::HashSet;
 use std::fs::{self, OpenOptions};
 use std::io::Read;
 use std::os::unix::fs::{self as unix_fs, PermissionsExt};
 use std::path::{Path, PathBuf};
 
 use filetime::{self, FileTime};
+use glob::{MatchOptions, Pattern};
+use log::warn;
 
 use crate::errors::ResultExt;
 use crate::snapshot::{Attributes, Entry, Reading};
@@ -13,55 +16,145 @@ pub trait Unpack {
     fn unpack<P>(self, prefix: Option<PathBuf>, dirs: &[P]) -> Result<(Vec<Entry>, usize), Error>
     where
         P: AsRef<Path>;
+
+    /// Same as `unpack`, but unpacks entries accepted by `include`, creating missing parents.
+    fn unpack_with<F>(
+        self,
+        prefix: Option<PathBuf>,
+        include: F,
+    ) -> Result<(Vec<Entry>, usize), Error>
+    where
+        F: Fn(&Path) -> bool;
 }
 
 impl<R: Read> Unpack for Reading<R> {
-    fn unpack<P>(
+    fn unpack<P>(self, prefix: Option<PathBuf>, dirs: &[P]) -> Result<(Vec<Entry>, usize), Error>
+    where
+        P: AsRef<Path>,
+    {
+        self.unpack_with(prefix, |path| is_include(dirs, path))
+    }
+
+    /// A failed unpack, e.g. a download broken mid-stream, removes what it has unpacked so far.
+    fn unpack_with<F>(
         mut self,
         prefix: Option<PathBuf>,
-        dirs: &[P],
+        include: F,
     ) -> Result<(Vec<Entry>, usize), Error>
     where
-        P: AsRef<Path>,
+        F: Fn(&Path) -> bool,
     {
-        let prefixed = prefixed(prefix);
-        let mut read: usize = 0;
-        let mut entries = Vec::new();
+        let mut unpacked = Vec::new();
+        let res = unpack_entries(&mut self, prefix, include, &mut unpacked);
 
-        while let Some((entry, len)) = self.read_entry()? {
-            read += len;
+        if res.is_err() {
+            remove_unpacked(&unpacked);
+        }
+        res
+    }
+}
 
-            if !is_include(dirs, entry.as_ref()) {
-                if let Some((_, _, _, len)) = entry.as_file() {
-                    self.skip(len)?;
-                }
-                continue;
-            }
+/// Unpacks the included entries, collecting the created paths in `unpacked`, parents first.
+fn unpack_entries<R, F>(
+    snapshot: &mut Reading<R>,
+    prefix: Option<PathBuf>,
+    include: F,
+    unpacked: &mut Vec<PathBuf>,
+) -> Result<(Vec<Entry>, usize), Error>
+where
+    R: Read,
+    F: Fn(&Path) -> bool,
+{
+    let prefixed = prefixed(prefix);
+    let mut read: usize = 0;
+    let mut entries = Vec::new();
+    let mut created = HashSet::new();
 
-            if let Some((path, attr)) = entry.as_dir() {
-                let path = prefixed(path);
-                fs::create_dir_all(&path).io_err(&path)?;
-                restore_attributes(&path, &attr)?;
-            }
+    while let Some((entry, len)) = snapshot.read_entry()? {
+        read += len;
 
-            if let Some((path, target, _)) = entry.as_symlink() {
-                let path = prefixed(path);
-                unix_fs::symlink(&target, &path).io_err(&path)?;
-                // restore_attributes(&path, &attr) only for osx
+        if !include(entry.as_ref()) {
+            if let Some((_, _, _, len)) = entry.as_file() {
+                snapshot.skip(len)?;
             }
+            continue;
+        }
 
-            if let Some((path, attr, _, len)) = entry.as_file() {
-                let path = prefixed(path);
-                let len = unpack_file(&mut self, &path, len)?;
-                restore_attributes(&path, &attr)?;
+        if let Some((path, attr)) = entry.as_dir() {
+            let path = prefixed(path);
+            create_dir_all(&path, unpacked)?;
+            restore_attributes(&path, &attr)?;
+            created.insert(path);
+        }
 
-                read += len;
-            }
+        if let Some((path, target, _)) = entry.as_symlink() {
+            let path = prefixed(path);
+            create_parent(&path, &mut created, unpacked)?;
+            unix_fs::symlink(&target, &path).io_err(&path)?;
+            unpacked.push(path);
+            // restore_attributes(&path, &attr) only for osx
+        }
+
+        if let Some((path, attr, _, len)) = entry.as_file() {
+            let path = prefixed(path);
+            create_parent(&path, &mut created, unpacked)?;
+            unpacked.push(path.clone());
+            let len = unpack_file(snapshot, &path, len)?;
+            restore_attributes(&path, &attr)?;
 
-            entries.push(entry);
+            read += len;
         }
 
-        Ok((entries, read))
+        entries.push(entry);
+    }
+
+    Ok((entries, read))
+}
+
+/// Entries come after their directories, so a parent is missing only when its
+/// directory wasn't included.
+fn create_parent(
+    path: &Path,
+    created: &mut HashSet<PathBuf>,
+    unpacked: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        if !created.contains(parent) {
+            create_dir_all(parent, unpacked)?;
+            created.insert(parent.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Same as `fs::create_dir_all`, but remembers the directories which didn't exist.
+fn create_dir_all(path: &Path, unpacked: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let mut missing = path
+        .ancestors()
+        .take_while(|it| !it.as_os_str().is_empty() && fs::symlink_metadata(it).is_err())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+
+    fs::create_dir_all(path).io_err(path)?;
+
+    missing.reverse();
+    unpacked.append(&mut missing);
+    Ok(())
+}
+
+/// Removes the unpacked paths, children first. Directories which existed before
+/// the unpack, or got other files in the meantime, are kept.
+fn remove_unpacked(unpacked: &[PathBuf]) {
+    for path in unpacked.iter().rev() {
+        let res = match fs::symlink_metadata(path) {
+            Ok(meta) if meta.is_dir() => fs::remove_dir(path),
+            Ok(_) => fs::remove_file(path),
+            Err(_) => continue,
+        };
+
+        if let Err(err) = res {
+            warn!("Cannot remove the partially unpacked {:?}: {}", path, err);
+        }
     }
 }
 
@@ -81,7 +174,7 @@ where
     snapshot.copy_to(&mut file, len)
 }
 
-fn restore_attributes<P>(path: P, attr: &Attributes) -> Result<(), Error>
+pub fn restore_attributes<P>(path: P, attr: &Attributes) -> Result<(), Error>
 where
     P: AsRef<Path>,
 {
@@ -98,14 +191,29 @@ where
 }
 
 #[inline]
-fn is_include<P>(dirs: &[P], path: &Path) -> bool
+pub fn is_include<P>(dirs: &[P], path: &Path) -> bool
 where
     P: AsRef<Path>,
 {
     dirs.iter().any(|it| path.starts_with(it))
 }
 
-fn prefixed(prefix: Option<PathBuf>) -> impl Fn(&Path) -> PathBuf {
+/// Same as `is_include`, but a path is included when it or one of its parents
+/// matches any of the patterns.
+pub fn is_glob_include(patterns: &[Pattern], path: &Path) -> bool {
+    let opts = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+
+    path.ancestors().any(|it| {
+        patterns
+            .iter()
+            .any(|pattern| pattern.matches_path_with(it, opts))
+    })
+}
+
+pub fn prefixed(prefix: Option<PathBuf>) -> impl Fn(&Path) -> PathBuf {
     move |path| match prefix {
         Some(ref prefix) => {
             let path = if path.is_absolute() {
@@ -124,9 +232,9 @@ fn prefixed(prefix: Option<PathBuf>) -> impl Fn(&Path) -> PathBuf {
 mod tests {
     use super::*;
 
+    use std::io::Cursor;
     use std::os::unix::fs::MetadataExt;
 
-    use crate::snapshot::{Pack, Writing};
     use crate::testing::{self, A_FILE_PATH, FIXTURES_PATH, IS_BIN_PATH, IS_DIR_PATH};
 
     #[test]
@@ -140,6 +248,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn is_glob_include() {
+        let patterns = vec![
+            Pattern::new("/a/*.jar").unwrap(),
+            Pattern::new("**/generated").unwrap(),
+        ];
+        let params = vec![
+            ("/a/name.jar", true),
+            ("/a/b/name.jar", false),
+            ("/a/name.txt", false),
+            ("/c/generated/name.java", true),
+            ("/c/generated", true),
+            ("/c/other/name.java", false),
+        ];
+
+        for (path, expected) in params {
+            let path = PathBuf::from(path);
+            assert_eq!(
+                super::is_glob_include(&patterns, path.as_path()),
+                expected,
+                "{:?}",
+                path
+            );
+        }
+    }
+
     #[test]
     fn prefixed() {
         let params = vec![
@@ -165,12 +299,9 @@ mod tests {
         let dst = testing::temp_dir();
         let dirs = vec![Path::new(FIXTURES_PATH)];
 
-        let expected = {
-            let snapshot = Writing::open(&src).unwrap();
-            snapshot.pack(&dirs).unwrap()
-        };
+        let expected = testing::pack(&src, &dirs);
 
-        let snapshot = Reading::open(&src).unwrap();
+        let snapshot = Reading::open(&src, None).unwrap();
         let (_, actual) = snapshot
             .unpack(Some(dst.as_ref().to_path_buf()), &dirs)
             .unwrap();
@@ -178,16 +309,34 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn unpack_failure_removes_unpacked() {
+        let src = testing::temp_file(".snappy");
+        let dst = testing::temp_dir();
+        let dirs = vec![Path::new(FIXTURES_PATH)];
+
+        testing::pack(&src, &dirs);
+
+        // a download broken in the middle of the snapshot
+        let mut bytes = fs::read(&src).unwrap();
+        bytes.truncate(bytes.len() - 16);
+
+        let snapshot = Reading::from(Cursor::new(bytes), None).unwrap();
+        let res = snapshot.unpack(Some(dst.as_ref().to_path_buf()), &dirs);
+
+        assert!(res.is_err());
+        assert_eq!(fs::read_dir(dst.as_ref()).unwrap().count(), 0);
+    }
+
     #[test]
     fn unpack_restore_permissions() {
         let src = testing::temp_file(".snappy");
         let dst = testing::temp_dir();
         let dirs = vec![Path::new(FIXTURES_PATH)];
 
-        let snapshot = Writing::open(&src).unwrap();
-        snapshot.pack(&dirs).unwrap();
+        testing::pack(&src, &dirs);
 
-        let snapshot = Reading::open(&src).unwrap();
+        let snapshot = Reading::open(&src, None).unwrap();
         snapshot
             .unpack(Some(dst.as_ref().to_path_buf()), &dirs)
             .unwrap();
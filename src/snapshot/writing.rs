@@ -3,24 +3,47 @@ use std::io::Write;
 use std::path::Path;
 
 use crate::bytes::IntoLeBytes;
+use crate::crypto::{Key, Sealing};
 use crate::errors::ResultExt;
-use crate::snapshot::{Entry, BUFFER_SIZE, VERSION};
+use crate::snapshot::{
+    Compressed, Compression, Entry, Footer, BUFFER_SIZE, FOOTER_MARKER, VERSION,
+};
 use crate::{mmap, Error, Stats};
 
 #[derive(Debug)]
 pub struct Writing<W = ()> {
     writer: W,
+    footer: Option<Footer>,
 }
 
 impl Writing {
-    pub fn from<W: Write>(writer: W) -> Result<Writing<snap::Writer<W>>, Error> {
-        let writer = snap::Writer::new(writer);
-        let mut writer = Writing { writer };
+    pub fn from<W: Write>(
+        writer: W,
+        key: Option<&Key>,
+        compression: Compression,
+    ) -> Result<Writing<Compressed<W>>, Error> {
+        let writer = Compressed::new(Sealing::new(writer, key)?, compression);
+        let mut writer = Writing {
+            writer,
+            footer: None,
+        };
 
         writer.write_version().map(|_| writer)
     }
 
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Writing<snap::Writer<File>>, Error> {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        key: Option<&Key>,
+    ) -> Result<Writing<Compressed<File>>, Error> {
+        Writing::open_compressed(path, key, Compression::default())
+    }
+
+    /// Same as `open`, but with the given compression.
+    pub fn open_compressed<P: AsRef<Path>>(
+        path: P,
+        key: Option<&Key>,
+        compression: Compression,
+    ) -> Result<Writing<Compressed<File>>, Error> {
         let file = OpenOptions::new()
             .create(true)
             .truncate(true)
@@ -28,11 +51,29 @@ impl Writing {
             .open(&path)
             .io_err(&path)?;
 
-        Writing::from(file)
+        Writing::from(file, key, compression)
+    }
+}
+
+impl<W: Write> Writing<Compressed<W>> {
+    pub fn finish(mut self) -> Result<W, Error> {
+        if let Some(footer) = self.footer.take() {
+            self.write_footer(&footer)?;
+        }
+
+        self.writer.into_inner()?.finish()
     }
 }
 
 impl<W: Write> Writing<W> {
+    /// A footer to write when the snapshot is finished.
+    pub fn footer(self, footer: Footer) -> Self {
+        Writing {
+            footer: Some(footer),
+            ..self
+        }
+    }
+
     fn write_version(&mut self) -> Result<(), Error> {
         Stats::current().packing().inc(VERSION.len());
 
@@ -41,10 +82,6 @@ impl<W: Write> Writing<W> {
             .snapshot_err("Write version header failed")
     }
 
-    pub fn flush(&mut self) -> Result<(), Error> {
-        self.writer.flush().snapshot_err("Flush failed")
-    }
-
     pub fn write_entry(&mut self, entry: &Entry) -> Result<usize, Error> {
         let meta = serde_cbor::to_vec(entry).snapshot_err("Create metadata failed")?;
         let mut written: usize = 0;
@@ -70,6 +107,21 @@ impl<W: Write> Writing<W> {
         Ok(written)
     }
 
+    fn write_footer(&mut self, footer: &Footer) -> Result<usize, Error> {
+        let meta = serde_cbor::to_vec(footer).snapshot_err("Create footer failed")?;
+
+        let mut bytes = FOOTER_MARKER.into_le_bytes().to_vec();
+        bytes.extend_from_slice(&(meta.len() as u32).into_le_bytes());
+        bytes.extend_from_slice(&meta);
+
+        self.writer
+            .write_all(&bytes)
+            .snapshot_err("Write footer failed")?;
+
+        Stats::current().packing().inc(bytes.len());
+        Ok(bytes.len())
+    }
+
     pub fn write_file<P>(&mut self, path: P, len: Option<usize>) -> Result<usize, Error>
     where
         P: AsRef<Path>,
@@ -101,7 +153,7 @@ mod tests {
     #[test]
     fn write_file_entry() {
         let dst = testing::temp_file(".sn");
-        let mut snapshot = Writing::open(&dst).unwrap();
+        let mut snapshot = Writing::open(&dst, None).unwrap();
 
         let file_entry = Entry::try_from_path(B_FILE_PATH).unwrap();
         assert_eq!(file_entry.as_file().is_some(), true);
@@ -113,6 +165,6 @@ mod tests {
         let written = snapshot.write_file(&path, Some(len)).unwrap();
         assert_eq!(written, 82944);
 
-        snapshot.flush().unwrap();
+        snapshot.finish().unwrap();
     }
 }
@@ -117,6 +117,9 @@ pub struct Stats {
     walking: Counter,
     download: Counter,
     upload: Counter,
+    hits: Counter,
+    misses: Counter,
+    snapshot: Counter,
 }
 
 impl Stats {
@@ -154,6 +157,24 @@ impl Stats {
     pub fn upload(&self) -> &Counter {
         &self.upload
     }
+
+    /// Pulls which unpacked a snapshot.
+    #[inline]
+    pub fn hits(&self) -> &Counter {
+        &self.hits
+    }
+
+    /// Pulls which found no usable snapshot.
+    #[inline]
+    pub fn misses(&self) -> &Counter {
+        &self.misses
+    }
+
+    /// Bytes of pushed snapshots.
+    #[inline]
+    pub fn snapshot(&self) -> &Counter {
+        &self.snapshot
+    }
 }
 
 impl Display for Stats {
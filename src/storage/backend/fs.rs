@@ -0,0 +1,456 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, SyncSender};
+
+use log::info;
+use url::Url;
+use walkdir::WalkDir;
+
+use crate::errors::ResultExt;
+use crate::pretty;
+use crate::storage::backend::{
+    cancelled, Backend, DownloadRequest, Metadata, Object, Part, UploadRequest,
+};
+use crate::Error;
+
+const FS_URI_SCHEME: &str = "file";
+const BUFFER_SIZE: usize = 1024 * 1024; // 1mb
+
+/// Keeps objects in a local (or mounted) directory, the key is a relative path inside it.
+#[derive(Debug)]
+pub struct Fs {
+    root: PathBuf,
+}
+
+impl Fs {
+    pub fn from(uri: &Url) -> Result<Self, Error> {
+        let root = uri.to_file_path().map_err(|_| {
+            let err = format!("Unrecognized directory '{}'", uri);
+            Error::storage(err)
+        })?;
+
+        Ok(Fs { root })
+    }
+
+    pub fn scheme() -> &'static str {
+        FS_URI_SCHEME
+    }
+
+    fn object_path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl Backend for Fs {
+    fn download(&self, req: DownloadRequest) -> Result<usize, Error> {
+        let src = self.object_path(&req.key);
+
+        info!("Attempting to copy archive from {:?}", src.as_os_str());
+
+        let len = fs::copy(&src, &req.path).io_err(&src)? as usize;
+
+        info!("Archive copied: {}", pretty::bytes(len));
+
+        Ok(len)
+    }
+
+    fn upload(&self, req: UploadRequest) -> Result<usize, Error> {
+        let dst = self.object_path(&req.key);
+
+        info!("Attempting to copy archive to {:?}", dst.as_os_str());
+
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).io_err(parent)?;
+        }
+
+        // copy under a temporary name first, so readers never see a partial object
+        let tmp = temp_path(&dst);
+        fs::copy(&req.path, &tmp).io_err(&tmp)?;
+        write_metadata(&dst, &req.metadata)?;
+        fs::rename(&tmp, &dst).io_err(&dst)?;
+
+        info!("Archive copied: {}", pretty::bytes(req.len));
+
+        Ok(req.len)
+    }
+
+    fn upload_parts(
+        &self,
+        key: &str,
+        metadata: &Metadata,
+        parts: Receiver<Part>,
+    ) -> Result<usize, Error> {
+        let dst = self.object_path(key);
+
+        info!("Attempting to copy archive to {:?}", dst.as_os_str());
+
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).io_err(parent)?;
+        }
+
+        let tmp = temp_path(&dst);
+        let mut file = File::create(&tmp).io_err(&tmp)?;
+        let mut len = 0;
+
+        loop {
+            match parts.recv() {
+                Ok(Part::Data(buf)) => {
+                    file.write_all(&buf).io_err(&tmp)?;
+                    len += buf.len();
+                }
+                Ok(Part::End) => break,
+                Err(err) => {
+                    fs::remove_file(&tmp).io_err(&tmp)?;
+                    return Err(cancelled(err));
+                }
+            }
+        }
+
+        write_metadata(&dst, metadata)?;
+        fs::rename(&tmp, &dst).io_err(&dst)?;
+
+        info!("Archive copied: {}", pretty::bytes(len));
+
+        Ok(len)
+    }
+
+    fn download_parts(&self, key: &str, parts: SyncSender<Part>) -> Result<usize, Error> {
+        let src = self.object_path(key);
+
+        info!("Attempting to copy archive from {:?}", src.as_os_str());
+
+        let mut file = File::open(&src).io_err(&src)?;
+        let mut len = 0;
+
+        loop {
+            let mut buf = vec![0; BUFFER_SIZE];
+            let read = file.read(&mut buf).io_err(&src)?;
+            if read == 0 {
+                break;
+            }
+
+            buf.truncate(read);
+            parts.send(Part::Data(buf)).map_err(cancelled)?;
+            len += read;
+        }
+
+        parts.send(Part::End).map_err(cancelled)?;
+
+        info!("Archive copied: {}", pretty::bytes(len));
+
+        Ok(len)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.object_path(key).is_file())
+    }
+
+    fn etag(&self, key: &str) -> Result<Option<String>, Error> {
+        let path = self.object_path(key);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        // an upload renames a new file over the object, so its inode changes as well
+        let meta = path.metadata().io_err(&path)?;
+        let etag = format!(
+            "{:x}-{:x}.{:x}-{:x}",
+            meta.ino(),
+            meta.mtime(),
+            meta.mtime_nsec(),
+            meta.len()
+        );
+        Ok(Some(etag))
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<Metadata>, Error> {
+        let path = self.object_path(key);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let meta_path = meta_path(&path);
+        if !meta_path.exists() {
+            return Ok(Some(Metadata::new()));
+        }
+
+        let file = File::open(&meta_path).io_err(&meta_path)?;
+        let metadata = serde_json::from_reader(&file).io_err(&meta_path)?;
+        Ok(Some(metadata))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<Object>, Error> {
+        // a prefix isn't always a directory, e.g. 'project/branch-'
+        let dir = self.object_path(prefix);
+        let dir = if prefix.is_empty() || prefix.ends_with('/') {
+            dir
+        } else {
+            dir.parent().map(Path::to_path_buf).unwrap_or(dir)
+        };
+
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut objects = Vec::new();
+
+        for item in WalkDir::new(&dir) {
+            let item = item.io_err(&dir)?;
+            if !item.file_type().is_file() {
+                continue;
+            }
+
+            let path = item.path();
+            let key = match path.strip_prefix(&self.root) {
+                Ok(val) => val.to_string_lossy().to_string(),
+                Err(_) => continue,
+            };
+
+            if !key.starts_with(prefix) || key.ends_with(".tmp") || key.ends_with(".meta") {
+                continue;
+            }
+
+            let meta = item.metadata().io_err(path)?;
+            objects.push(Object {
+                key,
+                len: meta.len() as usize,
+                modified: meta.mtime(),
+            });
+        }
+
+        Ok(objects)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        let path = self.object_path(key);
+        fs::remove_file(&path).io_err(&path)?;
+
+        let meta_path = meta_path(&path);
+        if meta_path.exists() {
+            fs::remove_file(&meta_path).io_err(&meta_path)?;
+        }
+
+        // leave no empty directories behind, as there are none in a bucket
+        let mut dir = path.parent();
+        while let Some(val) = dir {
+            if val == self.root || fs::remove_dir(val).is_err() {
+                break;
+            }
+            dir = val.parent();
+        }
+
+        Ok(())
+    }
+}
+
+impl ToString for Fs {
+    fn to_string(&self) -> String {
+        format!("{}://{}", FS_URI_SCHEME, self.root.display())
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Metadata is kept next to an object, as a file system has no place for it.
+fn meta_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".meta");
+    path.with_file_name(name)
+}
+
+/// Replaces metadata of the object, written before the object itself is.
+fn write_metadata(path: &Path, metadata: &Metadata) -> Result<(), Error> {
+    let meta_path = meta_path(path);
+
+    if metadata.is_empty() {
+        if meta_path.exists() {
+            fs::remove_file(&meta_path).io_err(&meta_path)?;
+        }
+        return Ok(());
+    }
+
+    let tmp = temp_path(&meta_path);
+    let file = File::create(&tmp).io_err(&tmp)?;
+    serde_json::to_writer(&file, metadata).io_err(&tmp)?;
+    fs::rename(&tmp, &meta_path).io_err(&meta_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use crate::hashing;
+    use crate::testing::{temp_dir, temp_file, A_FILE_PATH, B_FILE_PATH};
+
+    #[test]
+    fn upload_and_download() {
+        let root = temp_dir();
+        let uri = Url::from_directory_path(root.as_ref()).unwrap();
+        let backend = Fs::from(&uri).unwrap();
+        let dst = temp_file(".fs");
+        let len = { File::open(&B_FILE_PATH).unwrap().metadata().unwrap().len() as usize };
+
+        assert_eq!(backend.exists("prefix/file").unwrap(), false);
+
+        let upload = UploadRequest {
+            path: B_FILE_PATH.into(),
+            len,
+            key: "prefix/file".into(),
+            metadata: Metadata::new(),
+        };
+        backend.upload(upload).unwrap();
+
+        assert_eq!(backend.exists("prefix/file").unwrap(), true);
+
+        let download = DownloadRequest {
+            path: dst.as_ref().to_path_buf(),
+            key: "prefix/file".into(),
+        };
+        assert_eq!(backend.download(download).unwrap(), len);
+
+        let expected = hashing::md5::path(&B_FILE_PATH).unwrap();
+        let actual = hashing::md5::path(&dst).unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn upload_parts() {
+        let root = temp_dir();
+        let uri = Url::from_directory_path(root.as_ref()).unwrap();
+        let backend = Fs::from(&uri).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        sender.send(Part::Data(b"first ".to_vec())).unwrap();
+        sender.send(Part::Data(b"second".to_vec())).unwrap();
+        sender.send(Part::End).unwrap();
+
+        let metadata = Metadata::new();
+        assert_eq!(
+            backend.upload_parts("file", &metadata, receiver).unwrap(),
+            12
+        );
+        assert_eq!(
+            fs::read(root.as_ref().join("file")).unwrap(),
+            b"first second"
+        );
+
+        let (sender, receiver) = mpsc::channel();
+        sender.send(Part::Data(b"partial".to_vec())).unwrap();
+        drop(sender);
+
+        assert!(backend
+            .upload_parts("cancelled", &metadata, receiver)
+            .is_err());
+        assert_eq!(backend.exists("cancelled").unwrap(), false);
+        assert_eq!(root.as_ref().join("cancelled.tmp").exists(), false);
+    }
+
+    #[test]
+    fn list_and_delete() {
+        let root = temp_dir();
+        let uri = Url::from_directory_path(root.as_ref()).unwrap();
+        let backend = Fs::from(&uri).unwrap();
+
+        for key in &["project/a/file", "project/b/file", "other/file"] {
+            let upload = UploadRequest {
+                path: A_FILE_PATH.into(),
+                len: 1,
+                key: key.to_string(),
+                metadata: Metadata::new(),
+            };
+            backend.upload(upload).unwrap();
+        }
+
+        let keys = |prefix| {
+            let mut keys = backend
+                .list(prefix)
+                .unwrap()
+                .into_iter()
+                .map(|it| it.key)
+                .collect::<Vec<_>>();
+            keys.sort();
+            keys
+        };
+
+        assert_eq!(
+            keys(""),
+            vec!["other/file", "project/a/file", "project/b/file"]
+        );
+        assert_eq!(keys("project/"), vec!["project/a/file", "project/b/file"]);
+        assert_eq!(keys("project/a"), vec!["project/a/file"]);
+        assert_eq!(keys("missing/"), Vec::<String>::new());
+
+        backend.delete("project/a/file").unwrap();
+
+        assert_eq!(keys("project/"), vec!["project/b/file"]);
+        assert_eq!(root.as_ref().join("project/a").exists(), false);
+        assert_eq!(root.as_ref().join("project").exists(), true);
+    }
+
+    #[test]
+    fn etag_changes_on_upload() {
+        let root = temp_dir();
+        let uri = Url::from_directory_path(root.as_ref()).unwrap();
+        let backend = Fs::from(&uri).unwrap();
+        let upload = || UploadRequest {
+            path: A_FILE_PATH.into(),
+            len: 0,
+            key: "prefix/file".into(),
+            metadata: Metadata::new(),
+        };
+
+        assert_eq!(backend.etag("prefix/file").unwrap(), None);
+
+        backend.upload(upload()).unwrap();
+        let etag = backend.etag("prefix/file").unwrap();
+        assert!(etag.is_some());
+        assert_eq!(backend.etag("prefix/file").unwrap(), etag);
+
+        backend.upload(upload()).unwrap();
+        assert_ne!(backend.etag("prefix/file").unwrap(), etag);
+    }
+
+    #[test]
+    fn upload_with_metadata() {
+        let root = temp_dir();
+        let uri = Url::from_directory_path(root.as_ref()).unwrap();
+        let backend = Fs::from(&uri).unwrap();
+        let upload = |metadata: &Metadata| UploadRequest {
+            path: A_FILE_PATH.into(),
+            len: 0,
+            key: "prefix/file".into(),
+            metadata: metadata.clone(),
+        };
+
+        assert_eq!(backend.metadata("prefix/file").unwrap(), None);
+
+        let mut metadata = Metadata::new();
+        metadata.insert("generation".into(), "1".into());
+        backend.upload(upload(&metadata)).unwrap();
+
+        assert_eq!(
+            backend.metadata("prefix/file").unwrap(),
+            Some(metadata.clone())
+        );
+        assert_eq!(backend.list("prefix/").unwrap().len(), 1);
+
+        backend.upload(upload(&Metadata::new())).unwrap();
+        assert_eq!(
+            backend.metadata("prefix/file").unwrap(),
+            Some(Metadata::new())
+        );
+
+        backend.upload(upload(&metadata)).unwrap();
+        backend.delete("prefix/file").unwrap();
+        assert_eq!(root.as_ref().join("prefix").exists(), false);
+    }
+}
@@ -1,8 +1,12 @@
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, SyncSender};
 
+mod fs;
 mod s3;
 
+pub use self::fs::Fs;
 pub use self::s3::S3;
 use crate::Error;
 
@@ -17,9 +21,49 @@ pub struct UploadRequest {
     pub path: PathBuf,
     pub len: usize,
     pub key: String,
+    pub metadata: Metadata,
 }
 
-pub trait Backend: Debug {
+/// User-defined metadata stored along with an object.
+pub type Metadata = BTreeMap<String, String>;
+
+/// An object in a remote location, `modified` is in seconds since the epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub key: String,
+    pub len: usize,
+    pub modified: i64,
+}
+
+pub const PART_SIZE: usize = 1024 * 1024 * 10; // 10mb
+
+/// A piece of a streamed transfer, the other side is gone without `End` when it's cancelled.
+#[derive(Debug)]
+pub enum Part {
+    Data(Vec<u8>),
+    End,
+}
+
+fn cancelled<T>(_: T) -> Error {
+    Error::storage("Transfer is cancelled")
+}
+
+pub trait Backend: Debug + Send + Sync {
     fn download(&self, req: DownloadRequest) -> Result<usize, Error>;
     fn upload(&self, req: UploadRequest) -> Result<usize, Error>;
+    fn upload_parts(
+        &self,
+        key: &str,
+        metadata: &Metadata,
+        parts: Receiver<Part>,
+    ) -> Result<usize, Error>;
+    fn download_parts(&self, key: &str, parts: SyncSender<Part>) -> Result<usize, Error>;
+    fn exists(&self, key: &str) -> Result<bool, Error>;
+    /// A tag which changes whenever the object is replaced, `None` when it doesn't exist.
+    fn etag(&self, key: &str) -> Result<Option<String>, Error>;
+    /// Metadata the object was uploaded with, `None` when it doesn't exist.
+    fn metadata(&self, key: &str) -> Result<Option<Metadata>, Error>;
+    /// Objects which keys start with the prefix, in no particular order.
+    fn list(&self, prefix: &str) -> Result<Vec<Object>, Error>;
+    fn delete(&self, key: &str) -> Result<(), Error>;
 }
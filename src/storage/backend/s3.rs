@@ -1,24 +1,27 @@
+use std::collections::{HashMap, VecDeque};
 use std::io::{Cursor, Write};
 use std::str::FromStr;
 use std::string::ToString;
+use std::sync::mpsc::{Receiver, SyncSender};
 
 use futures::stream::{iter_ok, Stream};
 use futures::Future;
-use log::info;
-use rusoto_core::Region;
+use log::{info, warn};
+use rusoto_core::{Region, RusotoError};
 use rusoto_s3::{self as s3_api, S3Client, S3 as S3Api};
 use url::{Host, Url};
 
 use crate::errors::ResultExt;
 use crate::pretty;
-use crate::storage::backend::{Backend, DownloadRequest, UploadRequest};
+use crate::storage::backend::{
+    cancelled, Backend, DownloadRequest, Metadata, Object, Part, UploadRequest, PART_SIZE,
+};
 use crate::storage::futures_ext::FuturesExt;
 use crate::{mmap, Error};
 
 const S3_URI_SCHEME: &str = "s3";
 const REGION_QUERY_KEY: &str = "region";
 const ENDPOINT_QUERY_KEY: &str = "endpoint";
-const CHUNK_SIZE: usize = 1024 * 1024 * 10; // 10mb
 const CONCURRENCY: usize = 10;
 
 #[derive(Debug)]
@@ -84,6 +87,78 @@ impl S3 {
         S3_URI_SCHEME
     }
 
+    fn send_parts(
+        &self,
+        client: &S3Client,
+        key: &str,
+        upload_id: &str,
+        parts: Receiver<Part>,
+    ) -> Result<(Vec<s3_api::CompletedPart>, usize), Error> {
+        let mut pending = VecDeque::new();
+        let mut completed = Vec::new();
+        let mut len = 0;
+
+        loop {
+            let body = match parts.recv() {
+                Ok(Part::Data(body)) => body,
+                Ok(Part::End) => break,
+                Err(err) => return Err(cancelled(err)),
+            };
+
+            len += body.len();
+
+            let part_number = (completed.len() + pending.len() + 1) as i64;
+            let part = s3_api::UploadPartRequest {
+                body: Some(body.into()),
+                bucket: self.bucket_name.clone(),
+                key: key.to_string(),
+                upload_id: upload_id.to_string(),
+                part_number,
+                ..Default::default()
+            };
+
+            let part = client
+                .upload_part(part)
+                .map(move |res| s3_api::CompletedPart {
+                    e_tag: res.e_tag.clone(),
+                    part_number: Some(part_number),
+                })
+                .map_err(Error::storage)
+                .spawned();
+
+            pending.push_back(part);
+
+            // keeps memory bounded, a slow network slows down packing
+            if pending.len() >= CONCURRENCY {
+                completed.push(pending.pop_front().unwrap().wait()?);
+            }
+        }
+
+        for part in pending {
+            completed.push(part.wait()?);
+        }
+
+        Ok((completed, len))
+    }
+
+    /// An object's head, `None` when there is no such object.
+    fn head(&self, key: &str) -> Result<Option<s3_api::HeadObjectOutput>, Error> {
+        let client = S3Client::new(self.region.clone());
+
+        let head_object = s3_api::HeadObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key: self.key_prefixed(key),
+            ..Default::default()
+        };
+
+        match client.head_object(head_object).sync() {
+            Ok(res) => Ok(Some(res)),
+            Err(RusotoError::Service(s3_api::HeadObjectError::NoSuchKey(_))) => Ok(None),
+            Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => Ok(None),
+            Err(err) => Err(Error::storage(err)),
+        }
+    }
+
     fn key_prefixed<S>(&self, key: S) -> String
     where
         S: AsRef<str>,
@@ -154,6 +229,7 @@ impl Backend for S3 {
         let upload = s3_api::CreateMultipartUploadRequest {
             bucket: self.bucket_name.clone(),
             key: key.clone(),
+            metadata: user_metadata(&req.metadata),
             ..Default::default()
         };
 
@@ -169,7 +245,7 @@ impl Backend for S3 {
         let (_, len, src) = mmap::read(&req.path, None)?;
 
         let parts = src
-            .chunks(CHUNK_SIZE)
+            .chunks(PART_SIZE)
             .enumerate()
             .map(|(part_number, chunk)| {
                 let part_number = (part_number + 1) as i64;
@@ -214,6 +290,212 @@ impl Backend for S3 {
 
         Ok(len)
     }
+
+    fn upload_parts(
+        &self,
+        key: &str,
+        metadata: &Metadata,
+        parts: Receiver<Part>,
+    ) -> Result<usize, Error> {
+        let client = S3Client::new(self.region.clone());
+        let key = self.key_prefixed(key);
+
+        info!(
+            "Attempting to upload archive to s3://{}/{}",
+            self.bucket_name, key
+        );
+
+        let upload = s3_api::CreateMultipartUploadRequest {
+            bucket: self.bucket_name.clone(),
+            key: key.clone(),
+            metadata: user_metadata(metadata),
+            ..Default::default()
+        };
+
+        let upload = client
+            .create_multipart_upload(upload)
+            .map_err(Error::storage)
+            .sync()?;
+
+        let upload_id = upload
+            .upload_id
+            .ok_or_else(|| Error::storage("upload_id cannot be empty"))?;
+
+        let (parts, len) = match self.send_parts(&client, &key, &upload_id, parts) {
+            Ok(val) => val,
+            Err(err) => {
+                let abort = s3_api::AbortMultipartUploadRequest {
+                    bucket: self.bucket_name.clone(),
+                    key: key.clone(),
+                    upload_id,
+                    ..Default::default()
+                };
+
+                if let Err(err) = client.abort_multipart_upload(abort).sync() {
+                    warn!("Abort upload failed; {}", err);
+                }
+                return Err(err);
+            }
+        };
+
+        let complete = s3_api::CompleteMultipartUploadRequest {
+            bucket: self.bucket_name.clone(),
+            key: key.clone(),
+            upload_id,
+            multipart_upload: Some(s3_api::CompletedMultipartUpload { parts: Some(parts) }),
+            ..Default::default()
+        };
+
+        client
+            .complete_multipart_upload(complete)
+            .map_err(Error::storage)
+            .sync()?;
+
+        info!("Archive uploaded: {}", pretty::bytes(len));
+
+        Ok(len)
+    }
+
+    fn download_parts(&self, key: &str, parts: SyncSender<Part>) -> Result<usize, Error> {
+        let client = S3Client::new(self.region.clone());
+        let key = self.key_prefixed(key);
+
+        info!(
+            "Attempting to download archive from s3://{}/{}",
+            self.bucket_name, key
+        );
+
+        let get_object = s3_api::GetObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key: key.clone(),
+            ..Default::default()
+        };
+
+        let resp = client
+            .get_object(get_object)
+            .map_err(Error::storage)
+            .sync()?;
+
+        let body = resp.body.ok_or_else(|| Error::storage("body must be"))?;
+        let mut len = 0;
+
+        // a dropped receiver stops reading the body, which closes the connection
+        for chunk in body.wait() {
+            let chunk = chunk.map_err(Error::storage)?;
+            len += chunk.len();
+            parts.send(Part::Data(chunk.to_vec())).map_err(cancelled)?;
+        }
+
+        parts.send(Part::End).map_err(cancelled)?;
+
+        info!("Archive downloaded: {}", pretty::bytes(len));
+
+        Ok(len)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.head(key)?.is_some())
+    }
+
+    fn etag(&self, key: &str) -> Result<Option<String>, Error> {
+        let head = self.head(key)?;
+        Ok(head.map(|it| it.e_tag.unwrap_or_default()))
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<Metadata>, Error> {
+        let head = self.head(key)?;
+        Ok(head.map(|it| it.metadata.unwrap_or_default().into_iter().collect()))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<Object>, Error> {
+        let client = S3Client::new(self.region.clone());
+        let key_prefix = self.key_prefixed("");
+        let mut objects = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let list_objects = s3_api::ListObjectsV2Request {
+                bucket: self.bucket_name.clone(),
+                prefix: Some(self.key_prefixed(prefix)),
+                continuation_token: continuation_token.take(),
+                ..Default::default()
+            };
+
+            let res = client
+                .list_objects_v2(list_objects)
+                .sync()
+                .map_err(Error::storage)?;
+
+            for it in res.contents.unwrap_or_default() {
+                let key = match it.key {
+                    Some(val) if val.starts_with(&key_prefix) => {
+                        val[key_prefix.len()..].to_string()
+                    }
+                    _ => continue,
+                };
+
+                objects.push(Object {
+                    key,
+                    len: it.size.unwrap_or(0) as usize,
+                    modified: it
+                        .last_modified
+                        .as_ref()
+                        .and_then(|it| parse_timestamp(it))
+                        .unwrap_or(0),
+                });
+            }
+
+            match res.next_continuation_token {
+                Some(token) if res.is_truncated == Some(true) => continuation_token = Some(token),
+                _ => break,
+            }
+        }
+
+        Ok(objects)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        let client = S3Client::new(self.region.clone());
+
+        let delete_object = s3_api::DeleteObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key: self.key_prefixed(key),
+            ..Default::default()
+        };
+
+        client
+            .delete_object(delete_object)
+            .sync()
+            .map(|_| ())
+            .map_err(Error::storage)
+    }
+}
+
+/// Sent as `x-amz-meta-*` headers, S3 keeps their names lowercase.
+fn user_metadata(metadata: &Metadata) -> Option<HashMap<String, String>> {
+    if metadata.is_empty() {
+        None
+    } else {
+        Some(metadata.clone().into_iter().collect())
+    }
+}
+
+/// Parses a timestamp like '2019-10-12T17:50:30.000Z' into seconds since the epoch.
+fn parse_timestamp(value: &str) -> Option<i64> {
+    let field = |from: usize, to: usize| value.get(from..to)?.parse::<i64>().ok();
+
+    let (year, month, day) = (field(0, 4)?, field(5, 7)?, field(8, 10)?);
+    let (hour, min, sec) = (field(11, 13)?, field(14, 16)?, field(17, 19)?);
+
+    // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Some(days * 86_400 + hour * 3_600 + min * 60 + sec)
 }
 
 impl ToString for S3 {
@@ -286,6 +568,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_timestamps() {
+        let params = vec![
+            ("2019-10-12T17:50:30.000Z", Some(1_570_902_630)),
+            ("2000-02-29T00:00:00.000Z", Some(951_782_400)),
+            ("1970-01-01T00:00:00Z", Some(0)),
+            ("yesterday", None),
+        ];
+
+        for (value, expected) in params {
+            assert_eq!(parse_timestamp(value), expected, "{}", value);
+        }
+    }
+
     #[test]
     fn upload() {
         let endpoint = match env::var("S3_ENDPOINT") {
@@ -303,6 +599,7 @@ mod tests {
             path: B_FILE_PATH.into(),
             len,
             key: "file".into(),
+            metadata: Metadata::new(),
         };
 
         s3.upload(upload).unwrap();
@@ -1,4 +1,4 @@
-use futures::sync::oneshot::spawn;
+use futures::sync::oneshot::{spawn, SpawnHandle};
 use futures::Future;
 use lazy_static::lazy_static;
 use tokio::runtime::Runtime;
@@ -14,7 +14,12 @@ where
     Self::Error: Send,
 {
     fn sync(self) -> Result<Self::Item, Self::Error> {
-        spawn(self, &RUNTIME.executor()).wait()
+        self.spawned().wait()
+    }
+
+    /// Starts the future in background, it's cancelled when the handle is dropped.
+    fn spawned(self) -> SpawnHandle<Self::Item, Self::Error> {
+        spawn(self, &RUNTIME.executor())
     }
 }
 
@@ -1,22 +1,92 @@
+use std::fmt::{self, Display};
 use std::fs::{File, OpenOptions};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
 use serde_json::{self, json, Value};
 use url::Url;
 
 use crate::errors::ResultExt;
+use crate::snapshot::Compression;
 use crate::{Config, Error, Stats};
 
 mod backend;
 mod futures_ext;
+mod stream;
+
+pub use self::backend::Object;
+pub use self::stream::{Download, Upload};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// The whole snapshot in a single archive.
+    Archive,
+    /// Files split into content defined chunks, shared between keys, plus a manifest per key.
+    Chunks,
+    /// An archive per cached directory, only changed ones are uploaded.
+    Split,
+    /// A base archive plus archives of changes since it, compacted from time to time.
+    Delta,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Archive
+    }
+}
+
+impl Layout {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Layout::Archive => "archive",
+            Layout::Chunks => "chunks",
+            Layout::Split => "split",
+            Layout::Delta => "delta",
+        }
+    }
+
+    pub fn variants() -> &'static [&'static str] {
+        &["archive", "chunks", "split", "delta"]
+    }
+}
+
+impl FromStr for Layout {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "archive" => Ok(Layout::Archive),
+            "chunks" => Ok(Layout::Chunks),
+            "split" => Ok(Layout::Split),
+            "delta" => Ok(Layout::Delta),
+            _ => {
+                let err = format!("Unknown layout '{}'", s);
+                Err(Error::storage(err))
+            }
+        }
+    }
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.as_str())
+    }
+}
 
-#[derive(Debug, Default)]
+const GENERATION_METADATA_KEY: &str = "generation";
+
+#[derive(Debug, Clone, Default)]
 pub struct Storage {
-    backend: Option<Box<dyn backend::Backend>>,
+    backend: Option<Arc<dyn backend::Backend>>,
     uri: Option<String>,
+    project: Option<String>,
     key_prefix: Option<String>,
     path: PathBuf,
     uploadable: bool,
+    layout: Layout,
+    compression: Compression,
+    generation: Option<u64>,
 }
 
 impl Storage {
@@ -33,20 +103,20 @@ impl Storage {
     {
         let uri = Url::parse(uri.as_ref()).map_err(Error::storage)?;
 
-        if uri.scheme() == backend::S3::scheme() {
-            let s3 = backend::S3::from(&uri)?;
-            let backend: Option<Box<dyn backend::Backend>> = Some(Box::new(s3));
-            let uri = Some(uri.as_ref().to_string());
-
-            return Ok(Storage {
-                backend,
-                uri,
-                ..self
-            });
-        }
+        let backend: Arc<dyn backend::Backend> = if uri.scheme() == backend::S3::scheme() {
+            Arc::new(backend::S3::from(&uri)?)
+        } else if uri.scheme() == backend::Fs::scheme() {
+            Arc::new(backend::Fs::from(&uri)?)
+        } else {
+            let err = format!("Unknown remote uri '{}'", uri);
+            return Err(Error::storage(err));
+        };
 
-        let err = format!("Unknown remote uri '{}'", uri);
-        Err(Error::storage(err))
+        Ok(Storage {
+            backend: Some(backend),
+            uri: Some(uri.as_ref().to_string()),
+            ..self
+        })
     }
 
     pub fn key_prefix<S>(self, key: S) -> Self
@@ -64,10 +134,57 @@ impl Storage {
         }
     }
 
+    /// Prefixes keys with the project's one, what `gc` looks at by default.
+    pub fn project<S>(self, project: S) -> Self
+    where
+        S: AsRef<str>,
+    {
+        Storage {
+            project: Some(project.as_ref().to_string()),
+            ..self
+        }
+        .key_prefix(project)
+    }
+
+    #[inline]
+    pub fn as_project(&self) -> Option<&str> {
+        self.project.as_deref()
+    }
+
     pub fn uploadable(self, uploadable: bool) -> Self {
         Storage { uploadable, ..self }
     }
 
+    pub fn layout(self, layout: Layout) -> Self {
+        Storage { layout, ..self }
+    }
+
+    /// How pushed snapshots are compressed, pulls read any.
+    pub fn compression(self, compression: Compression) -> Self {
+        Storage {
+            compression,
+            ..self
+        }
+    }
+
+    /// A generation to upload objects with, see `snapshot::Footer`.
+    pub fn generation(self, generation: u64) -> Self {
+        Storage {
+            generation: Some(generation),
+            ..self
+        }
+    }
+
+    #[inline]
+    pub fn as_layout(&self) -> Layout {
+        self.layout
+    }
+
+    #[inline]
+    pub fn as_compression(&self) -> Compression {
+        self.compression
+    }
+
     pub fn is_uploadable(&self) -> bool {
         self.backend.is_some() && self.uploadable
     }
@@ -79,19 +196,63 @@ impl Storage {
     pub fn download<P>(&self, path: P) -> Result<(), Error>
     where
         P: AsRef<Path>,
+    {
+        let file_name = file_name(&path)?;
+        let key = self.key_prefixed(file_name);
+
+        self.download_object(key, path)
+    }
+
+    pub fn upload<P>(&self, path: P, len: usize) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let file_name = file_name(&path)?;
+        let key = self.key_prefixed(file_name);
+
+        self.upload_object(key, path, len)
+    }
+
+    /// Starts a streamed upload of an object named as the file would be.
+    pub fn upload_stream<S>(&self, file_name: S) -> Result<Upload, Error>
+    where
+        S: AsRef<str>,
+    {
+        match &self.backend {
+            Some(inner) => {
+                Upload::start(inner.clone(), self.key_prefixed(file_name), self.metadata())
+            }
+            None => Err(Error::storage("No remote location to upload to")),
+        }
+    }
+
+    /// Starts a streamed download of an object named as the file would be.
+    pub fn download_stream<S>(&self, file_name: S) -> Result<Download, Error>
+    where
+        S: AsRef<str>,
+    {
+        match &self.backend {
+            Some(inner) => Download::start(inner.clone(), self.key_prefixed(file_name)),
+            None => Err(Error::storage("No remote location to download from")),
+        }
+    }
+
+    /// Downloads an object by its full key, ignoring the key prefix.
+    pub fn download_object<S, P>(&self, key: S, path: P) -> Result<(), Error>
+    where
+        S: Into<String>,
+        P: AsRef<Path>,
     {
         let inner = match &self.backend {
             Some(val) => val,
             None => return Ok(()),
         };
 
-        let _timer = Stats::current().download();
-        let file_name = file_name(&path)?;
-        let file_name = self.key_prefixed(file_name);
+        let _timer = Stats::current().download().timer();
 
         let req = backend::DownloadRequest {
             path: path.as_ref().to_path_buf(),
-            key: file_name,
+            key: key.into(),
         };
 
         let len = inner.download(req)?;
@@ -100,8 +261,10 @@ impl Storage {
         Ok(())
     }
 
-    pub fn upload<P>(&self, path: P, len: usize) -> Result<(), Error>
+    /// Uploads an object by its full key, ignoring the key prefix.
+    pub fn upload_object<S, P>(&self, key: S, path: P, len: usize) -> Result<(), Error>
     where
+        S: Into<String>,
         P: AsRef<Path>,
     {
         let inner = match &self.backend {
@@ -109,14 +272,13 @@ impl Storage {
             None => return Ok(()),
         };
 
-        let _timer = Stats::current().upload();
-        let file_name = file_name(&path)?;
-        let file_name = self.key_prefixed(file_name);
+        let _timer = Stats::current().upload().timer();
 
         let req = backend::UploadRequest {
             path: path.as_ref().to_path_buf(),
-            key: file_name,
+            key: key.into(),
             len,
+            metadata: self.metadata(),
         };
 
         let len = inner.upload(req)?;
@@ -125,6 +287,77 @@ impl Storage {
         Ok(())
     }
 
+    /// The object every push of the layout replaces, none for the split layout
+    /// where groups are replaced independently.
+    pub fn head_file_name(&self) -> Option<&'static str> {
+        match self.layout {
+            Layout::Archive => Some(Config::snapshot_file_name()),
+            Layout::Chunks => Some(Config::manifest_file_name()),
+            Layout::Delta => Some(Config::chain_file_name()),
+            Layout::Split => None,
+        }
+    }
+
+    /// A tag of an object named as the file would be, `None` when it doesn't exist.
+    pub fn etag<S>(&self, file_name: S) -> Result<Option<String>, Error>
+    where
+        S: AsRef<str>,
+    {
+        match &self.backend {
+            Some(inner) => inner.etag(&self.key_prefixed(file_name)),
+            None => Ok(None),
+        }
+    }
+
+    /// A generation of an object named as the file would be, `None` when it doesn't exist
+    /// or was uploaded without one.
+    pub fn remote_generation<S>(&self, file_name: S) -> Result<Option<u64>, Error>
+    where
+        S: AsRef<str>,
+    {
+        let metadata = match &self.backend {
+            Some(inner) => inner.metadata(&self.key_prefixed(file_name))?,
+            None => None,
+        };
+
+        Ok(metadata
+            .as_ref()
+            .and_then(|it| it.get(GENERATION_METADATA_KEY))
+            .and_then(|it| it.parse().ok()))
+    }
+
+    pub fn exists<S>(&self, key: S) -> Result<bool, Error>
+    where
+        S: AsRef<str>,
+    {
+        match &self.backend {
+            Some(inner) => inner.exists(key.as_ref()),
+            None => Ok(false),
+        }
+    }
+
+    /// Lists objects by a full key prefix, ignoring the key prefix.
+    pub fn list<S>(&self, prefix: S) -> Result<Vec<Object>, Error>
+    where
+        S: AsRef<str>,
+    {
+        match &self.backend {
+            Some(inner) => inner.list(prefix.as_ref()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Deletes an object by its full key, ignoring the key prefix.
+    pub fn delete<S>(&self, key: S) -> Result<(), Error>
+    where
+        S: AsRef<str>,
+    {
+        match &self.backend {
+            Some(inner) => inner.delete(key.as_ref()),
+            None => Ok(()),
+        }
+    }
+
     pub fn key_prefixed<S>(&self, key: S) -> String
     where
         S: AsRef<str>,
@@ -136,11 +369,22 @@ impl Storage {
         }
     }
 
+    fn metadata(&self) -> backend::Metadata {
+        let mut metadata = backend::Metadata::new();
+        if let Some(generation) = self.generation {
+            metadata.insert(GENERATION_METADATA_KEY.into(), generation.to_string());
+        }
+        metadata
+    }
+
     pub fn save(&self) -> Result<(), Error> {
         let content = json!({
             "uri": self.uri,
+            "project": self.project,
             "key_prefix": self.key_prefix,
             "uploadable": self.uploadable,
+            "layout": self.layout.as_str(),
+            "compression": self.compression.as_str(),
         });
 
         let mut opts = OpenOptions::new();
@@ -176,6 +420,11 @@ impl Storage {
             storage = storage.uri(&uri)?;
         }
 
+        // the key prefix starts with the project's key
+        if let Some(project) = obj.get("project").and_then(|it| it.as_str()) {
+            storage.project = Some(project.to_string());
+        }
+
         if let Some(key_prefix) = obj.get("key_prefix").and_then(|it| it.as_str()) {
             storage = storage.key_prefix(&key_prefix);
         }
@@ -184,6 +433,14 @@ impl Storage {
             storage = storage.uploadable(uploadable);
         }
 
+        if let Some(layout) = obj.get("layout").and_then(|it| it.as_str()) {
+            storage = storage.layout(layout.parse()?);
+        }
+
+        if let Some(compression) = obj.get("compression").and_then(|it| it.as_str()) {
+            storage = storage.compression(compression.parse()?);
+        }
+
         Ok(storage)
     }
 }
@@ -228,10 +485,27 @@ mod tests {
         let storage = Storage::new(&cfg)
             .uri("s3://bucket/prefix")
             .unwrap()
-            .key_prefix("prefix");
+            .project("project")
+            .key_prefix("prefix")
+            .layout(Layout::Chunks)
+            .compression(Compression::None);
 
         storage.save().unwrap();
 
-        let _storage = Storage::load(cfg.storage_file).unwrap();
+        let storage = Storage::load(cfg.storage_file).unwrap();
+        assert_eq!(storage.as_layout(), Layout::Chunks);
+        assert_eq!(storage.as_compression(), Compression::None);
+        assert_eq!(storage.as_project(), Some("project"));
+        assert_eq!(storage.key_prefixed("foo"), "project/prefix/foo");
+    }
+
+    #[test]
+    fn file_uri() {
+        let work = testing::temp_dir();
+        let cfg = Config::from(work.as_ref()).unwrap();
+        let storage = Storage::new(&cfg).uri("file:///tmp/cache").unwrap();
+
+        assert_eq!(storage.is_downloable(), true);
+        assert!(Storage::new(&cfg).uri("ftp://host/cache").is_err());
     }
 }
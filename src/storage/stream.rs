@@ -0,0 +1,299 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::mem;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::storage::backend::{Backend, Metadata, Part, PART_SIZE};
+use crate::{Error, Stats};
+
+const PARTS_IN_CHANNEL: usize = 2;
+
+/// Uploads everything written into it in background, part by part, so packing and
+/// uploading overlap. Dropping it without `finish` cancels the upload.
+pub struct Upload {
+    sender: Option<SyncSender<Part>>,
+    handle: Option<JoinHandle<Result<usize, Error>>>,
+    buf: Vec<u8>,
+    written: usize,
+}
+
+impl Upload {
+    pub(super) fn start(
+        backend: Arc<dyn Backend>,
+        key: String,
+        metadata: Metadata,
+    ) -> Result<Self, Error> {
+        let (sender, receiver) = mpsc::sync_channel(PARTS_IN_CHANNEL);
+
+        let handle = thread::Builder::new()
+            .name("upload".into())
+            .spawn(move || {
+                let _timer = Stats::current().upload().timer();
+                let len = backend.upload_parts(&key, &metadata, receiver)?;
+                Stats::current().upload().inc(len);
+                Ok(len)
+            })
+            .map_err(Error::storage)?;
+
+        Ok(Upload {
+            sender: Some(sender),
+            handle: Some(handle),
+            buf: Vec::with_capacity(PART_SIZE),
+            written: 0,
+        })
+    }
+
+    /// Bytes written so far, all of them are uploaded once finished.
+    #[inline]
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Sends the rest and waits for the upload, returns uploaded bytes.
+    pub fn finish(mut self) -> Result<usize, Error> {
+        let buf = mem::replace(&mut self.buf, Vec::new());
+
+        // errors are reported by the uploader itself
+        if let Some(sender) = self.sender.take() {
+            let _ = sender
+                .send(Part::Data(buf))
+                .and_then(|_| sender.send(Part::End));
+        }
+
+        self.join()
+    }
+
+    fn send_part(&mut self) -> Result<(), IoError> {
+        let buf = mem::replace(&mut self.buf, Vec::with_capacity(PART_SIZE));
+        let sent = match &self.sender {
+            Some(sender) => sender.send(Part::Data(buf)).is_ok(),
+            None => false,
+        };
+
+        if sent {
+            return Ok(());
+        }
+
+        // the uploader is gone, so its error is the reason
+        self.sender = None;
+        let err = match self.join() {
+            Err(err) => err,
+            Ok(_) => Error::storage("Upload is finished unexpectedly"),
+        };
+        Err(IoError::new(IoErrorKind::BrokenPipe, err))
+    }
+
+    fn join(&mut self) -> Result<usize, Error> {
+        match self.handle.take() {
+            Some(handle) => handle
+                .join()
+                .unwrap_or_else(|_| Err(Error::storage("Upload thread panicked"))),
+            None => Err(Error::storage("Upload is already stopped")),
+        }
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let len = buf.len().min(PART_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+
+        if self.buf.len() == PART_SIZE {
+            self.send_part()?;
+        }
+
+        self.written += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        self.sender = None;
+        let _ = self.join();
+    }
+}
+
+/// Downloads an object in background and reads it part by part, so unpacking and
+/// downloading overlap. Dropping it before the end cancels the download.
+pub struct Download {
+    receiver: Option<Receiver<Part>>,
+    handle: Option<JoinHandle<Result<usize, Error>>>,
+    buf: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl Download {
+    pub(super) fn start(backend: Arc<dyn Backend>, key: String) -> Result<Self, Error> {
+        let (sender, receiver) = mpsc::sync_channel(PARTS_IN_CHANNEL);
+
+        let handle = thread::Builder::new()
+            .name("download".into())
+            .spawn(move || {
+                let _timer = Stats::current().download().timer();
+                let len = backend.download_parts(&key, sender)?;
+                Stats::current().download().inc(len);
+                Ok(len)
+            })
+            .map_err(Error::storage)?;
+
+        let mut download = Download {
+            receiver: Some(receiver),
+            handle: Some(handle),
+            buf: Vec::new(),
+            pos: 0,
+            finished: false,
+        };
+
+        // waits for the first part, so a missing object is reported here
+        // rather than in the middle of unpacking
+        download.next_part()?;
+
+        Ok(download)
+    }
+
+    fn next_part(&mut self) -> Result<(), Error> {
+        let part = match &self.receiver {
+            Some(receiver) => receiver.recv(),
+            None => return Err(Error::storage("Download is already stopped")),
+        };
+
+        match part {
+            Ok(Part::Data(buf)) => {
+                self.buf = buf;
+                self.pos = 0;
+                Ok(())
+            }
+            Ok(Part::End) => {
+                self.finished = true;
+                self.join().map(|_| ())
+            }
+            Err(_) => {
+                // the downloader is gone, so its error is the reason
+                self.receiver = None;
+                match self.join() {
+                    Err(err) => Err(err),
+                    Ok(_) => Err(Error::storage("Download is finished unexpectedly")),
+                }
+            }
+        }
+    }
+
+    fn join(&mut self) -> Result<usize, Error> {
+        match self.handle.take() {
+            Some(handle) => handle
+                .join()
+                .unwrap_or_else(|_| Err(Error::storage("Download thread panicked"))),
+            None => Err(Error::storage("Download is already stopped")),
+        }
+    }
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        loop {
+            if self.pos < self.buf.len() {
+                let len = buf.len().min(self.buf.len() - self.pos);
+                buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+                self.pos += len;
+                return Ok(len);
+            }
+
+            if self.finished {
+                return Ok(0);
+            }
+
+            // never `UnexpectedEof`, see `crypto::stream::into_io_error`
+            self.next_part()
+                .map_err(|err| IoError::new(IoErrorKind::Other, err))?;
+        }
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        // the downloader stops on its next send, no need to wait for it
+        self.receiver = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use url::Url;
+
+    use crate::storage::backend::Fs;
+    use crate::testing;
+
+    fn backend(root: &testing::DirGuard) -> Arc<dyn Backend> {
+        let uri = Url::from_directory_path(root.as_ref()).unwrap();
+        Arc::new(Fs::from(&uri).unwrap())
+    }
+
+    #[test]
+    fn upload_when_finished() {
+        let root = testing::temp_dir();
+        let src = vec![7; PART_SIZE * 2 + 10];
+
+        let mut upload = Upload::start(backend(&root), "file".into(), Metadata::new()).unwrap();
+        upload.write_all(&src).unwrap();
+
+        assert_eq!(upload.finish().unwrap(), src.len());
+        assert_eq!(fs::read(root.as_ref().join("file")).unwrap(), src);
+    }
+
+    #[test]
+    fn cancel_when_dropped() {
+        let root = testing::temp_dir();
+
+        {
+            let mut upload = Upload::start(backend(&root), "file".into(), Metadata::new()).unwrap();
+            upload.write_all(&vec![7; PART_SIZE + 10]).unwrap();
+        }
+
+        assert_eq!(root.as_ref().join("file").exists(), false);
+    }
+
+    #[test]
+    fn download_to_the_end() {
+        let root = testing::temp_dir();
+        let src = (0..PART_SIZE / 4).map(|it| it as u8).collect::<Vec<_>>();
+        fs::write(root.as_ref().join("file"), &src).unwrap();
+
+        let mut download = Download::start(backend(&root), "file".into()).unwrap();
+        let mut actual = Vec::new();
+        download.read_to_end(&mut actual).unwrap();
+
+        assert_eq!(actual, src);
+    }
+
+    #[test]
+    fn download_missing() {
+        let root = testing::temp_dir();
+        assert!(Download::start(backend(&root), "file".into()).is_err());
+    }
+
+    #[test]
+    fn cancel_download_when_dropped() {
+        let root = testing::temp_dir();
+        fs::write(root.as_ref().join("file"), vec![7; PART_SIZE * 8]).unwrap();
+
+        let mut download = Download::start(backend(&root), "file".into()).unwrap();
+        let mut buf = [0; 16];
+        download.read_exact(&mut buf).unwrap();
+
+        let handle = download.handle.take().unwrap();
+        drop(download);
+
+        assert!(handle.join().unwrap().is_err());
+    }
+}
@@ -2,6 +2,8 @@ use std::path::Path;
 
 use tempfile::{self, NamedTempFile, TempDir};
 
+use crate::snapshot::{Entry, Pack, Writing};
+
 pub const FIXTURES_PATH: &str = "tests/fixtures/snapshot";
 pub const A_FILE_PATH: &str = "tests/fixtures/snapshot/a.txt";
 pub const B_FILE_PATH: &str = "tests/fixtures/snapshot/b.txt";
@@ -10,6 +12,11 @@ pub const IS_DIR_PATH: &str = "tests/fixtures/snapshot/is_dir";
 pub const IS_BIN_PATH: &str = "tests/fixtures/snapshot/is_bin";
 pub const TEAMCITY_BUILD_PROPS_PATH: &str = "tests/fixtures/teamcity/build.properties";
 pub const TEAMCITY_CONFIG_PROPS_PATH: &str = "tests/fixtures/teamcity/config.properties";
+pub const TEAMCITY_ESCAPES_PROPS_PATH: &str = "tests/fixtures/teamcity/escapes.properties";
+pub const TEAMCITY_PARAMS_PROPS_PATH: &str = "tests/fixtures/teamcity/params.properties";
+pub const GITHUB_PUSH_EVENT_PATH: &str = "tests/fixtures/github/push.json";
+pub const GITHUB_PULL_REQUEST_EVENT_PATH: &str = "tests/fixtures/github/pull_request.json";
+pub const PROJECT_FILE_PATH: &str = "tests/fixtures/tc-cache.toml";
 
 #[derive(Debug)]
 pub struct FileGuard(Option<NamedTempFile>);
@@ -62,3 +69,10 @@ pub fn temp_dir() -> DirGuard {
     let dir = b.tempdir().unwrap();
     DirGuard(Some(dir))
 }
+
+/// Packs directories into an unencrypted snapshot, returns written bytes.
+pub fn pack<T: AsRef<Path>, P: AsRef<Path>>(snapshot: T, dirs: &[P]) -> usize {
+    let entries = Entry::walk_into_vec(dirs).unwrap();
+    let snapshot = Writing::open(snapshot, None).unwrap();
+    snapshot.pack_with_entries(&entries).unwrap()
+}